@@ -0,0 +1,136 @@
+//! The classic strategies that ship with IPDT
+//! (mostly from https://ncase.me/trust/)
+use crate::core::{Executor, Player};
+
+pub fn tit_for_tat() -> Player {
+    Player::new(
+        "Tit for Tat".to_string(),
+        Executor::Lua(
+            r#"
+            function(history, storage)
+                if #history == 0 then
+                    return true, storage
+                else
+                    return history[#history][2], storage
+                end
+            end
+            "#
+            .to_string(),
+        ),
+    )
+}
+
+pub fn grim_trigger() -> Player {
+    Player::new(
+        "Grim Trigger".to_string(),
+        Executor::Lua(
+            r#"
+            function(history, storage)
+                if storage == true then
+                    return false, storage
+                end
+                for _, round in ipairs(history) do
+                    if not round[2] then
+                        return false, true
+                    end
+                end
+                return true, storage
+            end
+            "#
+            .to_string(),
+        ),
+    )
+}
+
+pub fn always_cooperate() -> Player {
+    Player::new(
+        "Always Cooperate".to_string(),
+        Executor::Lua(
+            r#"
+            function(history, storage)
+                return true, storage
+            end
+            "#
+            .to_string(),
+        ),
+    )
+}
+
+pub fn always_defect() -> Player {
+    Player::new(
+        "Always Defect".to_string(),
+        Executor::Python(
+            r#"
+def main(history, storage):
+    return False, storage
+"#
+            .to_string(),
+        ),
+    )
+}
+
+pub fn forgiving_tit_for_tat() -> Player {
+    Player::new(
+        "Forgiving Tit for Tat".to_string(),
+        Executor::Lua(
+            r#"
+            function(history, storage)
+                if #history == 0 then
+                    return true, storage
+                elseif #history == 1 then
+                    return history[#history][2], storage
+                else
+                    if not history[#history][2] and not history[#history - 1][2] then
+                        return false, storage
+                    else
+                        return true, storage
+                    end
+                end
+            end
+            "#
+            .to_string(),
+        ),
+    )
+}
+
+pub fn detective() -> Player {
+    Player::new(
+        "Detective".to_string(),
+        Executor::Python(
+            r#"
+def main(history, storage):
+    if len(history) < 4:
+        moves = [True, False, True, True]
+        return moves[len(history)], storage
+    else:
+        for round in history:
+            if not round[1]:
+                return history[-1][1], storage
+        return False, storage
+"#
+            .to_string(),
+        ),
+    )
+}
+
+/// Every built-in strategy
+pub fn builtins() -> Vec<Player> {
+    vec![
+        tit_for_tat(),
+        grim_trigger(),
+        always_cooperate(),
+        always_defect(),
+        forgiving_tit_for_tat(),
+        detective(),
+    ]
+}
+
+/// Look up a built-in strategy by name. Matching ignores case and
+/// treats `-` and `_` like spaces so `tit-for-tat` works on the command line
+pub fn builtin(name: &str) -> Option<Player> {
+    let normalize = |name: &str| name.to_lowercase().replace(['-', '_'], " ");
+    let name = normalize(name);
+    builtins()
+        .into_iter()
+        .find(|player| normalize(&player.name) == name)
+}
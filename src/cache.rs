@@ -0,0 +1,229 @@
+//! Caching game results on disk so a tournament only plays what changed
+//!
+//! A game is stored under a hash of everything that decides its outcome:
+//! both programs and the storage they start with (and names, unless
+//! opponents are anonymous, since strategies are told them), the scores, the number of rounds, the noise,
+//! the seed, what strategies are told about the game, the sandbox and
+//! worker limits they run with and how much of their output is kept.
+//! When a new submission arrives, only its games get played and the rest
+//! come straight from the cache.
+//!
+//! Only runs with a fixed seed use the cache, since otherwise the seed
+//! changes every run and the games would never be asked for again. Games
+//! of local programs (`Executor::Command`) are never cached: they can read
+//! anything, so no hash covers everything that decides how they play
+use std::fs;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::core::{hex, Executor, MatchResult, TournamentConfig};
+use crate::worker::Isolation;
+
+pub struct MatchCache {
+    directory: PathBuf,
+}
+
+impl MatchCache {
+    pub fn new(directory: impl Into<PathBuf>) -> MatchCache {
+        MatchCache {
+            directory: directory.into(),
+        }
+    }
+    /// The cache key of the game `player1` vs `player2` of `config`,
+    /// unless the game can't be cached
+    pub fn key(
+        config: &TournamentConfig,
+        player1: usize,
+        player2: usize,
+        seed: u64,
+    ) -> Option<String> {
+        let mut hasher = Sha256::new();
+        for player in [player1, player2] {
+            let player = &config.players[player];
+            if let Executor::Command(_) = player.executor {
+                return None;
+            }
+            hasher.update(player.executor.fingerprint().as_bytes());
+            hasher.update([0]);
+            hasher.update(player.storage().to_string().as_bytes());
+            hasher.update([0]);
+            if !config.anonymize_opponents {
+                hasher.update(player.name.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        let isolation = config.isolation.as_ref().map(Isolation::limits);
+        hasher.update(
+            format!(
+                "{} {} {} {} {} {} {} {} {} {:?} {} {:?}",
+                config.rounds,
+                config.mutual_win_score,
+                config.mutual_loss_score,
+                config.win_score,
+                config.loss_score,
+                config.noise,
+                seed,
+                config.disclose_rounds,
+                config.anonymize_opponents,
+                config.sandbox,
+                config.debug_log_limit,
+                isolation
+            )
+            .as_bytes(),
+        );
+        Some(hex(&hasher.finalize()))
+    }
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{key}.json"))
+    }
+    /// Look up a game, relabelled for the given players
+    pub fn get(&self, key: &str, player1: usize, player2: usize) -> Option<MatchResult> {
+        let text = fs::read_to_string(self.path(key)).ok()?;
+        let result: MatchResult = serde_json::from_str(&text).ok()?;
+        Some(relabel(result, (0, 1), (player1, player2)))
+    }
+    /// Save a game. Failing to save only means it gets played again
+    pub fn put(&self, key: &str, result: &MatchResult) {
+        let result = relabel(result.clone(), (result.player1, result.player2), (0, 1));
+        let Ok(text) = serde_json::to_string(&result) else {
+            return;
+        };
+        // Write then rename, so a crash never leaves half a file behind
+        let temporary = self.directory.join(format!("{key}.tmp"));
+        let _ = fs::create_dir_all(&self.directory)
+            .and_then(|_| fs::write(&temporary, text))
+            .and_then(|_| fs::rename(&temporary, self.path(key)));
+    }
+}
+
+/// Player indices only make sense within one tournament,
+/// so the cache stores games as player 0 vs player 1
+fn relabel(mut result: MatchResult, from: (usize, usize), to: (usize, usize)) -> MatchResult {
+    let map = |player: usize| if player == from.0 { to.0 } else { to.1 };
+    for error in &mut result.errors {
+        error.player = map(error.player);
+    }
+    result.player1 = to.0;
+    result.player2 = to.1;
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::builtins::builtin;
+    use crate::command::CommandProgram;
+    use crate::core::{Player, Tournament};
+    use crate::testing::scratch;
+
+    fn config() -> TournamentConfig {
+        TournamentConfig::new().with_players(vec![
+            builtin("tit-for-tat").unwrap(),
+            builtin("always-defect").unwrap(),
+        ])
+    }
+
+    fn key(config: &TournamentConfig) -> String {
+        MatchCache::key(config, 0, 1, 1).unwrap()
+    }
+
+    fn renamed(config: TournamentConfig) -> TournamentConfig {
+        let mut players = config.players.clone();
+        let executor = players[0].executor.clone();
+        players[0] = Player::new("someone else".to_string(), executor);
+        config.with_players(players)
+    }
+
+    #[test]
+    fn names_count_unless_opponents_are_anonymous() {
+        assert_ne!(key(&config()), key(&renamed(config())));
+        let anonymous = config().with_anonymous_opponents(true);
+        assert_eq!(key(&anonymous), key(&renamed(anonymous.clone())));
+    }
+
+    #[test]
+    fn starting_storage_counts() {
+        let mut stored = config();
+        stored.players[1] = stored.players[1].clone().with_storage("grudge");
+        assert_ne!(key(&config()), key(&stored));
+    }
+
+    #[test]
+    fn limits_count() {
+        let plain = key(&config());
+        assert_ne!(plain, key(&config().with_debug_log_limit(1)));
+        let isolated = key(&config().with_isolation(Some(Isolation::new())));
+        assert_ne!(plain, isolated);
+        for isolation in [
+            Isolation::new().with_memory_limit(Some(1 << 20)),
+            Isolation::new().with_move_timeout(Some(Duration::from_secs(1))),
+            Isolation::new().with_namespaces(true),
+            Isolation::new().with_seccomp(true),
+        ] {
+            assert_ne!(isolated, key(&config().with_isolation(Some(isolation))));
+        }
+        // Where the worker comes from doesn't change how it plays
+        let elsewhere = Isolation::new().with_command("/elsewhere/ipdt", vec!["worker".into()]);
+        assert_eq!(isolated, key(&config().with_isolation(Some(elsewhere))));
+    }
+
+    #[test]
+    fn games_come_back_for_other_players() {
+        let directory = scratch("relabel");
+        let cache = MatchCache::new(&directory);
+        let result = Tournament::with_config(config().with_rounds(3).with_seed(1)).run();
+        let mut game = result.matches[0].clone();
+        game.player1 = 1;
+        game.player2 = 0;
+        cache.put("game", &game);
+        let found = cache.get("game", 4, 2).unwrap();
+        assert_eq!((found.player1, found.player2), (4, 2));
+        assert_eq!(found.rounds, game.rounds);
+        assert!(cache.get("missing", 4, 2).is_none());
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn local_programs_are_played_every_time() {
+        let directory = scratch("local");
+        let answer = r#"while read line; do echo "{\"action\": $ACTION, \"storage\": \"\"}"; done"#;
+        let run = |action: &str| {
+            let program = CommandProgram::new(vec!["sh".into(), "-c".into(), answer.into()])
+                .with_env(BTreeMap::from([("ACTION".to_string(), action.to_string())]));
+            let config = TournamentConfig::new()
+                .with_players(vec![
+                    Player::new("local".to_string(), Executor::Command(program)),
+                    builtin("always-defect").unwrap(),
+                ])
+                .with_rounds(3)
+                .with_seed(1);
+            assert!(MatchCache::key(&config, 0, 1, 1).is_none());
+            Tournament::with_config(config).with_cache(&directory).run()
+        };
+        let cooperating = run("true");
+        let defecting = run("false");
+        assert_ne!(cooperating.scores, defecting.scores);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn only_seeded_runs_are_cached() {
+        let directory = scratch("seeded");
+        let run = |config: TournamentConfig| {
+            Tournament::with_config(config.with_rounds(3))
+                .with_cache(&directory)
+                .run()
+        };
+        run(config());
+        assert!(!directory.exists());
+        let first = run(config().with_seed(1));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 2);
+        assert_eq!(run(config().with_seed(1)).scores, first.scores);
+        let _ = fs::remove_dir_all(directory);
+    }
+}
@@ -0,0 +1,256 @@
+//! Saving finished games as a tournament runs so it can be resumed
+//!
+//! A checkpoint is a JSON lines file. The first line records the
+//! fingerprint of the config (see `TournamentConfig::fingerprint`) and
+//! the seed, every other line is a finished game. Rerunning the same
+//! config with the same checkpoint skips the games that are already in it.
+//! A file that isn't a checkpoint of the same config is an error rather
+//! than overwritten, unless asked to start over (`Checkpoint::create`).
+//!
+//! Creating the checkpoint has to work, but writing games to it is
+//! best-effort: a game that couldn't be saved is simply played again
+//! on the next run, like an abandoned one
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::MatchResult;
+use crate::error::CheckpointError;
+use crate::observer::TournamentObserver;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    config: String,
+    seed: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    index: usize,
+    result: MatchResult,
+}
+
+pub struct Checkpoint {
+    seed: u64,
+    finished: Vec<(usize, MatchResult)>,
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    /// Pick up the checkpoint at `path` if it was written for the config
+    /// with this fingerprint, or start a new one with `seed` if there is
+    /// no file there yet. Anything else at `path` is left alone
+    pub fn open(path: &Path, fingerprint: &str, seed: u64) -> Result<Checkpoint, CheckpointError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(source) => {
+                return Err(CheckpointError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        if text.trim().is_empty() {
+            return Checkpoint::create(path, fingerprint, seed);
+        }
+        let mut lines = text.split_inclusive('\n');
+        let header = lines
+            .next()
+            .and_then(|line| line.strip_suffix('\n'))
+            .and_then(|line| serde_json::from_str::<Header>(line).ok())
+            .filter(|header| header.version == VERSION && header.config == fingerprint)
+            .ok_or_else(|| CheckpointError::Mismatch(path.to_path_buf()))?;
+        let mut length = text.find('\n').map_or(0, |end| end + 1);
+        let mut finished = vec![];
+        for line in lines {
+            // The last line might be cut off if we crashed while writing it
+            let Some(entry) = line
+                .strip_suffix('\n')
+                .and_then(|line| serde_json::from_str::<Entry>(line).ok())
+            else {
+                break;
+            };
+            length += line.len();
+            finished.push((entry.index, entry.result));
+        }
+        // Drop anything cut off, so the next game goes on a line of its own
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|file| {
+                file.set_len(length as u64)?;
+                Ok(file)
+            })
+            .map_err(|source| CheckpointError::Io {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Ok(Checkpoint {
+            seed: header.seed,
+            finished,
+            file: Mutex::new(file),
+        })
+    }
+    /// Start a new checkpoint at `path` with `seed`, replacing whatever is there
+    pub fn create(
+        path: &Path,
+        fingerprint: &str,
+        seed: u64,
+    ) -> Result<Checkpoint, CheckpointError> {
+        let header = Header {
+            version: VERSION,
+            config: fingerprint.to_string(),
+            seed,
+        };
+        let file = File::create(path).and_then(|mut file| {
+            let header = serde_json::to_string(&header)?;
+            writeln!(file, "{header}")?;
+            file.flush()?;
+            Ok(file)
+        });
+        let file = file.map_err(|source| CheckpointError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Checkpoint {
+            seed,
+            finished: vec![],
+            file: Mutex::new(file),
+        })
+    }
+    /// The seed the checkpointed run used
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    /// The games that were already played when it was opened, by schedule index
+    pub fn finished(&self) -> &[(usize, MatchResult)] {
+        &self.finished
+    }
+}
+
+impl TournamentObserver for Checkpoint {
+    fn on_match_end(&self, index: usize, result: &MatchResult) {
+        // Abandoned games get played again on the next run
+        if result.abandoned.is_some() {
+            return;
+        }
+        let entry = serde_json::json!({"index": index, "result": result});
+        let line = entry.to_string();
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writeln!(file, "{line}").and_then(|_| file.flush());
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::builtins::builtin;
+    use crate::core::{Tournament, TournamentConfig, TournamentResult};
+    use crate::testing::scratch;
+
+    /// Noisy, so games depend on the seed, and without a seed of its own
+    fn config(rounds: i32) -> TournamentConfig {
+        TournamentConfig::new()
+            .with_players(vec![
+                builtin("tit-for-tat").unwrap(),
+                builtin("always-defect").unwrap(),
+            ])
+            .with_rounds(rounds)
+            .with_noise(0.5)
+            .with_parallelism(1)
+    }
+
+    fn games(result: &TournamentResult) -> serde_json::Value {
+        serde_json::to_value(&result.matches).unwrap()
+    }
+
+    #[test]
+    fn resuming_skips_finished_games_and_keeps_the_seed() {
+        let path = scratch("resume");
+        let first = Tournament::with_config(config(20))
+            .with_checkpoint(&path)
+            .unwrap()
+            .run();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 1 + first.matches.len());
+
+        // Interrupted after the first game, halfway through writing the second
+        let mut lines = text.lines();
+        let kept = format!(
+            "{}\n{}\n{{\"index\"",
+            lines.next().unwrap(),
+            lines.next().unwrap()
+        );
+        fs::write(&path, kept).unwrap();
+        let checkpoint = Checkpoint::open(&path, &config(20).fingerprint(), 0).unwrap();
+        assert_eq!(checkpoint.finished().len(), 1);
+        assert_ne!(checkpoint.seed(), 0);
+        let mut tournament = Tournament::with_config(config(20))
+            .with_checkpoint(&path)
+            .unwrap();
+        assert_eq!(games(&tournament.run()), games(&first));
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn other_files_are_not_overwritten() {
+        let path = scratch("other");
+        Tournament::with_config(config(5))
+            .with_checkpoint(&path)
+            .unwrap()
+            .run();
+        let before = fs::read_to_string(&path).unwrap();
+        assert!(matches!(
+            Tournament::with_config(config(6)).with_checkpoint(&path),
+            Err(CheckpointError::Mismatch(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), before);
+
+        fs::write(&path, "my notes\n").unwrap();
+        assert!(matches!(
+            Tournament::with_config(config(5)).with_checkpoint(&path),
+            Err(CheckpointError::Mismatch(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "my notes\n");
+
+        let result = Tournament::with_config(config(6))
+            .with_new_checkpoint(&path)
+            .unwrap()
+            .run();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 1 + result.matches.len());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn limits_and_starting_storage_change_the_fingerprint() {
+        let plain = config(5).fingerprint();
+        let mut stored = config(5);
+        stored.players[0] = stored.players[0].clone().with_storage("grudge");
+        for other in [
+            config(5).with_debug_log_limit(1),
+            config(5).with_isolation(Some(crate::worker::Isolation::new())),
+            stored,
+        ] {
+            assert_ne!(plain, other.fingerprint());
+        }
+    }
+
+    #[test]
+    fn failing_to_create_is_an_error() {
+        let path = scratch("missing").join("run.ckpt");
+        assert!(matches!(
+            Tournament::with_config(config(5)).with_checkpoint(&path),
+            Err(CheckpointError::Io { .. })
+        ));
+    }
+}
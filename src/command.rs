@@ -0,0 +1,629 @@
+//! Running local programs
+//!
+//! `Executor::Command` starts a program once per game and talks to it in
+//! JSON lines over its stdin and stdout. Before every move it gets the
+//! envelope Piston programs read (see `piston`) as one line,
+//! `{"version": 1, "history": ..., "storage": ..., "context": ...}`, and
+//! answers with its move as one line, `{"action": true, "storage": ...}`
+//! (and `career` if it changes it). Any other line it prints, and whatever
+//! it writes to stderr, is output (see `debug_log`). Lines past the debug
+//! log limit are dropped as they're read, so a program that floods stdout
+//! only costs a line's worth of memory.
+//!
+//! A program that exits, takes longer than its timeout
+//! (`ExecutionError::Timeout`) or can't be started loses the move, and the
+//! next move starts it again (with the whole history and its storage, so it
+//! can pick up where it left off).
+//!
+//! The program runs with all the rights of the tournament and is never
+//! sandboxed or isolated, so only run programs you trust
+
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::core::{Move, MoveContext};
+use crate::error::{Diagnostic, ExecutionError};
+use crate::piston::{Envelope, ENVELOPE_VERSION};
+use crate::worker;
+
+/// How much of what a program writes to stderr to keep between moves
+const STDERR_LIMIT: usize = 64 * 1024;
+/// The longest move line read, storage and all. A longer line is dropped
+/// (and a longer move loses it, by timing out)
+const MOVE_LIMIT: usize = 16 * 1024 * 1024;
+/// How long to wait for the rest of the stderr of a program that died.
+/// Something it started might keep stderr open, so not forever
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// A local program, see `Executor::Command`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommandProgram {
+    /// The program and its arguments
+    pub command: Vec<String>,
+    /// Where to run it, the current directory by default
+    pub working_dir: Option<PathBuf>,
+    /// Set on top of the environment of the tournament
+    pub env: BTreeMap<String, String>,
+    /// How long the program may take to start and make its first move
+    pub startup_timeout: Option<Duration>,
+    /// How long every other move may take
+    pub move_timeout: Option<Duration>,
+    /// See `identity`
+    #[serde(skip)]
+    identity: Identity,
+}
+
+/// The identity of a program, worked out when it's built (or the first
+/// time it's asked for, after deserializing), so editing a script during
+/// a tournament doesn't change its fingerprint halfway
+#[derive(Clone, Debug, Default)]
+struct Identity(OnceLock<Vec<u8>>);
+
+/// It follows from the other fields
+impl PartialEq for Identity {
+    fn eq(&self, _other: &Identity) -> bool {
+        true
+    }
+}
+
+impl CommandProgram {
+    /// 30 seconds to start, 10 seconds per move after that
+    pub fn new(command: Vec<String>) -> CommandProgram {
+        CommandProgram {
+            command,
+            working_dir: None,
+            env: BTreeMap::new(),
+            startup_timeout: Some(Duration::from_secs(30)),
+            move_timeout: Some(Duration::from_secs(10)),
+            identity: Identity::default(),
+        }
+        .identified()
+    }
+    pub fn with_working_dir(mut self, directory: Option<PathBuf>) -> CommandProgram {
+        self.working_dir = directory;
+        self.identity = Identity::default();
+        self.identified()
+    }
+    pub fn with_env(mut self, env: BTreeMap<String, String>) -> CommandProgram {
+        self.env = env;
+        self.identity = Identity::default();
+        self.identified()
+    }
+    pub fn with_startup_timeout(mut self, timeout: Option<Duration>) -> CommandProgram {
+        self.startup_timeout = timeout;
+        self
+    }
+    pub fn with_move_timeout(mut self, timeout: Option<Duration>) -> CommandProgram {
+        self.move_timeout = timeout;
+        self
+    }
+    fn identified(self) -> CommandProgram {
+        self.identity();
+        self
+    }
+    /// What goes into `Executor::fingerprint`: a digest of the program
+    /// the command runs, the arguments and the contents of those that are
+    /// files (like the script an interpreter runs), the working directory
+    /// and the environment, as they were when the program was built
+    pub(crate) fn identity(&self) -> &[u8] {
+        self.identity.0.get_or_init(|| self.read_identity())
+    }
+    fn read_identity(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(
+            &self
+                .executable()
+                .and_then(|path| fs::read(path).ok())
+                .unwrap_or_default(),
+        );
+        for (index, argument) in self.command.iter().enumerate() {
+            field(argument.as_bytes());
+            let path = self.in_working_dir(argument);
+            let contents = (index > 0 && path.is_file())
+                .then(|| fs::read(&path).ok())
+                .flatten();
+            field(&contents.unwrap_or_default());
+        }
+        let directory = self.working_dir.as_deref().unwrap_or(Path::new(""));
+        field(directory.as_os_str().as_encoded_bytes());
+        for (name, value) in &self.env {
+            field(name.as_bytes());
+            field(value.as_bytes());
+        }
+        hasher.finalize().to_vec()
+    }
+    /// The file the command runs, found the way it will be: relative to
+    /// the working directory, or on the `PATH` the program gets
+    fn executable(&self) -> Option<PathBuf> {
+        let name = self.command.first()?;
+        if Path::new(name).components().count() > 1 {
+            return Some(self.in_working_dir(name));
+        }
+        let path = match self.env.get("PATH") {
+            Some(path) => OsString::from(path),
+            None => env::var_os("PATH")?,
+        };
+        env::split_paths(&path)
+            .map(|directory| directory.join(name))
+            .find(|candidate| candidate.is_file())
+    }
+    fn in_working_dir(&self, path: &str) -> PathBuf {
+        match &self.working_dir {
+            Some(directory) => directory.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+    /// Whether there is a program to start
+    pub(crate) fn check(&self) -> Result<(), ExecutionError> {
+        if self.command.is_empty() {
+            return Err(ExecutionError::InitializationError(
+                "The command is empty".to_string(),
+            ));
+        }
+        match &self.working_dir {
+            Some(directory) if !directory.is_dir() => Err(ExecutionError::InitializationError(
+                format!("No directory {}", directory.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The program of one player for one game. A copy starts without a
+/// program, so a player copied for a game gets a program of its own
+/// (behind a mutex only so players can be shared between threads)
+#[derive(Default)]
+pub(crate) struct Session {
+    process: Mutex<Option<Process>>,
+}
+
+impl Clone for Session {
+    fn clone(&self) -> Self {
+        Session::default()
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field(
+                "running",
+                &self
+                    .process
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .is_some(),
+            )
+            .finish()
+    }
+}
+
+impl Session {
+    /// Make a move, starting the program if it isn't running.
+    /// An empty history is a new game, which gets a new program, and
+    /// `limit` bytes of output
+    pub(crate) fn next(
+        &mut self,
+        program: &CommandProgram,
+        history: &[(bool, bool)],
+        storage: &Value,
+        context: Option<&MoveContext>,
+        limit: usize,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        let running = self
+            .process
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if history.is_empty() {
+            *running = None;
+        }
+        let process = match running {
+            Some(process) => process,
+            None => running.insert(Process::start(program, limit)?),
+        };
+        let result = process.call(program, history, storage, context, output);
+        // Whatever went wrong, the program might not be in a state to go on
+        if result.is_err() {
+            *running = None;
+        }
+        result
+    }
+}
+
+/// A line a program printed on stdout
+enum Line {
+    Move(Move),
+    Output(String),
+}
+
+/// A running program
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<Line>,
+    stderr: Arc<Mutex<String>>,
+    /// Disconnects once stderr is closed and all of it is in `stderr`
+    stderr_closed: Receiver<()>,
+    moves: usize,
+}
+
+impl Process {
+    fn start(program: &CommandProgram, limit: usize) -> Result<Process, ExecutionError> {
+        program.check()?;
+        let (name, arguments) = program.command.split_first().ok_or_else(|| {
+            ExecutionError::InitializationError("The command is empty".to_string())
+        })?;
+        let mut command = Command::new(name);
+        command
+            .args(arguments)
+            .envs(&program.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(directory) = &program.working_dir {
+            command.current_dir(directory);
+        }
+        let mut child = command.spawn().map_err(|err| {
+            ExecutionError::InitializationError(format!("Could not start {name}: {err}"))
+        })?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ExecutionError::InitializationError("No pipes".to_string()));
+        };
+        // Reading on threads of their own, so a move can time out
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || read_lines(stdout, limit, &sender));
+        let kept = Arc::new(Mutex::new(String::new()));
+        let stderr_kept = kept.clone();
+        let (closing, stderr_closed) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let _closing = closing;
+            let mut stderr = stderr;
+            let mut buffer = [0; 4096];
+            while let Ok(read @ 1..) = stderr.read(&mut buffer) {
+                let mut kept = stderr_kept.lock().unwrap_or_else(PoisonError::into_inner);
+                if kept.len() < STDERR_LIMIT {
+                    kept.push_str(&String::from_utf8_lossy(&buffer[..read]));
+                }
+            }
+        });
+        Ok(Process {
+            child,
+            stdin,
+            lines,
+            stderr: kept,
+            stderr_closed,
+            moves: 0,
+        })
+    }
+    fn call(
+        &mut self,
+        program: &CommandProgram,
+        history: &[(bool, bool)],
+        storage: &Value,
+        context: Option<&MoveContext>,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        let result = self.exchange(program, history, storage, context, output);
+        self.moves += 1;
+        let mut stderr = self.stderr.lock().unwrap_or_else(PoisonError::into_inner);
+        output.push_str(&stderr);
+        stderr.clear();
+        result
+    }
+    fn exchange(
+        &mut self,
+        program: &CommandProgram,
+        history: &[(bool, bool)],
+        storage: &Value,
+        context: Option<&MoveContext>,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        let envelope = serde_json::to_string(&Envelope {
+            version: ENVELOPE_VERSION,
+            history,
+            storage,
+            context,
+        })
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+        if writeln!(self.stdin, "{envelope}")
+            .and_then(|()| self.stdin.flush())
+            .is_err()
+        {
+            return Err(self.died());
+        }
+        let timeout = if self.moves == 0 {
+            program.startup_timeout
+        } else {
+            program.move_timeout
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let line = match deadline {
+                Some(deadline) => {
+                    match self
+                        .lines
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(line) => line,
+                        Err(RecvTimeoutError::Timeout) => {
+                            return Err(ExecutionError::Timeout(format!(
+                                "The move took longer than {}s",
+                                timeout.unwrap_or_default().as_secs_f64()
+                            )))
+                        }
+                        Err(RecvTimeoutError::Disconnected) => return Err(self.died()),
+                    }
+                }
+                None => match self.lines.recv() {
+                    Ok(line) => line,
+                    Err(_) => return Err(self.died()),
+                },
+            };
+            match line {
+                Line::Move(decision) => return Ok(decision),
+                Line::Output(line) => {
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+            }
+        }
+    }
+    /// What happened to a program that stopped answering
+    fn died(&mut self) -> ExecutionError {
+        let _ = self.child.kill();
+        let message = match self.child.wait() {
+            Ok(status) => worker::describe(status),
+            Err(err) => format!("Crashed: {err}"),
+        };
+        // Wait for the last words, which go in the error rather than the output
+        let _ = self.stderr_closed.recv_timeout(STDERR_GRACE);
+        let stderr =
+            std::mem::take(&mut *self.stderr.lock().unwrap_or_else(PoisonError::into_inner));
+        ExecutionError::RuntimeError(Diagnostic::new(message).with_traceback(Some(stderr)))
+    }
+}
+
+/// Send what a program prints on stdout, a line at a time. Anything that
+/// isn't a move is output, and output past `limit` bytes is dropped without
+/// holding more than a line of it
+fn read_lines(stdout: impl Read, limit: usize, sender: &Sender<Line>) {
+    let mut stdout = BufReader::new(stdout);
+    let mut room = limit;
+    loop {
+        let longest = room.saturating_add(MOVE_LIMIT);
+        let mut line = Vec::new();
+        match (&mut stdout)
+            .take(longest as u64 + 1)
+            .read_until(b'\n', &mut line)
+        {
+            Ok(1..) => {}
+            Ok(0) | Err(_) => break,
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        } else if line.len() > longest {
+            // Neither a move nor output we have room for
+            room = 0;
+            if stdout.skip_until(b'\n').is_err() {
+                break;
+            }
+            continue;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = match serde_json::from_str::<Move>(&line) {
+            Ok(decision) => Line::Move(decision),
+            // With its newline
+            Err(_) if line.len() < room => {
+                room -= line.len() + 1;
+                Line::Output(line.into_owned())
+            }
+            Err(_) => {
+                room = 0;
+                continue;
+            }
+        };
+        if sender.send(line).is_err() {
+            break;
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::debug_log;
+
+    fn shell(script: &str) -> CommandProgram {
+        CommandProgram::new(vec!["sh".to_string(), "-c".to_string(), script.to_string()])
+    }
+
+    fn play(
+        session: &mut Session,
+        program: &CommandProgram,
+        history: &[(bool, bool)],
+    ) -> (Result<Move, ExecutionError>, String) {
+        let mut output = String::new();
+        let result = session.next(
+            program,
+            history,
+            &Value::from(""),
+            None,
+            debug_log::DEFAULT_LIMIT,
+            &mut output,
+        );
+        (result, output)
+    }
+
+    #[test]
+    fn the_identity_is_read_once() {
+        let script = crate::testing::scratch("identity.sh");
+        fs::write(&script, "echo one").unwrap();
+        let program = CommandProgram::new(vec![
+            "sh".to_string(),
+            script.to_string_lossy().into_owned(),
+        ]);
+        let identity = program.identity().to_vec();
+        fs::write(&script, "echo two").unwrap();
+        assert_eq!(program.clone().identity(), identity);
+        let rebuilt = CommandProgram::new(program.command.clone());
+        assert_ne!(rebuilt.identity(), identity);
+        let _ = fs::remove_file(script);
+    }
+
+    #[test]
+    fn the_identity_covers_what_the_program_runs_with() {
+        let program = shell("true");
+        let identity = program.identity().to_vec();
+        let env = BTreeMap::from([("LEVEL".to_string(), "hard".to_string())]);
+        let changed = [
+            program.clone().with_env(env),
+            program.clone().with_working_dir(Some(std::env::temp_dir())),
+        ];
+        for changed in changed {
+            assert_ne!(changed.identity(), identity, "{changed:?}");
+        }
+        assert_eq!(shell("true").identity(), identity);
+
+        // The program itself, not just its name
+        let binary = crate::testing::scratch("identity-binary");
+        fs::write(&binary, "#!/bin/sh\necho one\n").unwrap();
+        let command = vec![binary.to_string_lossy().into_owned()];
+        let identity = CommandProgram::new(command.clone()).identity().to_vec();
+        fs::write(&binary, "#!/bin/sh\necho two\n").unwrap();
+        assert_ne!(CommandProgram::new(command).identity(), identity);
+        let _ = fs::remove_file(binary);
+    }
+
+    #[test]
+    fn programs_keep_running_through_a_game() {
+        let program = shell(
+            r#"n=0; while read line; do n=$((n+1)); echo "move $n"; echo "{\"action\": true, \"storage\": $n}"; done"#,
+        );
+        let mut session = Session::default();
+        for (history, storage) in [(&[][..], 1), (&[(true, true)][..], 2), (&[][..], 1)] {
+            let (result, output) = play(&mut session, &program, history);
+            let decision = result.unwrap();
+            assert!(decision.action);
+            assert_eq!(decision.storage, Value::from(storage));
+            assert_eq!(output, format!("move {storage}\n"));
+        }
+    }
+
+    #[test]
+    fn programs_get_the_envelope() {
+        let program =
+            shell(r#"read line; echo "$line" >&2; echo '{"action": false, "storage": ""}'"#);
+        let (result, output) = play(&mut Session::default(), &program, &[(true, false)]);
+        assert!(!result.unwrap().action);
+        let envelope: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(
+            envelope,
+            serde_json::json!({"version": 1, "history": [[true, false]], "storage": ""})
+        );
+    }
+
+    #[test]
+    fn output_past_the_limit_is_dropped() {
+        // A line over the limit and one with no end that's over the move limit too
+        let program = shell(
+            r#"read line; echo one; echo two; echo three; echo '{"action": true, "storage": ""}'; head -c 20000000 /dev/zero | tr '\0' x; echo; echo '{"action": false, "storage": ""}'"#,
+        );
+        let mut session = Session::default();
+        let mut output = String::new();
+        let result = session.next(&program, &[], &Value::from(""), None, 9, &mut output);
+        assert!(result.unwrap().action);
+        assert_eq!(output, "one\ntwo\n");
+        let mut output = String::new();
+        let result = session.next(
+            &program,
+            &[(true, true)],
+            &Value::from(""),
+            None,
+            9,
+            &mut output,
+        );
+        assert!(!result.unwrap().action);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn last_words_go_in_the_error_once() {
+        let program = shell("read line; echo 'last words' >&2; exit 3");
+        for _ in 0..20 {
+            let (result, output) = play(&mut Session::default(), &program, &[]);
+            let Err(ExecutionError::RuntimeError(diagnostic)) = result else {
+                panic!("{result:?}");
+            };
+            assert_eq!(diagnostic.traceback.as_deref(), Some("last words\n"));
+            assert_eq!(output, "");
+        }
+    }
+
+    #[test]
+    fn slow_moves_time_out_and_start_over() {
+        let program = shell(r#"read line; case "$line" in *'"history":[]'*) exec sleep 5;; esac; echo '{"action": true, "storage": ""}'"#)
+            .with_startup_timeout(Some(Duration::from_millis(200)));
+        let mut session = Session::default();
+        let started = Instant::now();
+        let (result, _) = play(&mut session, &program, &[]);
+        assert!(
+            matches!(result, Err(ExecutionError::Timeout(_))),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // A new program, which answers straight away when there is history
+        let (result, _) = play(&mut session, &program, &[(false, false)]);
+        assert!(result.unwrap().action);
+    }
+
+    #[test]
+    fn programs_that_can_not_start_lose_the_move() {
+        let program = CommandProgram::new(vec!["/nonexistent/program".to_string()]);
+        let (result, _) = play(&mut Session::default(), &program, &[]);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::InitializationError(_))
+        ));
+        let (result, _) = play(&mut Session::default(), &CommandProgram::new(vec![]), &[]);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::InitializationError(_))
+        ));
+    }
+}
@@ -1,337 +1,1229 @@
 //! This module contains the core logic for the
 //! Iterated Prisoner's Dilemma Tournament
 
-// use boa_engine::{
-//     js_string,
-//     object::{builtins::JsArray, ObjectInitializer},
-//     property::Attribute,
-//     Context, JsValue, Source,
-// };
 #![deny(clippy::unwrap_used, clippy::expect_used)]
 
-use mlua::{Function, Lua, LuaSerdeExt};
+use boa_engine::{js_string, Context, JsValue, Source};
+use mlua::{Function, Lua, LuaSerdeExt, Table};
 use rustpython::InterpreterConfig;
+use rustpython_vm::py_serde::{PyObjectDeserializer, PyObjectSerializer};
 use rustpython_vm::{
-    builtins::PyTuple, convert::IntoObject, function::FuncArgs, Interpreter, PyObjectRef, Settings,
+    builtins::{PyBaseExceptionRef, PyDict, PyDictRef, PyStrInterned, PyTuple, PyType, PyTypeRef},
+    convert::IntoObject,
+    function::FuncArgs,
+    types::PyTypeFlags,
+    AsObject, Interpreter, PyObjectRef, PyResult, Settings, VirtualMachine,
 };
+use serde::de::DeserializeSeed;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use wasmer::{imports, Instance, Module, Store};
-use wasmer::{Function as WASMFunction, Value as WASMValue};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::error::ExecutionError;
+use crate::cache::MatchCache;
+use crate::checkpoint::Checkpoint;
+use crate::command::{self, CommandProgram};
+use crate::debug_log::{self, DebugLog};
+use crate::error::{CheckpointError, Diagnostic, ExecutionError, ReplayError};
+use crate::observer::{Observers, ProgressEvent, Round, TournamentObserver};
+use crate::piston::{self, MatchSession, PistonInput, PistonProgram};
+use crate::replay::ReplayRecorder;
+use crate::rng::Rng;
+use crate::sandbox::{self, Sandbox};
+use crate::wasm_utils;
+use crate::worker::{self, Isolation};
+
+/// How many Python interpreters each thread keeps around
+const PYTHON_POOL_SIZE: usize = 16;
+
+/// The game of interpreters that were warmed up and haven't run anything yet
+const NO_GAME: u64 = 0;
+
+static GAMES: AtomicU64 = AtomicU64::new(NO_GAME + 1);
+
+/// A new game for one player, see `Executor::run_in_game`
+pub(crate) fn next_game() -> u64 {
+    GAMES.fetch_add(1, Ordering::Relaxed)
+}
+
+/// An interpreter set up for one sandbox, playing one game of one program
+struct PooledPython {
+    /// A SHA-256 of the sandbox
+    sandbox: String,
+    /// See `python_key`, empty while the interpreter is spare
+    key: String,
+    game: u64,
+    /// Before the interpreter, so it's dropped first
+    snapshot: Snapshot,
+    interpreter: Interpreter,
+    /// What the program imports or names that it may not, checked once
+    forbidden: Option<ExecutionError>,
+}
+
+/// What a strategy can change in an interpreter that was just set up:
+/// `sys.modules`, the dicts of the modules in it (which holds builtins and
+/// `sys`) and the attributes of the classes they define
+struct Snapshot {
+    modules: Vec<(PyObjectRef, PyObjectRef)>,
+    dicts: Vec<(PyDictRef, Vec<(PyObjectRef, PyObjectRef)>)>,
+    classes: Vec<(PyTypeRef, Vec<(&'static PyStrInterned, PyObjectRef)>)>,
+}
+
+impl Snapshot {
+    fn take(vm: &VirtualMachine) -> PyResult<Snapshot> {
+        let modules = vm
+            .sys_module
+            .get_attr("modules", vm)?
+            .downcast::<PyDict>()
+            .map_err(|_| vm.new_type_error("sys.modules is not a dict".to_string()))?;
+        let modules: Vec<_> = modules.into_iter().collect();
+        let mut dicts = vec![];
+        let mut classes = vec![];
+        for (_, module) in &modules {
+            let Some(dict) = module.dict() else {
+                continue;
+            };
+            let items: Vec<_> = (&*dict).into_iter().collect();
+            for (_, value) in &items {
+                if let Some(class) = value.payload::<PyType>() {
+                    if class.slots.flags.has_feature(PyTypeFlags::HEAPTYPE) {
+                        let class = value
+                            .clone()
+                            .downcast::<PyType>()
+                            .map_err(|_| vm.new_type_error("not a class".to_string()))?;
+                        let attributes = class
+                            .attributes
+                            .read()
+                            .iter()
+                            .map(|(name, value)| (*name, value.clone()))
+                            .collect();
+                        classes.push((class, attributes));
+                    }
+                }
+            }
+            dicts.push((dict, items));
+        }
+        Ok(Snapshot {
+            modules,
+            dicts,
+            classes,
+        })
+    }
+    /// Put everything back the way it was when the snapshot was taken
+    fn restore(&self, vm: &VirtualMachine) -> PyResult<()> {
+        let modules = vm
+            .sys_module
+            .get_attr("modules", vm)?
+            .downcast::<PyDict>()
+            .map_err(|_| vm.new_type_error("sys.modules is not a dict".to_string()))?;
+        modules.clear();
+        for (name, module) in &self.modules {
+            modules.set_item(&**name, module.clone(), vm)?;
+        }
+        for (dict, items) in &self.dicts {
+            dict.clear();
+            for (name, value) in items {
+                dict.set_item(&**name, value.clone(), vm)?;
+            }
+        }
+        for (class, attributes) in &self.classes {
+            let mut current = class.attributes.write();
+            current.clear();
+            current.extend(attributes.iter().cloned());
+        }
+        Ok(())
+    }
+}
 
 thread_local! {
-    pub static PYTHON: Interpreter = {
-        // let mut settings = Settings::default();
-        // settings.utf8_mode = 0;
-        // settings.import_site = false;
-        InterpreterConfig::new()
-            .settings(Settings::default())
-            .init_stdlib()
-            // .settings(settings)
-            // .init_hook(Box::new(|vm| {
-            //     vm.add_native_modules(rustpython_stdlib::get_module_inits().filter(
-            //         |(name, _)| {
-            //             name == "builtins"  // Surely we don't need builtins
-            //                 || name == "json"
-            //                 || name == "math"
-            //                 || name == "random"
-            //                 || name == "re"
-            //         },
-            //     ));
-            // }))
-            .interpreter()
+    /// Every Python program gets its own interpreter for every game (and
+    /// each of two players running the same program one of its own), so
+    /// strategies can't reach each other or their earlier games through
+    /// `sys.modules`, builtins or imported modules. Starting one is slow, so
+    /// when a game is over its interpreter is put back the way it was set up
+    /// (see `Snapshot`) and kept as a spare for the next game in the same
+    /// sandbox. Every thread keeps the most recently used ones, keyed by a
+    /// SHA-256 of the program and the sandbox, and the game. Warming up
+    /// leaves a spare that the next game of the program takes over
+    static PYTHON: RefCell<Vec<PooledPython>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Programs are submitted, so the key has to be one they can't collide on:
+/// an interpreter keeps what the first program's static scan found
+fn python_key(program: &str, sandbox: &Sandbox) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(sandbox_key(sandbox));
+    hasher.update([0]);
+    hasher.update(program);
+    hex(&hasher.finalize())
+}
+
+fn sandbox_key(sandbox: &Sandbox) -> String {
+    hex(&Sha256::digest(format!("{sandbox:?}")))
+}
+
+fn python_interpreter(sandbox: &Sandbox) -> Result<PooledPython, ExecutionError> {
+    let interpreter = InterpreterConfig::new()
+        .settings(Settings::default())
+        .init_stdlib()
+        .interpreter();
+    let snapshot = interpreter
+        .enter(|vm| {
+            // Every move seeds `random` and captures output into
+            // an `io.StringIO`, so import them before the sandbox does
+            vm.import("random", 0)?;
+            vm.import("io", 0)?;
+            sandbox.install(vm)?;
+            Snapshot::take(vm)
+        })
+        .map_err(|_| ExecutionError::InitializationError("Sandboxing failed".to_string()))?;
+    Ok(PooledPython {
+        sandbox: sandbox_key(sandbox),
+        key: String::new(),
+        game: NO_GAME,
+        snapshot,
+        interpreter,
+        forbidden: None,
+    })
+}
+
+/// Take the interpreter of `program` for `game` out of the pool: the one
+/// already playing the game, a spare warmed up for the program, any spare
+/// for the sandbox, or a new one
+fn take_python(
+    program: &str,
+    sandbox: &Sandbox,
+    game: u64,
+) -> Result<PooledPython, ExecutionError> {
+    let key = python_key(program, sandbox);
+    let sandbox_key = sandbox_key(sandbox);
+    let pooled = PYTHON.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let index = pool
+            .iter()
+            .position(|pooled| pooled.key == key && pooled.game == game)
+            .or_else(|| {
+                pool.iter()
+                    .position(|pooled| pooled.key == key && pooled.game == NO_GAME)
+            })
+            .or_else(|| {
+                pool.iter()
+                    .position(|pooled| pooled.sandbox == sandbox_key && pooled.key.is_empty())
+            })?;
+        Some(pool.remove(index))
+    });
+    let mut pooled = match pooled {
+        Some(pooled) => pooled,
+        None => python_interpreter(sandbox)?,
     };
+    if pooled.key.is_empty() {
+        pooled.key = key;
+        pooled.forbidden = sandbox.forbidden(program);
+    }
+    pooled.game = game;
+    Ok(pooled)
+}
+
+/// Run `f` in the interpreter of `program` for `game`
+fn with_python<R>(
+    program: &str,
+    sandbox: &Sandbox,
+    game: u64,
+    f: impl FnOnce(&VirtualMachine) -> Result<R, ExecutionError>,
+) -> Result<R, ExecutionError> {
+    let pooled = take_python(program, sandbox, game)?;
+    let result = match &pooled.forbidden {
+        Some(forbidden) => Err(forbidden.clone()),
+        None => pooled.interpreter.enter(|vm| {
+            vm.recursion_limit.set(sandbox.recursion_limit);
+            f(vm)
+        }),
+    };
+    pool_python(pooled);
+    result
+}
+
+fn pool_python(pooled: PooledPython) {
+    PYTHON.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() >= PYTHON_POOL_SIZE {
+            pool.remove(0);
+        }
+        pool.push(pooled);
+    });
+}
+
+/// Leave a spare interpreter for `program` for its next game
+fn warm_up_python(program: &str, sandbox: &Sandbox) {
+    let key = python_key(program, sandbox);
+    let spare = PYTHON.with(|pool| {
+        pool.borrow()
+            .iter()
+            .any(|pooled| pooled.key == key && pooled.game == NO_GAME)
+    });
+    if !spare {
+        if let Ok(pooled) = take_python(program, sandbox, NO_GAME) {
+            pool_python(pooled);
+        }
+    }
+}
+
+/// Put the interpreters of a game that's over back the way they were set
+/// up, as spares, and drop the ones that can't be
+pub(crate) fn end_game(game: u64) {
+    if game == NO_GAME {
+        return;
+    }
+    let over: Vec<PooledPython> = PYTHON.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let (over, playing) = pool.drain(..).partition(|pooled| pooled.game == game);
+        *pool = playing;
+        over
+    });
+    for mut pooled in over {
+        let restored = pooled
+            .interpreter
+            .enter(|vm| pooled.snapshot.restore(vm))
+            .is_ok();
+        if restored {
+            pooled.key.clear();
+            pooled.game = NO_GAME;
+            pooled.forbidden = None;
+            pool_python(pooled);
+        }
+    }
 }
 /// This enum represents the different player-agents
 /// that can play in the tournament
 /// The `history` parameter will always be a list of tuples
 /// where each tuple contains 2 boolean values representing
 /// the actions of (you, them) in the previous rounds
-/// The `storage` parameter will be a string that can be used
-/// to store any information between rounds
+/// The `storage` parameter can be used to store any information
+/// between rounds. It starts as an empty string, but can be any JSON
+/// value, which comes in as a native table, dict, list or object
 /// The return value of the function should be a tuple of 2 values
 /// where the first value is a boolean representing the action of the player
-/// and the second value is a string representing the storage value
-#[derive(Clone, Debug)]
+/// and the second value is the new storage value. With career storage,
+/// a third one is the new career (see `TournamentConfig::career_storage`)
+/// Strategies can take a third `context` parameter, see `MoveContext`
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Executor {
-    /// Lua programs must only contain ae
+    /// Lua programs must only contain an
     /// anonymous function definition that takes
-    /// 2 arguments: `history` and `storage`
-    /// and returns a tuple of 2 values: `action` and `storage`
+    /// 2 or 3 arguments: `history`, `storage` and optionally `context`
+    /// and returns 2 or 3 values: `action`, `storage` and optionally `career`
     Lua(String),
     /// Python programs must only contain a single
     /// function definition named `main` that takes
-    /// 2 arguments: `history` and `storage`
-    /// and returns a tuple of 2 values: `action` and `storage`
+    /// 2 or 3 arguments: `history`, `storage` and optionally `context`
+    /// and returns a tuple of 2 or 3 values: `action`, `storage` and
+    /// optionally `career`
     Python(String),
+    /// JavaScript programs must define a function named `main`
+    /// that takes 2 or 3 arguments: `history`, `storage` and optionally
+    /// `context` and returns an array of 2 or 3 values:
+    /// `[action, storage]` or `[action, storage, career]`
     JavaScript(String),
-    /// We use JSON and sys.argv to pass the history and storage
+    /// We use JSON on stdin to pass the history, storage and context
     /// to the Piston executor. Your program should output
     /// your next move and storage as a JSON object of
-    /// {action: boolean, storage: string} If your language does not support
+    /// {action: boolean, storage: any, career?: any} If your language does not support
     /// JSON input built-in, well you're screwed
-    /// (language, program)
-    Piston(String, String),
-    WASM(String),
+    /// See `piston` for the envelope, match mode and where it runs
+    Piston(PistonProgram),
+    /// A WASM module (binary or text format).
+    /// The history and storage are passed in as a JSON object of
+    /// {history: [[boolean, boolean]], storage: any, context?: object}
+    /// and the module returns the same JSON as the Piston executor.
+    /// See `wasm_utils` for the exports your module needs
+    WASM(Vec<u8>),
+    /// A local program, started once per game, that gets the same JSON as
+    /// the Piston executor on a line of stdin before every move and answers
+    /// with the same JSON on a line of stdout. See `command`
+    Command(CommandProgram),
 }
 impl Executor {
-    // (you, them)
-    // true = cooperate, false = defect
-    pub fn run(
-        &self,
-        history: &[(bool, bool)],
-        storage: String,
-    ) -> Result<(bool, String), ExecutionError> {
+    /// A SHA-256 of the language and the program,
+    /// so the same strategy always gets the same fingerprint
+    /// (a pinned Piston version counts as part of the language)
+    pub fn fingerprint(&self) -> String {
+        let (language, program): (Cow<str>, &[u8]) = match self {
+            Executor::Lua(program) => ("lua".into(), program.as_bytes()),
+            Executor::Python(program) => ("python".into(), program.as_bytes()),
+            Executor::JavaScript(program) => ("javascript".into(), program.as_bytes()),
+            Executor::Piston(piston) => (piston.runtime().into(), piston.program.as_bytes()),
+            Executor::WASM(module) => ("wasm".into(), module),
+            Executor::Command(program) => ("command".into(), program.identity()),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(language.as_bytes());
+        hasher.update([0]);
+        hasher.update(program);
+        hex(&hasher.finalize())
+    }
+    /// Check that the program parses and has the entry point it needs,
+    /// without making a move (Lua and Python still run the top level of
+    /// the program). Piston programs can't be checked without running them,
+    /// and local programs are only checked for a command to start
+    pub fn validate(&self) -> Result<(), ExecutionError> {
+        self.validate_sandboxed(&Sandbox::default())
+    }
+    /// Like `validate`, with Python imports checked against `sandbox`
+    pub fn validate_sandboxed(&self, sandbox: &Sandbox) -> Result<(), ExecutionError> {
         match self {
             Executor::Lua(program) => {
                 let lua = Lua::new();
                 lua.sandbox(true).map_err(|_| {
                     ExecutionError::InitializationError("Sandboxing failed".to_string())
                 })?;
-                // Set memory to 1GB
-                lua.set_memory_limit(1024 * 1024 * 1024).map_err(|_| {
-                    ExecutionError::InitializationError("Memory limit failed".to_string())
-                })?;
-                let function: Function = lua
-                    .load(program)
-                    .eval()
-                    .map_err(|_| ExecutionError::SyntaxError)?;
-
-                function
-                    .call::<(bool, String)>((
-                        #[allow(clippy::expect_used)]
-                        lua.to_value(&history).expect("Could not serialize history"),
-                        #[allow(clippy::expect_used)]
-                        lua.to_value(&storage).expect("Could not serialize storage"),
-                    ))
-                    .map_err(|err| ExecutionError::DeserializationError(err.to_string()))
+                match lua.load(program).set_name(LUA_CHUNK).eval::<mlua::Value>() {
+                    Ok(mlua::Value::Function(_)) => Ok(()),
+                    Ok(_) => Err(ExecutionError::InitializationError(
+                        "The program must evaluate to a function".to_string(),
+                    )),
+                    Err(err) => Err(lua_error(&err)),
+                }
             }
             Executor::Python(program) => {
-                let output: Result<(bool, String), ExecutionError> = PYTHON.with(|interpreter| {
-                    interpreter.enter(|vm| {
-                        let scope = vm.new_scope_with_builtins();
-                        vm.run_block_expr(scope.clone(), program)
-                            .map_err(|_| ExecutionError::SyntaxError)?;
-                        let function = scope.globals.get_item("main", vm).map_err(|_| {
-                            ExecutionError::InitializationError(
-                                "Main function not found".to_string(),
-                            )
-                        })?;
-
-                        let args_history: PyObjectRef = vm
-                            .ctx
-                            .new_list(
-                                history
-                                    .into_iter()
-                                    .map(|(you, them)| {
-                                        vm.ctx
-                                            .new_tuple(vec![
-                                                vm.ctx.new_bool(*you).into(),
-                                                vm.ctx.new_bool(*them).into(),
-                                            ])
-                                            .into()
-                                    })
-                                    .collect::<Vec<PyObjectRef>>(),
-                            )
-                            .into();
-                        let result = function
-                            .to_callable()
-                            .ok_or(ExecutionError::InitializationError(
-                                "Expected a function".to_string(),
-                            ))
-                            .map(|callable| {
-                                callable.invoke(
-                                    FuncArgs::from(vec![
-                                        args_history,
-                                        vm.ctx.new_str(storage).into(),
-                                    ]),
-                                    vm,
-                                )
-                            })?
-                            .map_err(|_| ExecutionError::RuntimeError("Wtf".to_string()))?;
-                        let result_tuple = result
-                            .downcast::<PyTuple>()
-                            .map_err(|_| {
-                                ExecutionError::DeserializationError(
-                                    "Could not deserialize output into a tuple".to_string(),
-                                )
-                            })?
-                            .into_object();
-                        let result_tuple = result_tuple.to_sequence();
-                        let action: bool = result_tuple
-                            .get_item(0, vm)
-                            .map_err(|_| {
-                                ExecutionError::DeserializationError("Could not des".to_string())
-                            })?
-                            .try_into_value(vm)
-                            .map_err(|_| {
-                                ExecutionError::DeserializationError("Could not des".to_string())
-                            })?;
-                        let new_storage: String = result_tuple
-                            .get_item(1, vm)
-                            .map_err(|_| {
-                                ExecutionError::DeserializationError("Could not des".to_string())
-                            })?
-                            .try_into_value(vm)
-                            .map_err(|_| {
-                                ExecutionError::DeserializationError("Could not des".to_string())
-                            })?;
-                        Ok((action, new_storage))
-                    })
+                // Running the top level is like a game of its own
+                let game = next_game();
+                let result = with_python(program, sandbox, game, |vm| {
+                    let scope =
+                        sandbox::strategy_scope(vm).map_err(|err| python_error(vm, &err))?;
+                    vm.run_block_expr(scope.clone(), program)
+                        .map_err(|err| python_error(vm, &err))?;
+                    scope
+                        .globals
+                        .get_item("main", vm)
+                        .ok()
+                        .filter(|main| main.is_callable())
+                        .map(|_| ())
+                        .ok_or(ExecutionError::InitializationError(
+                            "Main function not found".to_string(),
+                        ))
                 });
-                output
+                end_game(game);
+                result
+            }
+            Executor::JavaScript(program) => {
+                let mut context = Context::default();
+                context
+                    .eval(Source::from_bytes(program))
+                    .map_err(|err| js_error(&err))?;
+                context
+                    .global_object()
+                    .get(js_string!("main"), &mut context)
+                    .ok()
+                    .filter(JsValue::is_callable)
+                    .map(|_| ())
+                    .ok_or(ExecutionError::InitializationError(
+                        "Main function not found".to_string(),
+                    ))
+            }
+            Executor::WASM(module) => wasm_utils::check(module),
+            Executor::Piston(..) => Ok(()),
+            Executor::Command(program) => program.check(),
+        }
+    }
+    /// Get the interpreter of the program ready on this thread,
+    /// so the first move isn't much slower than the others
+    pub(crate) fn warm_up(&self, sandbox: &Sandbox) {
+        if let Executor::Python(program) = self {
+            warm_up_python(program, sandbox);
+        }
+    }
+    // (you, them)
+    // true = cooperate, false = defect
+    pub fn run(&self, history: &[(bool, bool)], storage: Value) -> Result<Move, ExecutionError> {
+        self.run_seeded(history, storage, 0, None)
+    }
+    /// Like `run`, with the random number generator of the language
+    /// (`math.random`, `random`, `Math.random` or the `env.random` import
+    /// of WASM) seeded with `seed`. Piston and local programs get no seed.
+    /// The `context` is passed as a third argument (Python only passes it
+    /// if `main` takes one), or as `context` in the JSON for WASM,
+    /// Piston and local programs
+    pub fn run_seeded(
+        &self,
+        history: &[(bool, bool)],
+        storage: Value,
+        seed: u64,
+        context: Option<&MoveContext>,
+    ) -> Result<Move, ExecutionError> {
+        self.run_logged(
+            history,
+            storage,
+            seed,
+            context,
+            &Sandbox::default(),
+            &mut String::new(),
+        )
+    }
+    /// Like `run_seeded`, within the limits of `sandbox`, appending whatever
+    /// the strategy prints to `output` (also when the move fails).
+    /// See `debug_log`
+    pub fn run_logged(
+        &self,
+        history: &[(bool, bool)],
+        storage: Value,
+        seed: u64,
+        context: Option<&MoveContext>,
+        sandbox: &Sandbox,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        // A move on its own is a game on its own
+        let game = next_game();
+        let result = self.run_in_game(game, history, storage, seed, context, sandbox, output);
+        end_game(game);
+        result
+    }
+    /// Like `run_logged`, in the Python interpreter of `game` (see
+    /// `next_game`), which keeps what the program did in earlier moves of
+    /// the game but nothing else
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn run_in_game(
+        &self,
+        game: u64,
+        history: &[(bool, bool)],
+        storage: Value,
+        seed: u64,
+        context: Option<&MoveContext>,
+        sandbox: &Sandbox,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        match self {
+            Executor::Lua(program) => {
+                let printed = Rc::new(RefCell::new(String::new()));
+                let result = lua_move(program, history, storage, seed, context, &printed);
+                output.push_str(&printed.borrow());
+                result
             }
-            Executor::JavaScript(_program) => {
-                // let mut context = Context::default();
-                // // Evaluate function definition
-                // context.eval(Source::from_bytes(program)).unwrap();
-                // // Create an object that can be used in eval calls.
-                // let history = {
-                //     history.into_iter().map(|x| {
-                //         Into::<JsValue>::into(JsArray::from_iter::<Vec<JsValue>>(
-                //             vec![JsValue::Boolean(x.0), JsValue::Boolean(x.1)],
-                //             &mut context,
-                //         ))
-                //     })
-                // };
-                // // let args_history = ;
-                // let arg = ObjectInitializer::new(&mut context)
-                //     .property(
-                //         js_string!("history"),
-                //         JsArray::from_iter(history.into_iter(), &mut context),
-                //         Attribute::READONLY,
-                //     )
-                //     .property(
-                //         js_string!("storage"),
-                //         js_string!(storage),
-                //         Attribute::READONLY,
-                //     )
-                //     .build();
-                // context
-                //     .register_global_property(js_string!("input"), arg, Attribute::all())
-                //     .expect("property shouldn't exist");
-
-                // let value = context
-                //     .eval(Source::from_bytes("main(input.history, input.storage)"))
-                //     .unwrap();
-                todo!()
+            Executor::Python(program) => with_python(program, sandbox, game, |vm| {
+                // The interpreter outlives the move, so stdout and
+                // stderr only point at the buffer while it runs
+                let sys = vm.sys_module.as_object();
+                let saved = (sys.get_attr("stdout", vm), sys.get_attr("stderr", vm));
+                let buffer = vm
+                    .import("io", 0)
+                    .and_then(|io| io.get_attr("StringIO", vm))
+                    .and_then(|string_io| string_io.call((), vm))
+                    .and_then(|buffer| {
+                        sys.set_attr("stdout", buffer.clone(), vm)?;
+                        sys.set_attr("stderr", buffer.clone(), vm)?;
+                        Ok(buffer)
+                    })
+                    .map_err(|_| {
+                        ExecutionError::InitializationError("Capturing output failed".to_string())
+                    })?;
+                let result = python_move(vm, program, history, storage, seed, context);
+                if let Ok(printed) = vm.call_method(&buffer, "getvalue", ()) {
+                    if let Ok(printed) = printed.str(vm) {
+                        output.push_str(printed.as_str());
+                    }
+                }
+                if let (Ok(stdout), Ok(stderr)) = saved {
+                    let _ = sys.set_attr("stdout", stdout, vm);
+                    let _ = sys.set_attr("stderr", stderr, vm);
+                }
+                result
+            }),
+            Executor::JavaScript(program) => {
+                let mut js = Context::default();
+                js.eval(Source::from_bytes(&seeded_math_random(seed)))
+                    .and_then(|_| js.eval(Source::from_bytes(JS_CONSOLE)))
+                    .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+                let result = js_move(&mut js, program, history, storage, context);
+                if let Some(printed) = js
+                    .eval(Source::from_bytes(
+                        "__output.map(function (line) { return line + '\\n'; }).join('')",
+                    ))
+                    .ok()
+                    .and_then(|printed| {
+                        printed
+                            .as_string()
+                            .map(|printed| printed.to_std_string_escaped())
+                    })
+                {
+                    output.push_str(&printed);
+                }
+                result
             }
-            Executor::WASM(_) => {
-                // let module_wat = r#"
-                // (module
-                //   (type $t0 (func (param i32) (result i32)))
-                //   (func $add_one (export "add_one") (type $t0) (param $p0 i32) (result i32)
-                //     local.get $p0
-                //     i32.const 1
-                //     i32.add))
-                // "#;
-
-                // let mut store = Store::default();
-                // let module = Module::new(&store, &module_wat).map_err(|_| {
-                //     ExecutionError::InitializationError("Could not initialize WASM module".into())
-                // })?;
-                // // The module doesn't import anything, so we create an empty import object.
-                // let import_object = imports! {};
-                // let instance =
-                //     Instance::new(&mut store, &module, &import_object).map_err(|_| {
-                //         ExecutionError::InitializationError("Could not initialize WASM".to_string())
-                //     })?;
-
-                // let main = instance.exports.get_function("main").map_err(|_| {
-                //     ExecutionError::InitializationError("Could not get main function".to_string())
-                // })?;
-                // main.call(
-                //     &mut store,
-                //     &[WASMValue::FuncRef(Some(WASMFunction::new_typed(
-                //         &mut store,
-                //         |index: usize, which: bool| {
-                //             if which {
-                //                 history[index].0
-                //             } else {
-                //                 history[index].1
-                //             }
-                //         },
-                //     )))],
-                // );
-                todo!()
+            Executor::WASM(module) => {
+                let mut input = serde_json::json!({
+                    "history": history,
+                    "storage": storage,
+                });
+                if let Some(context) = context {
+                    input["context"] = serde_json::json!(context);
+                }
+                let input = serde_json::to_vec(&input)
+                    .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+                serde_json::from_slice(&wasm_utils::call(module, &input, seed, output)?)
+                    .map_err(|err| ExecutionError::DeserializationError(err.to_string()))
             }
-            Executor::Piston(language, program) => tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(run_piston(language, program, history, storage)),
+            Executor::Piston(piston) => piston::run(piston, history, storage, context, output),
+            // The program only lives for this one move
+            Executor::Command(program) => command::Session::default().next(
+                program,
+                history,
+                &storage,
+                context,
+                debug_log::DEFAULT_LIMIT,
+                output,
+            ),
         }
     }
 }
-#[derive(serde::Deserialize)]
-struct Output {
-    action: bool,
-    storage: String,
+/// Lua `print`, into `printed`
+fn capture_print(lua: &Lua, printed: &Rc<RefCell<String>>) -> mlua::Result<()> {
+    let printed = printed.clone();
+    let print = lua.create_function(move |_, values: mlua::Variadic<mlua::Value>| {
+        let line = values
+            .iter()
+            .map(|value| value.to_string().unwrap_or_else(|_| format!("{value:?}")))
+            .collect::<Vec<_>>()
+            .join("\t");
+        let mut printed = printed.borrow_mut();
+        printed.push_str(&line);
+        printed.push('\n');
+        Ok(())
+    })?;
+    lua.globals().set("print", print)
 }
-async fn run_piston(
-    language: &String,
-    program: &String,
+fn lua_move(
+    program: &str,
     history: &[(bool, bool)],
-    storage: String,
-) -> Result<(bool, String), ExecutionError> {
-    let client = piston_rs::Client::new();
-    let executor = piston_rs::Executor::new()
-        .set_language(language)
-        .set_version("*")
-        .add_file(piston_rs::File::default().set_content(program))
-        .add_args(vec![
-            &serde_json::to_string(history).unwrap(),
-            &serde_json::to_string(&storage).unwrap(),
-        ]);
-
-    match client.execute(&executor).await {
-        Ok(response) => {
-            println!("Language: {}", response.language);
-            println!("Version: {}", response.version);
-
-            if let Some(c) = response.compile {
-                println!("Compilation: {}", c.output);
-            }
+    storage: Value,
+    seed: u64,
+    context: Option<&MoveContext>,
+    printed: &Rc<RefCell<String>>,
+) -> Result<Move, ExecutionError> {
+    let lua = Lua::new();
+    // Luau only takes a 32-bit seed
+    lua.globals()
+        .get::<Table>("math")
+        .and_then(|math| math.get::<Function>("randomseed"))
+        .and_then(|randomseed| randomseed.call::<()>(seed as i32))
+        .map_err(|_| ExecutionError::InitializationError("Seeding failed".to_string()))?;
+    // Sandboxing makes the globals read-only, so this goes first
+    capture_print(&lua, printed)
+        .map_err(|_| ExecutionError::InitializationError("Capturing output failed".to_string()))?;
+    lua.sandbox(true)
+        .map_err(|_| ExecutionError::InitializationError("Sandboxing failed".to_string()))?;
+    // Set memory to 1GB
+    lua.set_memory_limit(1024 * 1024 * 1024)
+        .map_err(|_| ExecutionError::InitializationError("Memory limit failed".to_string()))?;
+    let function: Function = lua
+        .load(program)
+        .set_name(LUA_CHUNK)
+        .eval()
+        .map_err(|err| lua_error(&err))?;
 
-            println!("Output: {}", response.run.output);
-            let output: Output = serde_json::from_str(&response.run.stdout).unwrap();
-            return Ok((output.action, output.storage));
-        }
-        Err(e) => {
-            return Err(ExecutionError::RuntimeError(e.to_string()));
-        }
+    // JSON null becomes nil rather than a sentinel
+    let options = mlua::SerializeOptions::new()
+        .serialize_none_to_null(false)
+        .serialize_unit_to_null(false);
+    let serialization = |err: mlua::Error| ExecutionError::DeserializationError(err.to_string());
+    let arguments = (
+        lua.to_value(&history).map_err(serialization)?,
+        lua.to_value_with(&storage, options)
+            .map_err(serialization)?,
+        lua.to_value_with(&context, options)
+            .map_err(serialization)?,
+    );
+    let (action, storage, career) = function
+        .call::<(bool, mlua::Value, mlua::Value)>(arguments)
+        .map_err(|err| lua_error(&err))?;
+    let storage = lua
+        .from_value(storage)
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+    let career = match career {
+        mlua::Value::Nil => None,
+        career => Some(
+            lua.from_value(career)
+                .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?,
+        ),
+    };
+    Ok(Move {
+        action,
+        storage,
+        career,
+    })
+}
+fn python_move(
+    vm: &VirtualMachine,
+    program: &str,
+    history: &[(bool, bool)],
+    storage: Value,
+    seed: u64,
+    context: Option<&MoveContext>,
+) -> Result<Move, ExecutionError> {
+    // The interpreter is reused for every move, so reseed each time
+    vm.import("random", 0)
+        .and_then(|random| random.get_attr("seed", vm))
+        .and_then(|random_seed| random_seed.call((vm.ctx.new_int(seed),), vm))
+        .map_err(|_| ExecutionError::InitializationError("Seeding failed".to_string()))?;
+    let scope = sandbox::strategy_scope(vm).map_err(|err| python_error(vm, &err))?;
+    vm.run_block_expr(scope.clone(), program)
+        .map_err(|err| python_error(vm, &err))?;
+    let function = scope
+        .globals
+        .get_item("main", vm)
+        .map_err(|_| ExecutionError::InitializationError("Main function not found".to_string()))?;
+
+    let args_storage = PyObjectDeserializer::new(vm)
+        .deserialize(storage)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+    let args_history: PyObjectRef = vm
+        .ctx
+        .new_list(
+            history
+                .iter()
+                .map(|(you, them)| {
+                    vm.ctx
+                        .new_tuple(vec![
+                            vm.ctx.new_bool(*you).into(),
+                            vm.ctx.new_bool(*them).into(),
+                        ])
+                        .into()
+                })
+                .collect::<Vec<PyObjectRef>>(),
+        )
+        .into();
+    let mut args = vec![args_history, args_storage];
+    // Older strategies only take two arguments
+    if let Some(context) = context.filter(|_| takes_context(&function, vm)) {
+        args.push(
+            PyObjectDeserializer::new(vm)
+                .deserialize(serde_json::json!(context))
+                .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?,
+        );
+    }
+    let result = function
+        .to_callable()
+        .ok_or(ExecutionError::InitializationError(
+            "Expected a function".to_string(),
+        ))
+        .map(|callable| callable.invoke(FuncArgs::from(args), vm))?
+        .map_err(|err| python_error(vm, &err))?;
+    let result_tuple = result
+        .downcast::<PyTuple>()
+        .map_err(|_| {
+            ExecutionError::DeserializationError(
+                "Could not deserialize output into a tuple".to_string(),
+            )
+        })?
+        .into_object();
+    let result_tuple = result_tuple.to_sequence();
+    let action: bool = result_tuple
+        .get_item(0, vm)
+        .and_then(|action| action.try_into_value(vm))
+        .map_err(|_| {
+            ExecutionError::DeserializationError(
+                "The first value of the output must be a bool".to_string(),
+            )
+        })?;
+    let new_storage = result_tuple.get_item(1, vm).map_err(|_| {
+        ExecutionError::DeserializationError(
+            "The output must have a second value with the storage".to_string(),
+        )
+    })?;
+    let new_storage = serde_json::to_value(PyObjectSerializer::new(vm, &new_storage))
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+    // An optional third value is the new career storage
+    let career = match result_tuple.get_item(2, vm) {
+        Ok(career) if !vm.is_none(&career) => Some(
+            serde_json::to_value(PyObjectSerializer::new(vm, &career))
+                .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?,
+        ),
+        _ => None,
+    };
+    Ok(Move {
+        action,
+        storage: new_storage,
+        career,
+    })
+}
+fn js_move(
+    context: &mut Context,
+    program: &str,
+    history: &[(bool, bool)],
+    storage: Value,
+    move_context: Option<&MoveContext>,
+) -> Result<Move, ExecutionError> {
+    context
+        .eval(Source::from_bytes(program))
+        .map_err(|err| js_error(&err))?;
+    let main = context
+        .global_object()
+        .get(js_string!("main"), context)
+        .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+    let main = main
+        .as_callable()
+        .ok_or(ExecutionError::InitializationError(
+            "Main function not found".to_string(),
+        ))?;
+    let args_history = serde_json::to_value(history)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))
+        .and_then(|history| {
+            JsValue::from_json(&history, context)
+                .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))
+        })?;
+    let args_storage = JsValue::from_json(&storage, context)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+    let args_context = match move_context {
+        Some(move_context) => JsValue::from_json(&serde_json::json!(move_context), context)
+            .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?,
+        None => JsValue::undefined(),
+    };
+    let result = main
+        .call(
+            &JsValue::undefined(),
+            &[args_history, args_storage, args_context],
+            context,
+        )
+        .map_err(|err| js_error(&err))?
+        .to_json(context)
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+    move_from_array(result)
+}
+/// `console.log` and friends, collecting into `__output`
+const JS_CONSOLE: &str = "var __output = [];
+var console = {};
+['log', 'info', 'warn', 'error', 'debug'].forEach(function (level) {
+    console[level] = function (...values) {
+        __output.push(values.map(function (value) {
+            return typeof value === 'object' && value !== null ? JSON.stringify(value) : String(value);
+        }).join(' '));
+    };
+});";
+/// The chunk name Lua errors point at, as in `strategy:3: message`
+const LUA_CHUNK: &str = "=strategy";
+fn lua_error(err: &mlua::Error) -> ExecutionError {
+    let (text, syntax) = match err {
+        mlua::Error::SyntaxError { message, .. } => (message.clone(), true),
+        mlua::Error::RuntimeError(message) => (message.clone(), false),
+        mlua::Error::CallbackError { cause, .. } => return lua_error(cause),
+        err => return ExecutionError::RuntimeError(err.to_string().into()),
+    };
+    let (message, traceback) = match text.split_once("\nstack traceback:\n") {
+        Some((message, traceback)) => (
+            message,
+            Some(
+                traceback
+                    .lines()
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        ),
+        None => (text.as_str(), None),
+    };
+    // Errors from the strategy itself start with `strategy:<line>: `
+    let located = message
+        .strip_prefix(&LUA_CHUNK[1..])
+        .and_then(|rest| rest.strip_prefix(':'))
+        .and_then(|rest| rest.split_once(": "))
+        .and_then(|(line, message)| Some((line.parse().ok()?, message)));
+    let diagnostic = match located {
+        Some((line, message)) => Diagnostic::new(message).with_line(Some(line)),
+        None => Diagnostic::new(message),
+    }
+    .with_traceback(traceback);
+    if syntax {
+        ExecutionError::SyntaxError(diagnostic)
+    } else {
+        ExecutionError::RuntimeError(diagnostic)
+    }
+}
+fn python_error(vm: &VirtualMachine, err: &PyBaseExceptionRef) -> ExecutionError {
+    let usize_attr = |object: &PyObjectRef, name: &'static str| {
+        object
+            .get_attr(name, vm)
+            .ok()
+            .and_then(|value| value.try_into_value::<usize>(vm).ok())
+    };
+    let object: PyObjectRef = err.clone().into();
+    let class = object.class().name().to_string();
+    let text = object
+        .str(vm)
+        .map(|text| text.as_str().to_string())
+        .unwrap_or_default();
+    let message = if text.is_empty() {
+        class.clone()
+    } else {
+        format!("{class}: {text}")
+    };
+    let mut traceback = String::new();
+    let _ = vm.write_exception(&mut traceback, err);
+    if object.fast_isinstance(vm.ctx.exceptions.syntax_error) {
+        // `str` of a syntax error repeats the location, `msg` doesn't
+        let message = object
+            .get_attr("msg", vm)
+            .and_then(|message| message.str(vm))
+            .map_or(message, |message| message.as_str().to_string());
+        return ExecutionError::SyntaxError(
+            Diagnostic::new(message)
+                .with_line(usize_attr(&object, "lineno"))
+                .with_column(usize_attr(&object, "offset"))
+                .with_traceback(Some(traceback)),
+        );
+    }
+    // The innermost frame is where it was raised
+    let mut line = None;
+    let mut frame = object.get_attr("__traceback__", vm).ok();
+    while let Some(current) = frame.filter(|frame| !vm.is_none(frame)) {
+        line = usize_attr(&current, "tb_lineno").or(line);
+        frame = current.get_attr("tb_next", vm).ok();
+    }
+    // Raised by the guards of the sandbox
+    if class == "ForbiddenImport" || class == "ForbiddenAccess" {
+        let diagnostic = Diagnostic::new(text)
+            .with_line(line)
+            .with_traceback(Some(traceback));
+        return if class == "ForbiddenImport" {
+            ExecutionError::ForbiddenImport(diagnostic)
+        } else {
+            ExecutionError::ForbiddenAccess(diagnostic)
+        };
+    }
+    ExecutionError::RuntimeError(
+        Diagnostic::new(message)
+            .with_line(line)
+            .with_traceback(Some(traceback)),
+    )
+}
+fn js_error(err: &boa_engine::JsError) -> ExecutionError {
+    let text = err.to_string();
+    // Parse errors end with ` at line <line>, col <column>`
+    let located = text
+        .rsplit_once(" at line ")
+        .and_then(|(message, position)| {
+            let (line, column) = position.split_once(", col ")?;
+            Some((message, line.parse().ok()?, column.parse().ok()?))
+        });
+    match located {
+        Some((message, line, column)) => ExecutionError::SyntaxError(
+            Diagnostic::new(message)
+                .with_line(Some(line))
+                .with_column(Some(column)),
+        ),
+        None if text.starts_with("SyntaxError") => ExecutionError::SyntaxError(text.into()),
+        None => ExecutionError::RuntimeError(text.into()),
     }
 }
+/// Replaces `Math.random` with Mulberry32, seeded with 32 bits of `seed`
+fn seeded_math_random(seed: u64) -> String {
+    format!(
+        "Math.random = (function (a) {{
+            return function () {{
+                a = (a + 0x6D2B79F5) | 0;
+                let t = Math.imul(a ^ (a >>> 15), 1 | a);
+                t = (t + Math.imul(t ^ (t >>> 7), 61 | t)) ^ t;
+                return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+            }};
+        }})({});",
+        seed as u32
+    )
+}
+/// The seeds for the random number generators of the two strategies
+/// of a game, so each gets its own stream that doesn't depend on the schedule
+pub fn strategy_seeds(seed: u64, player1: &Executor, player2: &Executor) -> (u64, u64) {
+    let (player1, player2) = (player1.fingerprint(), player2.fingerprint());
+    (
+        Rng::derive(seed, &[&player1, &player2, "player1"]).next_u64(),
+        Rng::derive(seed, &[&player1, &player2, "player2"]).next_u64(),
+    )
+}
+/// Whether a Python `main` takes a third argument (or `*args`)
+fn takes_context(function: &PyObjectRef, vm: &VirtualMachine) -> bool {
+    let code = |name: &'static str| {
+        function
+            .get_attr("__code__", vm)
+            .and_then(|code| code.get_attr(name, vm))
+            .and_then(|value| value.try_into_value::<usize>(vm))
+            .unwrap_or_default()
+    };
+    // 0x04 is CO_VARARGS
+    code("co_argcount") >= 3 || code("co_flags") & 0x04 != 0
+}
+/// What a strategy is told about the game besides the history
+/// (serialized as JSON for the strategy)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MoveContext {
+    /// 0-based
+    pub round: usize,
+    /// The number of rounds in the game, if the config discloses it
+    pub rounds: Option<i32>,
+    pub payoffs: Payoffs,
+    /// The opponent's name, or an anonymous ID if the config hides names
+    pub opponent: String,
+    /// The probability that a move gets flipped
+    pub noise: f64,
+    /// The (you, them) scores so far
+    pub scores: (i32, i32),
+    /// What the strategy kept from its earlier games,
+    /// if the config has career storage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub career: Option<Value>,
+}
+/// The score you get for each outcome, named like the config file scoring
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Payoffs {
+    pub mutual_win: i32,
+    pub mutual_loss: i32,
+    pub win: i32,
+    pub loss: i32,
+}
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+/// What a strategy decided. WASM and Piston programs output it as JSON
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Move {
+    pub action: bool,
+    pub storage: Value,
+    /// The new career storage, if the strategy changed it
+    /// (see `TournamentConfig::career_storage`)
+    #[serde(default)]
+    pub career: Option<Value>,
+}
+/// `[action, storage]` or `[action, storage, career]`
+fn move_from_array(value: Value) -> Result<Move, ExecutionError> {
+    let invalid = || {
+        ExecutionError::DeserializationError(
+            "Expected [action, storage] or [action, storage, career]".to_string(),
+        )
+    };
+    let Value::Array(mut items) = value else {
+        return Err(invalid());
+    };
+    if !(2..=3).contains(&items.len()) {
+        return Err(invalid());
+    }
+    let career = items
+        .get_mut(2)
+        .map(Value::take)
+        .filter(|career| !career.is_null());
+    Ok(Move {
+        action: items[0].as_bool().ok_or_else(invalid)?,
+        storage: items[1].take(),
+        career,
+    })
+}
+/// What a panic said, if it said it with a string
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
 #[derive(Clone, Debug)]
 pub struct Player {
     pub name: String,
     pub executor: Executor,
-    storage: String,
+    /// Free-form information about the submission (author, description, ...)
+    pub metadata: BTreeMap<String, String>,
+    storage: Value,
+    career: Value,
+    seed: u64,
+    log: DebugLog,
+    log_limit: usize,
+    sandbox: Sandbox,
+    isolation: Option<Isolation>,
+    /// The match so far, for Piston programs in match mode
+    session: Option<MatchSession>,
+    /// The running program, for local programs
+    process: command::Session,
+    /// The game being played, for its Python interpreter
+    game: Game,
 }
 impl Player {
     pub fn new(name: String, executor: Executor) -> Player {
         Player {
             name,
             executor,
-            storage: String::new(),
+            metadata: BTreeMap::new(),
+            storage: Value::from(""),
+            career: Value::from(""),
+            seed: 0,
+            log: DebugLog::new(),
+            log_limit: debug_log::DEFAULT_LIMIT,
+            sandbox: Sandbox::default(),
+            isolation: None,
+            session: None,
+            process: command::Session::default(),
+            game: Game(NO_GAME),
         }
     }
-    pub fn with_storage(mut self, storage: String) -> Player {
-        self.storage = storage;
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Player {
+        self.metadata = metadata;
         self
     }
-    pub fn run(&mut self, history: &[(bool, bool)]) -> bool {
-        let Ok((action, storage)) = self.executor.run(history, self.storage.clone()) else {
-            todo!()
-        };
-        self.storage = storage;
-        action
+    /// The storage the strategy starts with (an empty string by default)
+    pub fn with_storage(mut self, storage: impl Into<Value>) -> Player {
+        self.storage = storage.into();
+        self
+    }
+    /// Seed the random number generator the strategy sees
+    /// (see `Executor::run_seeded`)
+    pub fn with_seed(mut self, seed: u64) -> Player {
+        self.seed = seed;
+        self
+    }
+    /// How much of what the strategy prints to keep, in bytes
+    pub fn with_log_limit(mut self, limit: usize) -> Player {
+        self.log_limit = limit;
+        self
+    }
+    /// What the strategy printed so far
+    pub fn log(&self) -> &DebugLog {
+        &self.log
+    }
+    pub fn take_log(&mut self) -> DebugLog {
+        std::mem::take(&mut self.log)
+    }
+    /// What the strategy may do (see `sandbox`)
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Player {
+        self.sandbox = sandbox;
+        self
+    }
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+    /// Make the moves in a worker process (see `worker`)
+    pub fn with_isolation(mut self, isolation: Option<Isolation>) -> Player {
+        self.isolation = isolation;
+        self
+    }
+    /// Check the program (see `Executor::validate_sandboxed`). An isolated
+    /// player is checked in its worker, which the move timeout stops
+    pub(crate) fn check(&self) -> Result<(), ExecutionError> {
+        match &self.isolation {
+            Some(isolation) => worker::check(isolation, &self.executor, &self.sandbox),
+            None => self.executor.validate_sandboxed(&self.sandbox),
+        }
+    }
+    /// Get the interpreter (or worker) ready on this thread
+    pub(crate) fn warm_up(&self) {
+        match &self.isolation {
+            Some(isolation) => worker::warm_up(isolation, &self.executor, &self.sandbox),
+            None => self.executor.warm_up(&self.sandbox),
+        }
+    }
+    /// Make a move. On error the storage is left as it was
+    pub fn run(
+        &mut self,
+        history: &[(bool, bool)],
+        context: Option<&MoveContext>,
+    ) -> Result<bool, ExecutionError> {
+        // An empty history is a new game, which gets a new interpreter
+        if history.is_empty() || self.game.0 == NO_GAME {
+            self.game = Game(next_game());
+        }
+        // A fresh seed every round, since every round starts a fresh program
+        let seed = Rng::derive(self.seed, &[&history.len().to_string()]).next_u64();
+        let mut output = String::new();
+        // A strategy that finds a bug in an interpreter loses the move,
+        // not the whole tournament
+        let decision = panic::catch_unwind(AssertUnwindSafe(|| {
+            match (&self.executor, &self.isolation) {
+                // A new history is a new match
+                (Executor::Piston(piston), _) if piston.input == PistonInput::Match => {
+                    let session = match &mut self.session {
+                        Some(session) if !history.is_empty() => session,
+                        session => session.insert(MatchSession::new(&self.storage, context)?),
+                    };
+                    session.next(piston, history, context, &mut output)
+                }
+                (Executor::Command(program), _) => self.process.next(
+                    program,
+                    history,
+                    &self.storage,
+                    context,
+                    self.log_limit,
+                    &mut output,
+                ),
+                (_, Some(isolation)) => worker::run(
+                    isolation,
+                    self.game.0,
+                    &self.executor,
+                    &self.sandbox,
+                    history,
+                    self.storage.clone(),
+                    seed,
+                    context,
+                    &mut output,
+                ),
+                (executor, None) => executor.run_in_game(
+                    self.game.0,
+                    history,
+                    self.storage.clone(),
+                    seed,
+                    context,
+                    &self.sandbox,
+                    &mut output,
+                ),
+            }
+        }))
+        .unwrap_or_else(|payload| Err(ExecutionError::Panic(panic_message(&*payload))));
+        self.log.push(history.len(), &output, self.log_limit);
+        let decision = decision?;
+        self.storage = decision.storage;
+        if let Some(career) = decision.career {
+            self.career = career;
+        }
+        Ok(decision.action)
+    }
+    /// What the strategy kept from its earlier games
+    /// (see `TournamentConfig::career_storage`)
+    pub fn career(&self) -> &Value {
+        &self.career
+    }
+    /// The career storage the strategy starts the tournament with
+    /// (an empty string by default)
+    pub fn with_career(mut self, career: impl Into<Value>) -> Player {
+        self.career = career.into();
+        self
+    }
+    /// What the strategy saved for itself last
+    pub fn storage(&self) -> &Value {
+        &self.storage
     }
     pub fn reset_storage(&mut self) {
-        self.storage = String::new();
+        self.storage = Value::from("");
     }
 }
+/// The game a player is in (see `next_game`). A copy of the player isn't
+/// in it, so dropping the copy leaves the interpreters of the game alone
+#[derive(Debug)]
+struct Game(u64);
+
+impl Clone for Game {
+    fn clone(&self) -> Self {
+        Game(NO_GAME)
+    }
+}
+
+/// Players are dropped on the thread that played their game
+impl Drop for Game {
+    fn drop(&mut self) {
+        end_game(self.0);
+    }
+}
+#[derive(Clone, Debug)]
 pub struct TournamentConfig {
     pub players: Vec<Player>,
     pub rounds: i32,
@@ -339,6 +1231,28 @@ pub struct TournamentConfig {
     pub mutual_loss_score: i32,
     pub win_score: i32,
     pub loss_score: i32,
+    /// Seed for everything random in the tournament.
+    /// When `None`, a seed is picked from the clock at the start of the run
+    pub seed: Option<u64>,
+    /// The probability that a move gets flipped by accident
+    pub noise: f64,
+    /// How many matches to play at once (0 = one per CPU)
+    pub parallelism: usize,
+    /// Tell strategies how many rounds a game has (see `MoveContext`)
+    pub disclose_rounds: bool,
+    /// Tell strategies an anonymous ID instead of their opponent's name
+    pub anonymize_opponents: bool,
+    /// Let every player keep a "career" storage across all its games, next
+    /// to the storage of each game. It comes in as `MoveContext::career` and
+    /// a strategy changes it by returning it as a third value. Games are then
+    /// played one at a time, in schedule order, and never come from the cache
+    pub career_storage: bool,
+    /// Bytes of output to keep per player per game (see `debug_log`)
+    pub debug_log_limit: usize,
+    /// What strategies may do
+    pub sandbox: Sandbox,
+    /// Run strategies in worker processes instead of this one
+    pub isolation: Option<Isolation>,
 }
 impl TournamentConfig {
     pub fn new() -> TournamentConfig {
@@ -386,6 +1300,124 @@ impl TournamentConfig {
         self.loss_score = 3;
         self
     }
+    pub fn with_seed(mut self, seed: u64) -> TournamentConfig {
+        self.seed = Some(seed);
+        self
+    }
+    pub fn with_noise(mut self, noise: f64) -> TournamentConfig {
+        self.noise = noise;
+        self
+    }
+    pub fn with_parallelism(mut self, parallelism: usize) -> TournamentConfig {
+        self.parallelism = parallelism;
+        self
+    }
+    pub fn with_disclosed_rounds(mut self, disclose: bool) -> TournamentConfig {
+        self.disclose_rounds = disclose;
+        self
+    }
+    pub fn with_anonymous_opponents(mut self, anonymize: bool) -> TournamentConfig {
+        self.anonymize_opponents = anonymize;
+        self
+    }
+    pub fn with_career_storage(mut self, career_storage: bool) -> TournamentConfig {
+        self.career_storage = career_storage;
+        self
+    }
+    /// 0 throws all output away
+    pub fn with_debug_log_limit(mut self, limit: usize) -> TournamentConfig {
+        self.debug_log_limit = limit;
+        self
+    }
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> TournamentConfig {
+        self.sandbox = sandbox;
+        self
+    }
+    pub fn with_isolation(mut self, isolation: Option<Isolation>) -> TournamentConfig {
+        self.isolation = isolation;
+        self
+    }
+    /// A SHA-256 of everything that affects the results: the players
+    /// (names, programs and the storage and career they start with),
+    /// rounds, scores, noise, seed, sandbox, worker limits and how much
+    /// output is kept, like `MatchCache::key`
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for player in &self.players {
+            hasher.update(player.name.as_bytes());
+            hasher.update([0]);
+            hasher.update(player.executor.fingerprint().as_bytes());
+            hasher.update([0]);
+            hasher.update(player.storage().to_string().as_bytes());
+            hasher.update([0]);
+            hasher.update(player.career().to_string().as_bytes());
+        }
+        let isolation = self.isolation.as_ref().map(Isolation::limits);
+        hasher.update(
+            format!(
+                "{} {} {} {} {} {} {:?} {} {} {} {:?} {} {:?}",
+                self.rounds,
+                self.mutual_win_score,
+                self.mutual_loss_score,
+                self.win_score,
+                self.loss_score,
+                self.noise,
+                self.seed,
+                self.disclose_rounds,
+                self.anonymize_opponents,
+                self.career_storage,
+                self.sandbox,
+                self.debug_log_limit,
+                isolation
+            )
+            .as_bytes(),
+        );
+        hex(&hasher.finalize())
+    }
+    /// Whether a bigger score is a better score
+    /// (true for Nick style, false for classic style)
+    pub fn higher_is_better(&self) -> bool {
+        self.win_score >= self.loss_score
+    }
+    /// What a strategy gets told before making move `round` against
+    /// `opponent` (their name and `Executor::fingerprint`).
+    /// The anonymous ID only depends on the seed and the program,
+    /// so it stays the same for the whole tournament
+    pub fn move_context(
+        &self,
+        seed: u64,
+        round: usize,
+        opponent: (&str, &str),
+        scores: (i32, i32),
+    ) -> MoveContext {
+        MoveContext {
+            round,
+            rounds: self.disclose_rounds.then_some(self.rounds),
+            payoffs: Payoffs {
+                mutual_win: self.mutual_win_score,
+                mutual_loss: self.mutual_loss_score,
+                win: self.win_score,
+                loss: self.loss_score,
+            },
+            opponent: if self.anonymize_opponents {
+                format!("{:016x}", Rng::derive(seed, &[opponent.1]).next_u64())
+            } else {
+                opponent.0.to_string()
+            },
+            noise: self.noise,
+            scores,
+            career: None,
+        }
+    }
+    /// The score you get for playing `you` against `them`
+    pub fn score(&self, you: bool, them: bool) -> i32 {
+        match (you, them) {
+            (true, true) => self.mutual_win_score,
+            (false, false) => self.mutual_loss_score,
+            (false, true) => self.win_score,
+            (true, false) => self.loss_score,
+        }
+    }
 }
 impl Default for TournamentConfig {
     fn default() -> TournamentConfig {
@@ -397,12 +1429,126 @@ impl Default for TournamentConfig {
             mutual_loss_score: 0,
             win_score: 3,
             loss_score: -1,
+            seed: None,
+            noise: 0.0,
+            parallelism: 1,
+            disclose_rounds: false,
+            anonymize_opponents: false,
+            career_storage: false,
+            debug_log_limit: debug_log::DEFAULT_LIMIT,
+            sandbox: Sandbox::default(),
+            isolation: None,
         }
     }
 }
 
+/// The outcome of a single game between two players
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MatchResult {
+    /// Indices into `TournamentConfig::players`
+    pub player1: usize,
+    pub player2: usize,
+    /// The (player1, player2) actions of every round, after noise
+    pub rounds: Vec<(bool, bool)>,
+    /// The (player1, player2) score of every round
+    pub payoffs: Vec<(i32, i32)>,
+    /// The total (player1, player2) scores of the game
+    pub scores: (i32, i32),
+    /// Moves that failed (and counted as defecting)
+    pub errors: Vec<StrategyError>,
+    /// The (player1, player2) career storage after the game,
+    /// if the config has career storage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub careers: Option<(Value, Value)>,
+    /// What the (player1, player2) strategies printed, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs: Option<(DebugLog, DebugLog)>,
+    /// Why the game stopped early, if running a strategy failed in a way
+    /// that isn't its fault (`ExecutionError::Unavailable`).
+    /// Abandoned games don't count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abandoned: Option<StrategyError>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StrategyError {
+    /// Index into `TournamentConfig::players`
+    pub player: usize,
+    /// 0-based
+    pub round: usize,
+    pub message: String,
+    /// Location and traceback, if the interpreter gave them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<Diagnostic>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TournamentResult {
+    /// The seed that was actually used
+    pub seed: u64,
+    /// Player names, in the same order as `TournamentConfig::players`
+    pub players: Vec<String>,
+    /// Total score of each player
+    pub scores: Vec<i32>,
+    /// Every game played, in a stable order
+    pub matches: Vec<MatchResult>,
+    /// Games that were abandoned (see `MatchResult::abandoned`), and
+    /// aren't part of the scores or `matches`
+    pub abandoned: Vec<MatchResult>,
+    /// False for scoring like the classic prisoner scenario
+    pub higher_is_better: bool,
+}
+impl TournamentResult {
+    /// Player indices from first to last place
+    pub fn standings(&self) -> Vec<usize> {
+        let mut standings: Vec<usize> = (0..self.players.len()).collect();
+        standings.sort_by_key(|&i| {
+            if self.higher_is_better {
+                -(self.scores[i] as i64)
+            } else {
+                self.scores[i] as i64
+            }
+        });
+        standings
+    }
+    /// How many games each player finished
+    pub fn games(&self) -> Vec<usize> {
+        count_games(self.players.len(), &self.matches)
+    }
+    /// How many of each player's games were abandoned
+    pub fn abandoned_games(&self) -> Vec<usize> {
+        count_games(self.players.len(), &self.abandoned)
+    }
+    /// `head_to_head()[i][j]` is the total score player `i` got against player `j`
+    pub fn head_to_head(&self) -> Vec<Vec<i32>> {
+        let mut matrix = vec![vec![0; self.players.len()]; self.players.len()];
+        for result in &self.matches {
+            matrix[result.player1][result.player2] += result.scores.0;
+            matrix[result.player2][result.player1] += result.scores.1;
+        }
+        matrix
+    }
+}
+
+fn count_games(players: usize, games: &[MatchResult]) -> Vec<usize> {
+    let mut counts = vec![0; players];
+    for game in games {
+        counts[game.player1] += 1;
+        if game.player2 != game.player1 {
+            counts[game.player2] += 1;
+        }
+    }
+    counts
+}
+
 pub struct Tournament {
     pub config: TournamentConfig,
+    observers: Vec<Box<dyn TournamentObserver + Send>>,
+    checkpoint: Option<Checkpoint>,
+    replay_log: Option<PathBuf>,
+    /// The replay log, opened by `with_replay_log` for the first run
+    replay_file: Option<File>,
+    cache: Option<MatchCache>,
 }
 
 impl Default for Tournament {
@@ -413,54 +1559,742 @@ impl Default for Tournament {
 
 impl Tournament {
     pub fn new() -> Tournament {
+        Tournament::with_config(TournamentConfig::default())
+    }
+    pub fn with_config(config: TournamentConfig) -> Tournament {
         Tournament {
-            config: TournamentConfig::default(),
+            config,
+            observers: vec![],
+            checkpoint: None,
+            replay_log: None,
+            replay_file: None,
+            cache: None,
         }
     }
-    pub fn with_config(config: TournamentConfig) -> Tournament {
-        Tournament { config }
+    /// Reuse games from earlier runs stored in this directory,
+    /// and store the new ones. See `cache` for the details
+    pub fn with_cache(mut self, directory: impl Into<PathBuf>) -> Tournament {
+        self.cache = Some(MatchCache::new(directory));
+        self
+    }
+    /// A game from the cache, if there is a cache and it has the game
+    fn play_cached_match(
+        &self,
+        index: usize,
+        player1: usize,
+        player2: usize,
+        seed: u64,
+        observer: &dyn TournamentObserver,
+        careers: Option<&Mutex<Vec<Value>>>,
+    ) -> MatchResult {
+        // With career storage a game also depends on every game before it,
+        // and a seed from the clock never comes round again
+        let cached = self
+            .cache
+            .as_ref()
+            .filter(|_| !self.config.career_storage && self.config.seed.is_some())
+            .and_then(|cache| {
+                Some((
+                    cache,
+                    MatchCache::key(&self.config, player1, player2, seed)?,
+                ))
+            });
+        let Some((cache, key)) = cached else {
+            return self.play_scheduled_match(index, player1, player2, seed, observer, careers);
+        };
+        match cache.get(&key, player1, player2) {
+            Some(result) => {
+                observer.on_match_start(index, player1, player2);
+                observer.on_match_end(index, &result);
+                result
+            }
+            None => {
+                let result =
+                    self.play_scheduled_match(index, player1, player2, seed, observer, careers);
+                if result.abandoned.is_none() {
+                    cache.put(&key, &result);
+                }
+                result
+            }
+        }
+    }
+    /// Save finished games to this file as they happen, and skip the games
+    /// that are already in it. Fails if the file is something other than a
+    /// checkpoint of this config, so set the config up first. See `checkpoint`
+    /// for the details
+    pub fn with_checkpoint(
+        mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Tournament, CheckpointError> {
+        let seed = self.resolve_seed();
+        self.checkpoint = Some(Checkpoint::open(
+            path.as_ref(),
+            &self.config.fingerprint(),
+            seed,
+        )?);
+        Ok(self)
+    }
+    /// Like `with_checkpoint`, but start the checkpoint over whatever is in the file
+    pub fn with_new_checkpoint(
+        mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Tournament, CheckpointError> {
+        let seed = self.resolve_seed();
+        self.checkpoint = Some(Checkpoint::create(
+            path.as_ref(),
+            &self.config.fingerprint(),
+            seed,
+        )?);
+        Ok(self)
+    }
+    /// Record every move and the storage after every round to this file.
+    /// Fails if the file can't be created. See `replay` for the details
+    pub fn with_replay_log(mut self, path: impl Into<PathBuf>) -> Result<Tournament, ReplayError> {
+        let path = path.into();
+        let file = File::create(&path).map_err(|source| ReplayError::Write {
+            path: path.clone(),
+            source,
+        })?;
+        self.replay_log = Some(path);
+        self.replay_file = Some(file);
+        Ok(self)
+    }
+    /// Get told about everything that happens during `run`
+    pub fn with_observer(
+        mut self,
+        observer: impl TournamentObserver + Send + 'static,
+    ) -> Tournament {
+        self.observers.push(Box::new(observer));
+        self
+    }
+    fn observers<'a>(&'a self, extra: Option<&'a dyn TournamentObserver>) -> Observers<'a> {
+        let mut observers: Vec<&dyn TournamentObserver> = self
+            .observers
+            .iter()
+            .map(|observer| observer.as_ref() as &dyn TournamentObserver)
+            .collect();
+        observers.extend(extra);
+        Observers(observers)
+    }
+    /// The seed from the config, or a fresh one from the clock
+    pub fn resolve_seed(&self) -> u64 {
+        self.config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or_default()
+        })
+    }
+    /// Every (player1, player2) game of the tournament, in order
+    fn pairings(&self) -> Vec<(usize, usize)> {
+        let count = self.config.players.len();
+        (0..count)
+            .flat_map(|i| (0..count).map(move |j| (i, j)))
+            // don't let a player play against themselves
+            .filter(|(i, j)| i != j)
+            .collect()
     }
-    pub fn run(&mut self) -> Vec<i32> {
-        // TODO: Multithread this
+    fn workers(&self) -> usize {
+        if self.config.career_storage {
+            return 1;
+        }
+        match self.config.parallelism {
+            0 => std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1),
+            count => count,
+        }
+    }
+    /// Play a single game between two players of the config
+    pub fn play_match(&self, player1: usize, player2: usize, seed: u64) -> MatchResult {
+        self.play_scheduled_match(0, player1, player2, seed, &self.observers(None), None)
+    }
+    fn play_scheduled_match(
+        &self,
+        index: usize,
+        player1: usize,
+        player2: usize,
+        seed: u64,
+        observer: &dyn TournamentObserver,
+        careers: Option<&Mutex<Vec<Value>>>,
+    ) -> MatchResult {
+        observer.on_match_start(index, player1, player2);
+        let seeds = strategy_seeds(
+            seed,
+            &self.config.players[player1].executor,
+            &self.config.players[player2].executor,
+        );
+        let mut player1_state = self.config.players[player1]
+            .clone()
+            .with_seed(seeds.0)
+            .with_log_limit(self.config.debug_log_limit)
+            .with_sandbox(self.config.sandbox.clone())
+            .with_isolation(self.config.isolation.clone());
+        let mut player2_state = self.config.players[player2]
+            .clone()
+            .with_seed(seeds.1)
+            .with_log_limit(self.config.debug_log_limit)
+            .with_sandbox(self.config.sandbox.clone())
+            .with_isolation(self.config.isolation.clone());
+        if let Some(careers) = careers.and_then(|careers| careers.lock().ok()) {
+            player1_state = player1_state.with_career(careers[player1].clone());
+            player2_state = player2_state.with_career(careers[player2].clone());
+        }
+        let fingerprints = (
+            player1_state.executor.fingerprint(),
+            player2_state.executor.fingerprint(),
+        );
+        // Derived from the programs so a game is reproducible no matter
+        // where it lands in the schedule (and can be cached)
+        let mut rng = Rng::derive(seed, &[&fingerprints.0, &fingerprints.1]);
+        let mut player1_history = vec![];
+        let mut player2_history = vec![];
+        let mut rounds = vec![];
+        let mut payoffs = vec![];
+        let mut scores = (0, 0);
+        let mut errors = vec![];
+        let mut abandoned = None;
+        for round in 0..self.config.rounds.max(0) as usize {
+            // A move that fails counts as defecting
+            let mut fail = |player: usize, err: ExecutionError| {
+                let error = StrategyError {
+                    player,
+                    round,
+                    message: err.to_string(),
+                    diagnostic: err.diagnostic().cloned(),
+                };
+                if let ExecutionError::Unavailable(..) = err {
+                    abandoned.get_or_insert(error);
+                } else {
+                    observer.on_strategy_error(index, &error);
+                    errors.push(error);
+                }
+                false
+            };
+            let mut contexts = (
+                self.config.move_context(
+                    seed,
+                    round,
+                    (&player2_state.name, &fingerprints.1),
+                    scores,
+                ),
+                self.config.move_context(
+                    seed,
+                    round,
+                    (&player1_state.name, &fingerprints.0),
+                    (scores.1, scores.0),
+                ),
+            );
+            if self.config.career_storage {
+                contexts.0.career = Some(player1_state.career().clone());
+                contexts.1.career = Some(player2_state.career().clone());
+            }
+            let intended = (
+                player1_state
+                    .run(&player1_history, Some(&contexts.0))
+                    .unwrap_or_else(|err| fail(player1, err)),
+                player2_state
+                    .run(&player2_history, Some(&contexts.1))
+                    .unwrap_or_else(|err| fail(player2, err)),
+            );
+            if abandoned.is_some() {
+                break;
+            }
+            let (mut player1_action, mut player2_action) = intended;
+            if rng.chance(self.config.noise) {
+                player1_action = !player1_action;
+            }
+            if rng.chance(self.config.noise) {
+                player2_action = !player2_action;
+            }
+            let payoff = (
+                self.config.score(player1_action, player2_action),
+                self.config.score(player2_action, player1_action),
+            );
+            scores.0 += payoff.0;
+            scores.1 += payoff.1;
+            payoffs.push(payoff);
+            player1_history.push((player1_action, player2_action));
+            player2_history.push((player2_action, player1_action));
+            rounds.push((player1_action, player2_action));
+            observer.on_round(
+                index,
+                &Round {
+                    player1,
+                    player2,
+                    round,
+                    intended,
+                    actions: (player1_action, player2_action),
+                    payoffs: payoff,
+                    storages: (
+                        player1_state.storage().clone(),
+                        player2_state.storage().clone(),
+                    ),
+                },
+            );
+        }
+        // An abandoned game doesn't count, so neither does what it did to the careers
+        let careers = (self.config.career_storage && abandoned.is_none()).then(|| {
+            let finished = (
+                player1_state.career().clone(),
+                player2_state.career().clone(),
+            );
+            if let Some(mut careers) = careers.and_then(|careers| careers.lock().ok()) {
+                careers[player1] = finished.0.clone();
+                careers[player2] = finished.1.clone();
+            }
+            finished
+        });
+        let logs = (player1_state.take_log(), player2_state.take_log());
+        let logs = (!logs.0.is_empty() || !logs.1.is_empty()).then_some(logs);
+        let result = MatchResult {
+            player1,
+            player2,
+            rounds,
+            payoffs,
+            scores,
+            errors,
+            careers,
+            logs,
+            abandoned,
+        };
+        observer.on_match_end(index, &result);
+        result
+    }
+    pub fn run(&mut self) -> TournamentResult {
+        self.play(None)
+    }
+    /// Like `run`, but also sends what is going on to `progress` as it happens
+    pub fn run_with_progress(&mut self, progress: Sender<ProgressEvent>) -> TournamentResult {
+        self.play(Some(&progress))
+    }
+    fn play(&mut self, extra: Option<&dyn TournamentObserver>) -> TournamentResult {
+        let replay_file = self.replay_file.take();
+        let mut observer = self.observers(extra);
+        let checkpoint = self.checkpoint.as_ref();
+        let seed = checkpoint.map_or_else(|| self.resolve_seed(), Checkpoint::seed);
+        let pairings = self.pairings();
+        // Once the file is open, writing is best-effort like writing games to
+        // the checkpoint: a log we can't write shouldn't stop the tournament
+        let recorder = self.replay_log.as_deref().and_then(|path| {
+            match replay_file {
+                Some(file) => ReplayRecorder::new(file, &self.config, seed),
+                None => ReplayRecorder::create(path, &self.config, seed),
+            }
+            .ok()
+        });
+        if let Some(recorder) = &recorder {
+            observer.0.push(recorder);
+        }
+        observer.on_tournament_start(&self.config, pairings.len());
+
+        let mut results = Vec::with_capacity(pairings.len());
+        for (index, result) in checkpoint
+            .into_iter()
+            .flat_map(Checkpoint::finished)
+            .cloned()
+        {
+            if pairings.get(index) == Some(&(result.player1, result.player2))
+                && !results.iter().any(|(done, _)| *done == index)
+            {
+                observer.on_match_start(index, result.player1, result.player2);
+                observer.on_match_end(index, &result);
+                results.push((index, result));
+            }
+        }
+        let remaining: Vec<usize> = (0..pairings.len())
+            .filter(|index| !results.iter().any(|(done, _)| done == index))
+            .collect();
+        // Pick up the careers where the checkpointed games left them
+        let careers = self.config.career_storage.then(|| {
+            let mut careers: Vec<Value> = self
+                .config
+                .players
+                .iter()
+                .map(|player| player.career().clone())
+                .collect();
+            let mut restored: Vec<&(usize, MatchResult)> = results.iter().collect();
+            restored.sort_by_key(|(index, _)| *index);
+            for (_, result) in restored {
+                if let Some((career1, career2)) = &result.careers {
+                    careers[result.player1] = career1.clone();
+                    careers[result.player2] = career2.clone();
+                }
+            }
+            Mutex::new(careers)
+        });
+        if let Some(checkpoint) = checkpoint {
+            observer.0.push(checkpoint);
+        }
+
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(results);
+        std::thread::scope(|scope| {
+            let work = || {
+                while let Some(&index) = remaining.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let (i, j) = pairings[index];
+                    let result =
+                        self.play_cached_match(index, i, j, seed, &observer, careers.as_ref());
+                    if let Ok(mut results) = results.lock() {
+                        results.push((index, result));
+                    }
+                }
+            };
+            let spawned = (0..self.workers().min(remaining.len()))
+                .filter(|_| {
+                    std::thread::Builder::new()
+                        .stack_size(sandbox::STACK_SIZE)
+                        .spawn_scoped(scope, work)
+                        .is_ok()
+                })
+                .count();
+            if spawned == 0 {
+                work();
+            }
+        });
+        let mut results = results.into_inner().unwrap_or_default();
+        results.sort_by_key(|(index, _)| *index);
+
         let mut scores = vec![0; self.config.players.len()];
-        for (i, player1) in self.config.players.iter().enumerate() {
-            for (j, player2) in self.config.players.iter().enumerate() {
-                // don't let a player play against themselves
-                if i == j {
-                    continue;
+        let (matches, abandoned): (Vec<MatchResult>, Vec<MatchResult>) = results
+            .into_iter()
+            .map(|(_, result)| result)
+            .partition(|result| result.abandoned.is_none());
+        for result in &matches {
+            scores[result.player1] += result.scores.0;
+            scores[result.player2] += result.scores.1;
+        }
+        let result = TournamentResult {
+            seed,
+            players: self
+                .config
+                .players
+                .iter()
+                .map(|player| player.name.clone())
+                .collect(),
+            scores,
+            matches,
+            abandoned,
+            higher_is_better: self.config.higher_is_better(),
+        };
+        observer.on_tournament_end(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Cooperates as long as nothing it did before this game is left over
+    const COUNTING: &str = "
+import math
+def main(history, storage):
+    math.calls = getattr(math, 'calls', 0) + 1
+    return (math.calls == len(history) + 1, storage)
+";
+
+    /// Python needs more stack than test threads get
+    fn with_stack(f: impl FnOnce() + Send + 'static) {
+        std::thread::Builder::new()
+            .stack_size(sandbox::STACK_SIZE)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn python_state_does_not_outlive_a_game() {
+        let players = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| Player::new(name.to_string(), Executor::Python(COUNTING.to_string())))
+            .collect();
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(3)
+                .with_seed(1),
+        )
+        .run();
+        assert_eq!(result.matches.len(), 6);
+        for game in &result.matches {
+            assert!(game.errors.is_empty(), "{:?}", game.errors);
+            assert!(game.rounds.iter().all(|&round| round == (true, true)));
+        }
+    }
+
+    #[test]
+    fn run_starts_from_scratch_every_time() {
+        with_stack(|| {
+            let executor = Executor::Python(COUNTING.to_string());
+            for _ in 0..3 {
+                assert!(executor.run(&[], Value::from("")).unwrap().action);
+            }
+        });
+    }
+
+    #[test]
+    fn dropping_a_copy_leaves_the_game_alone() {
+        with_stack(|| {
+            let mut player = Player::new(
+                "counting".to_string(),
+                Executor::Python(COUNTING.to_string()),
+            );
+            assert!(player.run(&[], None).unwrap());
+            let mut copy = player.clone();
+            // The copy starts from scratch, so it counts one call
+            assert!(!copy.run(&[(true, true)], None).unwrap());
+            drop(copy);
+            assert!(player.run(&[(true, true)], None).unwrap());
+            assert!(player.run(&[(true, true), (true, true)], None).unwrap());
+        });
+    }
+
+    #[test]
+    fn interpreters_are_put_back_and_reused() {
+        with_stack(|| {
+            let sneaky = Executor::Python(
+                "
+import collections, json
+def main(history, storage):
+    fresh = not hasattr(collections.Counter, 'seen') and not hasattr(json, 'seen')
+    collections.Counter.seen = True
+    json.seen = True
+    return (fresh, storage)
+"
+                .to_string(),
+            );
+            for executor in [
+                sneaky.clone(),
+                Executor::Python(COUNTING.to_string()),
+                sneaky,
+            ] {
+                assert!(executor.run(&[], Value::from("")).unwrap().action);
+            }
+            PYTHON.with(|pool| {
+                let pool = pool.borrow();
+                assert_eq!(pool.len(), 1);
+                assert!(pool[0].key.is_empty());
+            });
+        });
+    }
+
+    #[test]
+    fn negative_rounds_play_no_rounds() {
+        let players = ["tit-for-tat", "always-defect"]
+            .into_iter()
+            .map(|name| crate::builtins::builtin(name).unwrap())
+            .collect();
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(-1),
+        )
+        .run();
+        assert!(result.matches.iter().all(|game| game.rounds.is_empty()));
+    }
+
+    const JSON_STORAGE: &str = r#"{"list": [1, 2.5, "x"], "nested": {"flag": true}, "text": ""}"#;
+
+    #[test]
+    fn storage_can_be_any_json() {
+        let storage: Value = serde_json::from_str(JSON_STORAGE).unwrap();
+        for executor in [
+            Executor::Lua("return function(history, storage) return true, storage end".to_string()),
+            Executor::JavaScript(
+                "function main(history, storage) { return [true, storage]; }".to_string(),
+            ),
+        ] {
+            let decision = executor.run(&[], storage.clone()).unwrap();
+            assert_eq!(decision.storage, storage, "{executor:?}");
+        }
+        with_stack(move || {
+            let executor = Executor::Python(
+                "def main(history, storage):\n    return (True, storage)\n".to_string(),
+            );
+            assert_eq!(executor.run(&[], storage.clone()).unwrap().storage, storage);
+        });
+    }
+
+    #[test]
+    fn strategies_see_the_context() {
+        let config = TournamentConfig::new()
+            .with_rounds(7)
+            .with_disclosed_rounds(true);
+        let context = config.move_context(1, 2, ("Grim Trigger", "abc"), (4, 1));
+        let executor = Executor::JavaScript(
+            "function main(history, storage, context) { return [true, context]; }".to_string(),
+        );
+        let decision = executor
+            .run_seeded(&[], Value::from(""), 0, Some(&context))
+            .unwrap();
+        assert_eq!(
+            decision.storage,
+            serde_json::json!({
+                "round": 2,
+                "rounds": 7,
+                "payoffs": {"mutual_win": 2, "mutual_loss": 0, "win": 3, "loss": -1},
+                "opponent": "Grim Trigger",
+                "noise": 0.0,
+                "scores": [4, 1],
+            })
+        );
+
+        // Anonymous IDs only depend on the seed and the program
+        let anonymous = config
+            .with_disclosed_rounds(false)
+            .with_anonymous_opponents(true);
+        let id = |seed, name, fingerprint| {
+            anonymous
+                .move_context(seed, 0, (name, fingerprint), (0, 0))
+                .opponent
+        };
+        assert_eq!(id(1, "a", "abc"), id(1, "b", "abc"));
+        assert_ne!(id(1, "a", "abc"), id(1, "a", "def"));
+        assert_ne!(id(1, "a", "abc"), id(2, "a", "abc"));
+        assert!(anonymous
+            .move_context(1, 0, ("a", "abc"), (0, 0))
+            .rounds
+            .is_none());
+    }
+
+    #[test]
+    fn careers_carry_over_between_games() {
+        let counting = Executor::JavaScript(
+            "function main(history, storage, context) { return [true, storage, (context.career || 0) + 1]; }"
+                .to_string(),
+        );
+        let players = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| Player::new(name.to_string(), counting.clone()))
+            .collect();
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(5)
+                .with_career_storage(true)
+                .with_seed(1),
+        )
+        .run();
+        // Every player plays 4 games of 5 moves, and counts them all
+        let mut careers = [0; 3];
+        for game in &result.matches {
+            let (career1, career2) = game.careers.clone().unwrap();
+            careers[game.player1] = careers[game.player1].max(career1.as_i64().unwrap());
+            careers[game.player2] = careers[game.player2].max(career2.as_i64().unwrap());
+        }
+        assert_eq!(careers, [20; 3]);
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        let lua = Executor::Lua(
+            "return function(history, storage)\n    local x = nil\n    return x.y\nend".to_string(),
+        );
+        match lua.run(&[], Value::from("")) {
+            Err(ExecutionError::RuntimeError(diagnostic)) => {
+                assert_eq!(diagnostic.line, Some(3), "{diagnostic:?}")
+            }
+            other => panic!("{other:?}"),
+        }
+        let javascript = Executor::JavaScript(
+            "function main(history, storage) {\n    return [true,\n}".to_string(),
+        );
+        assert!(matches!(
+            javascript.run(&[], Value::from("")),
+            Err(ExecutionError::SyntaxError(_))
+        ));
+        with_stack(|| {
+            let python = Executor::Python(
+                "def main(history, storage):\n    x = 1\n    return 1 / 0\n".to_string(),
+            );
+            match python.run(&[], Value::from("")) {
+                Err(ExecutionError::RuntimeError(diagnostic)) => {
+                    assert_eq!(diagnostic.line, Some(3), "{diagnostic:?}");
+                    let traceback = diagnostic.traceback.unwrap_or_default();
+                    assert!(traceback.contains("ZeroDivisionError"), "{traceback}");
                 }
-                let mut player1 = player1.clone();
-                let mut player2 = player2.clone();
-                let mut player1_history = vec![];
-                let mut player2_history = vec![];
-                for _ in 0..self.config.rounds {
-                    let player1_action = player1.run(&player1_history);
-                    let player2_action = player2.run(&player2_history);
-                    let player1_score = if player1_action && player2_action {
-                        self.config.mutual_win_score
-                    } else if !player1_action && !player2_action {
-                        self.config.mutual_loss_score
-                    } else if !player1_action && player2_action {
-                        self.config.win_score
-                    } else {
-                        self.config.loss_score
-                    };
-                    let player2_score = if player2_action && player1_action {
-                        self.config.mutual_win_score
-                    } else if !player2_action && !player1_action {
-                        self.config.mutual_loss_score
-                    } else if !player2_action && player1_action {
-                        self.config.win_score
-                    } else {
-                        self.config.loss_score
-                    };
-                    scores[i] += player1_score;
-                    scores[j] += player2_score;
-                    player1_history.push((player1_action, player2_action));
-                    player2_history.push((player2_action, player1_action));
+                other => panic!("{other:?}"),
+            }
+            let python = Executor::Python("def main(history, storage)\n    pass\n".to_string());
+            match python.validate() {
+                Err(ExecutionError::SyntaxError(diagnostic)) => {
+                    assert_eq!(diagnostic.line, Some(1), "{diagnostic:?}")
                 }
+                other => panic!("{other:?}"),
             }
+        });
+    }
+
+    #[test]
+    fn randomness_follows_the_seed() {
+        for executor in [
+            Executor::Lua(
+                "return function(history, storage) return true, math.random() end".to_string(),
+            ),
+            Executor::JavaScript(
+                "function main(history, storage) { return [true, Math.random()]; }".to_string(),
+            ),
+        ] {
+            let draw = |seed| {
+                executor
+                    .run_seeded(&[], Value::from(""), seed, None)
+                    .unwrap()
+                    .storage
+            };
+            assert_eq!(draw(1), draw(1), "{executor:?}");
+            assert_ne!(draw(1), draw(2), "{executor:?}");
         }
-        scores
+    }
+
+    #[test]
+    fn odd_answers_are_errors_not_panics() {
+        for executor in [
+            Executor::Lua("return function(history, storage) error('no') end".to_string()),
+            Executor::Lua(
+                "return function(history, storage) return true, function() end end".to_string(),
+            ),
+            Executor::Lua("return 42".to_string()),
+            Executor::JavaScript("function main(history, storage) { return 1; }".to_string()),
+            Executor::JavaScript("function main(history, storage) { return []; }".to_string()),
+            Executor::JavaScript(
+                "function main(history, storage) { throw new Error('no'); }".to_string(),
+            ),
+            Executor::WASM(b"(module)".to_vec()),
+            Executor::WASM(b"not a module".to_vec()),
+        ] {
+            let mut player = Player::new("odd".to_string(), executor);
+            assert!(
+                !matches!(player.run(&[], None), Ok(_) | Err(ExecutionError::Panic(_))),
+                "{:?}",
+                player.executor
+            );
+        }
+        with_stack(|| {
+            for program in [
+                "def main(history, storage):\n    return None\n",
+                "def main(history, storage):\n    return (True,)\n",
+                "def main(history, storage):\n    return ('yes', storage)\n",
+            ] {
+                let mut player =
+                    Player::new("odd".to_string(), Executor::Python(program.to_string()));
+                assert!(
+                    !matches!(player.run(&[], None), Ok(_) | Err(ExecutionError::Panic(_))),
+                    "{program}"
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn panics_say_what_happened() {
+        let payload = std::panic::catch_unwind(|| panic!("interpreter bug {}", 42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "interpreter bug 42");
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(7)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "unknown panic");
     }
 }
@@ -0,0 +1,371 @@
+//! A live terminal dashboard for a running tournament
+//!
+//! Feed it the events of `Tournament::run_with_progress`:
+//! ```no_run
+//! # use ipdt::core::{Tournament, TournamentConfig};
+//! # let mut tournament = Tournament::with_config(TournamentConfig::new());
+//! let (sender, receiver) = std::sync::mpsc::channel();
+//! let dashboard = std::thread::spawn(move || ipdt::dashboard::show(receiver));
+//! let result = tournament.run_with_progress(sender);
+//! dashboard.join().ok();
+//! ```
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::observer::ProgressEvent;
+
+/// Don't redraw more often than this
+const FRAME: Duration = Duration::from_millis(100);
+const RECENT_ERRORS: usize = 5;
+const LEADERBOARD: usize = 10;
+const BAR_WIDTH: usize = 40;
+
+#[derive(Default)]
+struct State {
+    players: Vec<String>,
+    matches: usize,
+    finished: usize,
+    scores: Vec<i32>,
+    higher_is_better: bool,
+    /// (player1, player2, rounds played) of the games
+    /// being played right now, by schedule index
+    playing: BTreeMap<usize, (usize, usize, usize)>,
+    errors: VecDeque<String>,
+    done: bool,
+}
+
+impl State {
+    fn update(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Started {
+                players,
+                matches,
+                higher_is_better,
+            } => {
+                self.scores = vec![0; players.len()];
+                self.players = players;
+                self.matches = matches;
+                self.higher_is_better = higher_is_better;
+            }
+            ProgressEvent::MatchStarted {
+                index,
+                player1,
+                player2,
+            } => {
+                self.playing.insert(index, (player1, player2, 0));
+            }
+            ProgressEvent::MatchFinished {
+                index,
+                player1,
+                player2,
+                scores,
+            } => {
+                self.playing.remove(&index);
+                self.finished += 1;
+                if let Some(score) = self.scores.get_mut(player1) {
+                    *score += scores.0;
+                }
+                if let Some(score) = self.scores.get_mut(player2) {
+                    *score += scores.1;
+                }
+            }
+            ProgressEvent::StrategyError { error, .. } => {
+                let name = self.name(error.player).to_string();
+                self.errors.push_front(format!(
+                    "{} (round {}): {}",
+                    name,
+                    error.round + 1,
+                    error.message.lines().next().unwrap_or_default()
+                ));
+                self.errors.truncate(RECENT_ERRORS);
+            }
+            ProgressEvent::MatchAbandoned { index, error } => {
+                self.playing.remove(&index);
+                self.finished += 1;
+                let name = self.name(error.player).to_string();
+                self.errors.push_front(format!(
+                    "{} (round {}): abandoned the game, {}",
+                    name,
+                    error.round + 1,
+                    error.message.lines().next().unwrap_or_default()
+                ));
+                self.errors.truncate(RECENT_ERRORS);
+            }
+            ProgressEvent::Round { index, round } => {
+                if let Some(game) = self.playing.get_mut(&index) {
+                    game.2 = round.round + 1;
+                }
+            }
+            ProgressEvent::Finished => self.done = true,
+        }
+    }
+    fn name(&self, player: usize) -> &str {
+        self.players.get(player).map_or("?", String::as_str)
+    }
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        // Home, then clear everything below
+        write!(out, "\x1b[H\x1b[J")?;
+        let ratio = if self.matches == 0 {
+            1.0
+        } else {
+            self.finished as f64 / self.matches as f64
+        };
+        let filled = (ratio * BAR_WIDTH as f64).round() as usize;
+        writeln!(
+            out,
+            "[{}{}] {}/{} games ({:.0}%)\n",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            self.finished,
+            self.matches,
+            ratio * 100.0
+        )?;
+
+        writeln!(out, "Playing")?;
+        if self.playing.is_empty() {
+            writeln!(out, "  -")?;
+        }
+        for (player1, player2, rounds) in self.playing.values() {
+            writeln!(
+                out,
+                "  {} vs {} (round {})",
+                self.name(*player1),
+                self.name(*player2),
+                rounds
+            )?;
+        }
+
+        writeln!(out, "\nLeaderboard")?;
+        let mut standings: Vec<usize> = (0..self.scores.len()).collect();
+        standings.sort_by_key(|&player| {
+            if self.higher_is_better {
+                -(self.scores[player] as i64)
+            } else {
+                self.scores[player] as i64
+            }
+        });
+        for (place, player) in standings.iter().take(LEADERBOARD).enumerate() {
+            writeln!(
+                out,
+                "  {:>3}. {:<30} {:>8}",
+                place + 1,
+                self.name(*player),
+                self.scores[*player]
+            )?;
+        }
+
+        writeln!(out, "\nRecent errors")?;
+        if self.errors.is_empty() {
+            writeln!(out, "  -")?;
+        }
+        for error in &self.errors {
+            writeln!(out, "  {error}")?;
+        }
+        out.flush()
+    }
+}
+
+/// Draw the dashboard on stderr until the tournament finishes
+/// (or the sender goes away)
+pub fn show(events: Receiver<ProgressEvent>) {
+    let mut state = State::default();
+    let mut out = io::stderr().lock();
+    let mut last_frame = Instant::now() - FRAME;
+    // Hide the cursor while we draw
+    let _ = write!(out, "\x1b[?25l");
+    while !state.done {
+        match events.recv_timeout(FRAME) {
+            Ok(event) => state.update(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if last_frame.elapsed() >= FRAME {
+            let _ = state.draw(&mut out);
+            last_frame = Instant::now();
+        }
+    }
+    let _ = state.draw(&mut out);
+    let _ = write!(out, "\x1b[?25h");
+    let _ = out.flush();
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::core::StrategyError;
+    use crate::observer::Round;
+
+    fn started(higher_is_better: bool) -> State {
+        let mut state = State::default();
+        state.update(ProgressEvent::Started {
+            players: vec!["Alpha".to_string(), "Beta".to_string(), "Gamma".to_string()],
+            matches: 4,
+            higher_is_better,
+        });
+        state
+    }
+
+    fn finished(index: usize, player1: usize, player2: usize, scores: (i32, i32)) -> ProgressEvent {
+        ProgressEvent::MatchFinished {
+            index,
+            player1,
+            player2,
+            scores,
+        }
+    }
+
+    fn error(index: usize, round: usize, message: &str) -> ProgressEvent {
+        ProgressEvent::StrategyError {
+            index,
+            error: StrategyError {
+                player: 2,
+                round,
+                message: message.to_string(),
+                diagnostic: None,
+            },
+        }
+    }
+
+    fn drawn(state: &State) -> String {
+        let mut out = vec![];
+        state.draw(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The lines of a section of the screen
+    fn section<'a>(screen: &'a str, title: &str) -> Vec<&'a str> {
+        screen
+            .split(&format!("{title}\n"))
+            .nth(1)
+            .unwrap()
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn games_in_play_are_listed() {
+        let mut state = started(true);
+        for (index, player1, player2) in [(0, 0, 1), (1, 1, 2)] {
+            state.update(ProgressEvent::MatchStarted {
+                index,
+                player1,
+                player2,
+            });
+        }
+        let screen = drawn(&state);
+        assert!(screen.contains(&format!("[{}] 0/4 games (0%)", "-".repeat(BAR_WIDTH))));
+        assert_eq!(
+            section(&screen, "Playing"),
+            ["  Alpha vs Beta (round 0)", "  Beta vs Gamma (round 0)"]
+        );
+        assert_eq!(section(&screen, "Recent errors"), ["  -"]);
+
+        state.update(finished(0, 0, 1, (3, 5)));
+        let screen = drawn(&state);
+        assert!(screen.contains(&format!(
+            "[{}{}] 1/4 games (25%)",
+            "#".repeat(BAR_WIDTH / 4),
+            "-".repeat(BAR_WIDTH * 3 / 4)
+        )));
+        assert_eq!(section(&screen, "Playing"), ["  Beta vs Gamma (round 0)"]);
+    }
+
+    #[test]
+    fn games_in_play_show_their_round() {
+        let mut state = started(true);
+        state.update(ProgressEvent::MatchStarted {
+            index: 0,
+            player1: 0,
+            player2: 1,
+        });
+        state.update(ProgressEvent::Round {
+            index: 0,
+            round: Round {
+                player1: 0,
+                player2: 1,
+                round: 2,
+                intended: (true, false),
+                actions: (true, false),
+                payoffs: (0, 3),
+                storages: (Value::Null, Value::Null),
+            },
+        });
+        let screen = drawn(&state);
+        assert_eq!(section(&screen, "Playing"), ["  Alpha vs Beta (round 3)"]);
+    }
+
+    #[test]
+    fn the_leaderboard_follows_the_scoring() {
+        for (higher_is_better, order) in [
+            (true, ["Beta", "Alpha", "Gamma"]),
+            (false, ["Gamma", "Alpha", "Beta"]),
+        ] {
+            let mut state = started(higher_is_better);
+            state.update(finished(0, 0, 1, (3, 5)));
+            state.update(finished(1, 0, 2, (1, 1)));
+            state.update(finished(2, 1, 2, (2, -1)));
+            let screen = drawn(&state);
+            let leaderboard = section(&screen, "Leaderboard");
+            assert_eq!(leaderboard.len(), 3);
+            for ((line, name), place) in leaderboard.iter().zip(order).zip(1..) {
+                assert!(line.starts_with(&format!("    {place}. {name} ")), "{line}");
+            }
+            assert!(leaderboard[0].ends_with(if higher_is_better { " 7" } else { " 0" }));
+        }
+    }
+
+    #[test]
+    fn only_the_latest_errors_are_kept() {
+        let mut state = started(true);
+        for round in 0..RECENT_ERRORS + 2 {
+            state.update(error(1, round, &format!("boom {round}\nTraceback ...")));
+        }
+        let screen = drawn(&state);
+        let errors = section(&screen, "Recent errors");
+        assert_eq!(errors.len(), RECENT_ERRORS);
+        assert_eq!(
+            errors[0],
+            format!(
+                "  Gamma (round {}): boom {}",
+                RECENT_ERRORS + 2,
+                RECENT_ERRORS + 1
+            )
+        );
+
+        assert!(!state.done);
+        state.update(ProgressEvent::Finished);
+        assert!(state.done);
+    }
+
+    #[test]
+    fn abandoned_games_are_finished_with_an_error() {
+        let mut state = started(true);
+        state.update(ProgressEvent::MatchStarted {
+            index: 1,
+            player1: 1,
+            player2: 2,
+        });
+        state.update(ProgressEvent::MatchAbandoned {
+            index: 1,
+            error: StrategyError {
+                player: 1,
+                round: 9,
+                message: "the server is down".to_string(),
+                diagnostic: None,
+            },
+        });
+        let screen = drawn(&state);
+        assert!(screen.contains("1/4 games (25%)"), "{screen}");
+        assert_eq!(section(&screen, "Playing"), ["  -"]);
+        assert_eq!(
+            section(&screen, "Recent errors"),
+            ["  Beta (round 10): abandoned the game, the server is down"]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! What strategies print while they play
+//!
+//! Every executor captures the output of a move instead of letting it
+//! through to our stdout: Lua `print`, Python `sys.stdout`/`sys.stderr`,
+//! JavaScript `console`, the `env.log` import of WASM and whatever a
+//! Piston program prints before its move. It ends up in a `DebugLog` per
+//! player per game, capped at `TournamentConfig::debug_log_limit` bytes
+use serde::{Deserialize, Serialize};
+
+/// 64 KiB per player per game
+pub const DEFAULT_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugLog {
+    pub entries: Vec<LogEntry>,
+    /// Output was dropped because the log hit its limit
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// The output of one move
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// 0-based
+    pub round: usize,
+    pub output: String,
+}
+
+impl DebugLog {
+    pub fn new() -> DebugLog {
+        DebugLog::default()
+    }
+    /// Add the output of a move, keeping the whole log under `limit` bytes
+    pub fn push(&mut self, round: usize, output: &str, limit: usize) {
+        if output.is_empty() || self.truncated {
+            return;
+        }
+        let room = limit.saturating_sub(self.len());
+        let mut end = output.len().min(room);
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end < output.len() {
+            self.truncated = true;
+        }
+        if end > 0 {
+            self.entries.push(LogEntry {
+                round,
+                output: output[..end].to_string(),
+            });
+        }
+    }
+    /// Size of the captured output in bytes
+    pub fn len(&self) -> usize {
+        self.entries.iter().map(|entry| entry.output.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && !self.truncated
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::core::{Executor, Player, Tournament, TournamentConfig};
+
+    #[test]
+    fn logs_stop_at_the_limit() {
+        let mut log = DebugLog::new();
+        assert!(log.is_empty());
+        log.push(0, "", 10);
+        assert!(log.is_empty());
+        log.push(0, "hello\n", 10);
+        // "é" is 2 bytes and doesn't fit in the 4 left
+        log.push(1, "abcé\n", 10);
+        log.push(2, "more\n", 10);
+        assert_eq!(log.len(), 9);
+        assert!(log.truncated);
+        assert_eq!(
+            log.entries,
+            [
+                LogEntry {
+                    round: 0,
+                    output: "hello\n".to_string()
+                },
+                LogEntry {
+                    round: 1,
+                    output: "abc".to_string()
+                },
+            ]
+        );
+    }
+
+    fn players() -> Vec<Player> {
+        vec![
+            Player::new(
+                "lua".to_string(),
+                Executor::Lua(
+                    "return function(history, storage) print('lua', #history) return true, storage end"
+                        .to_string(),
+                ),
+            ),
+            Player::new(
+                "python".to_string(),
+                Executor::Python(
+                    "def main(history, storage):\n    print('python', len(history))\n    return (True, storage)\n"
+                        .to_string(),
+                ),
+            ),
+            Player::new(
+                "javascript".to_string(),
+                Executor::JavaScript(
+                    "function main(history, storage) { console.log('javascript', history.length); return [true, storage]; }"
+                        .to_string(),
+                ),
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_language_is_captured() {
+        let config = TournamentConfig::new()
+            .with_players(players())
+            .with_rounds(2)
+            .with_seed(1);
+        let result = Tournament::with_config(config.clone()).run();
+        let expected = ["lua\t0\n", "python 0\n", "javascript 0\n"];
+        assert_eq!(result.matches.len(), 6);
+        for game in &result.matches {
+            let logs = game.logs.as_ref().unwrap();
+            for (player, log) in [(game.player1, &logs.0), (game.player2, &logs.1)] {
+                assert_eq!(log.entries.len(), 2, "{log:?}");
+                assert_eq!(log.entries[0].output, expected[player]);
+                assert_eq!(log.entries[1].round, 1);
+            }
+        }
+
+        let result = Tournament::with_config(config.with_debug_log_limit(0)).run();
+        for game in &result.matches {
+            let logs = game.logs.clone().unwrap_or_default();
+            assert!(logs.0.entries.is_empty() && logs.1.entries.is_empty());
+        }
+    }
+}
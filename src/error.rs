@@ -1,13 +1,157 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Clone, Error, Debug, Serialize, Deserialize)]
 pub enum ExecutionError {
-    #[error("Syntax error: could not parse the program")]
-    SyntaxError,
+    #[error("Syntax error: {0}")]
+    SyntaxError(Diagnostic),
     #[error("Runtime error: {0}")]
-    RuntimeError(String),
+    RuntimeError(Diagnostic),
+    /// A Python strategy imported a module the sandbox doesn't allow
+    #[error("Forbidden import: {0}")]
+    ForbiddenImport(Diagnostic),
+    /// A Python strategy reached for something the sandbox hides, like a
+    /// private name of a module or the globals of a function
+    #[error("Forbidden access: {0}")]
+    ForbiddenAccess(Diagnostic),
     #[error("Deserialization error: {0}")]
     DeserializationError(String),
     #[error("Initialization error: {0}")]
     InitializationError(String),
+    /// The worker process running the strategy crashed
+    /// or hit one of its limits (see `worker`)
+    #[error("Worker error: {0}")]
+    WorkerError(String),
+    /// The move took longer than the strategy's timeout
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    /// Running the strategy made us (or one of the interpreters) panic
+    #[error("Panic: {0}")]
+    Panic(String),
+    /// The server running the strategy couldn't be reached or kept turning
+    /// us away, which isn't the strategy's fault (see `piston`)
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl ExecutionError {
+    /// Where in the program it went wrong, for syntax, runtime and sandbox errors
+    pub fn diagnostic(&self) -> Option<&Diagnostic> {
+        match self {
+            ExecutionError::SyntaxError(diagnostic)
+            | ExecutionError::RuntimeError(diagnostic)
+            | ExecutionError::ForbiddenImport(diagnostic)
+            | ExecutionError::ForbiddenAccess(diagnostic) => Some(diagnostic),
+            _ => None,
+        }
+    }
+}
+
+/// What the interpreter had to say about an error
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub message: String,
+    /// 1-based, in the strategy's source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// 1-based
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceback: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            message: message.into(),
+            ..Diagnostic::default()
+        }
+    }
+    pub fn with_line(mut self, line: Option<usize>) -> Diagnostic {
+        self.line = line;
+        self
+    }
+    pub fn with_column(mut self, column: Option<usize>) -> Diagnostic {
+        self.column = column;
+        self
+    }
+    pub fn with_traceback(mut self, traceback: Option<String>) -> Diagnostic {
+        self.traceback = traceback.filter(|traceback| !traceback.trim().is_empty());
+        self
+    }
+}
+
+/// The message and location, without the traceback
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " (line {line}, column {column})"),
+            (Some(line), None) => write!(f, " (line {line})"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Diagnostic {
+        Diagnostic::new(message)
+    }
+}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Diagnostic {
+        Diagnostic::new(message)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Could not read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Unsupported strategy file {0}")]
+    UnsupportedExtension(PathBuf),
+    #[error("Could not compile {path}: {message}")]
+    CompileError { path: PathBuf, message: String },
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Could not write the checkpoint {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is there, but isn't a checkpoint of this tournament
+    #[error("{0} is not a checkpoint of this tournament, not overwriting it")]
+    Mismatch(PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Could not read {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not write the replay log {path}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid replay log {path} (line {line}): {message}")]
+    Invalid {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    #[error("No strategy matches the logged program of {0}")]
+    MissingPlayer(String),
 }
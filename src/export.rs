@@ -0,0 +1,409 @@
+//! Writing tournament results in machine-readable formats
+//!
+//! The JSON output follows a stable schema, versioned by `schema_version`:
+//! ```json
+//! {
+//!     "schema_version": 2,
+//!     "seed": 42,
+//!     "higher_is_better": true,
+//!     "players": [{
+//!         "index": 0,
+//!         "name": "Tit for Tat",
+//!         "score": 1234,
+//!         "rank": 1,
+//!         "games": 2,
+//!         "abandoned": 0
+//!     }],
+//!     "head_to_head": [[0, 600], [580, 0]],
+//!     "matches": [{
+//!         "player1": 0,
+//!         "player2": 1,
+//!         "scores": [600, 580],
+//!         "rounds": [{"actions": [true, false], "payoffs": [-1, 3]}],
+//!         "errors": [{
+//!             "player": 1,
+//!             "round": 7,
+//!             "message": "...",
+//!             "diagnostic": {"message": "...", "line": 3, "column": 5, "traceback": "..."}
+//!         }],
+//!         "logs": [{"entries": [{"round": 0, "output": "..."}]}, {"entries": []}]
+//!     }],
+//!     "abandoned": [{
+//!         "player1": 0,
+//!         "player2": 1,
+//!         ...,
+//!         "error": {"player": 1, "round": 3, "message": "...", "diagnostic": null}
+//!     }]
+//! }
+//! ```
+//! `diagnostic` is null when the interpreter didn't say more than the
+//! message, and `line`, `column` and `traceback` are left out when unknown.
+//! `logs` is what each player printed (null if neither printed anything),
+//! with `"truncated": true` if it hit the limit. `abandoned` are the games
+//! given up on (see `MatchResult::abandoned`), with the rounds played so far
+//! and the error that ended them; they don't count towards the scores, and
+//! `games` in `players` only counts the others.
+//!
+//! Version 2 added `games` and `abandoned`
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::core::{MatchResult, StrategyError, TournamentResult};
+use crate::report;
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    /// One row per player, best first
+    StandingsCsv,
+    /// A player x player matrix of the score the row player got against the column player
+    HeadToHeadCsv,
+    /// One row per round of every game
+    RoundsCsv,
+    /// One row per abandoned game, with the error that ended it
+    AbandonedCsv,
+    /// A leaderboard table
+    Markdown,
+    /// A self-contained report with charts, see `report`
+    Html,
+}
+
+/// Write `result` to `writer` in the given format
+pub fn write(result: &TournamentResult, format: Format, writer: &mut impl Write) -> io::Result<()> {
+    match format {
+        Format::Json => write_json(result, writer),
+        Format::StandingsCsv => write_standings_csv(result, writer),
+        Format::HeadToHeadCsv => write_head_to_head_csv(result, writer),
+        Format::RoundsCsv => write_rounds_csv(result, writer),
+        Format::AbandonedCsv => write_abandoned_csv(result, writer),
+        Format::Markdown => write_markdown(result, writer),
+        Format::Html => report::write_html(result, writer),
+    }
+}
+
+/// Write `standings.csv`, `head_to_head.csv`, `rounds.csv` and
+/// `abandoned.csv` into `directory`
+pub fn write_csv_directory(result: &TournamentResult, directory: &Path) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    for (name, format) in [
+        ("standings.csv", Format::StandingsCsv),
+        ("head_to_head.csv", Format::HeadToHeadCsv),
+        ("rounds.csv", Format::RoundsCsv),
+        ("abandoned.csv", Format::AbandonedCsv),
+    ] {
+        let mut writer = BufWriter::new(File::create(directory.join(name))?);
+        write(result, format, &mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// 1-based ranks, with ties sharing a rank
+pub(crate) fn ranks(result: &TournamentResult) -> Vec<usize> {
+    let mut ranks = vec![0; result.players.len()];
+    let standings = result.standings();
+    for (place, &player) in standings.iter().enumerate() {
+        ranks[player] = match place {
+            0 => 1,
+            _ if result.scores[standings[place - 1]] == result.scores[player] => {
+                ranks[standings[place - 1]]
+            }
+            _ => place + 1,
+        };
+    }
+    ranks
+}
+
+fn error_json(error: &StrategyError) -> serde_json::Value {
+    json!({
+        "player": error.player,
+        "round": error.round,
+        "message": error.message,
+        "diagnostic": error.diagnostic,
+    })
+}
+
+fn match_json(game: &MatchResult) -> serde_json::Value {
+    let mut value = json!({
+        "player1": game.player1,
+        "player2": game.player2,
+        "scores": game.scores,
+        "rounds": game
+            .rounds
+            .iter()
+            .zip(&game.payoffs)
+            .map(|(actions, payoffs)| json!({"actions": actions, "payoffs": payoffs}))
+            .collect::<Vec<_>>(),
+        "errors": game.errors.iter().map(error_json).collect::<Vec<_>>(),
+        "logs": game.logs,
+    });
+    if let Some(error) = &game.abandoned {
+        value["error"] = error_json(error);
+    }
+    value
+}
+
+pub fn to_json(result: &TournamentResult) -> serde_json::Value {
+    let ranks = ranks(result);
+    let games = result.games();
+    let abandoned = result.abandoned_games();
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "seed": result.seed,
+        "higher_is_better": result.higher_is_better,
+        "players": result
+            .players
+            .iter()
+            .enumerate()
+            .map(|(index, name)| json!({
+                "index": index,
+                "name": name,
+                "score": result.scores[index],
+                "rank": ranks[index],
+                "games": games[index],
+                "abandoned": abandoned[index],
+            }))
+            .collect::<Vec<_>>(),
+        "head_to_head": result.head_to_head(),
+        "matches": result.matches.iter().map(match_json).collect::<Vec<_>>(),
+        "abandoned": result.abandoned.iter().map(match_json).collect::<Vec<_>>(),
+    })
+}
+
+pub fn write_json(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *writer, &to_json(result))?;
+    writeln!(writer)
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_standings_csv(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    let ranks = ranks(result);
+    let games = result.games();
+    let abandoned = result.abandoned_games();
+    writeln!(writer, "rank,player,score,games,abandoned")?;
+    for player in result.standings() {
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            ranks[player],
+            csv_field(&result.players[player]),
+            result.scores[player],
+            games[player],
+            abandoned[player]
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_head_to_head_csv(
+    result: &TournamentResult,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let names: Vec<String> = result.players.iter().map(|name| csv_field(name)).collect();
+    writeln!(writer, "player,{}", names.join(","))?;
+    for (name, row) in names.iter().zip(result.head_to_head()) {
+        let row: Vec<String> = row.iter().map(|score| score.to_string()).collect();
+        writeln!(writer, "{},{}", name, row.join(","))?;
+    }
+    Ok(())
+}
+
+pub fn write_rounds_csv(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        writer,
+        "match,round,player1,player2,action1,action2,payoff1,payoff2"
+    )?;
+    for (index, game) in result.matches.iter().enumerate() {
+        let player1 = csv_field(&result.players[game.player1]);
+        let player2 = csv_field(&result.players[game.player2]);
+        for (round, (actions, payoffs)) in game.rounds.iter().zip(&game.payoffs).enumerate() {
+            let action = |cooperate: bool| if cooperate { "C" } else { "D" };
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{}",
+                index,
+                round,
+                player1,
+                player2,
+                action(actions.0),
+                action(actions.1),
+                payoffs.0,
+                payoffs.1
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_abandoned_csv(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    writeln!(writer, "player1,player2,rounds,player,round,error")?;
+    for game in &result.abandoned {
+        let Some(error) = &game.abandoned else {
+            continue;
+        };
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            csv_field(&result.players[game.player1]),
+            csv_field(&result.players[game.player2]),
+            game.rounds.len(),
+            csv_field(&result.players[error.player]),
+            error.round,
+            csv_field(&error.message)
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_markdown(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    let ranks = ranks(result);
+    let games = result.games();
+    let abandoned = result.abandoned_games();
+    writeln!(writer, "| Rank | Player | Score | Games | Abandoned |")?;
+    writeln!(writer, "| ---: | :----- | ----: | ----: | --------: |")?;
+    for player in result.standings() {
+        writeln!(
+            writer,
+            "| {} | {} | {} | {} | {} |",
+            ranks[player],
+            result.players[player].replace('|', "\\|"),
+            result.scores[player],
+            games[player],
+            abandoned[player]
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn game(player1: usize, player2: usize, rounds: Vec<(bool, bool)>) -> MatchResult {
+        let payoffs: Vec<(i32, i32)> = rounds
+            .iter()
+            .map(|&round| match round {
+                (true, true) => (2, 2),
+                (true, false) => (-1, 3),
+                (false, true) => (3, -1),
+                (false, false) => (0, 0),
+            })
+            .collect();
+        MatchResult {
+            player1,
+            player2,
+            scores: payoffs.iter().fold((0, 0), |total, round| {
+                (total.0 + round.0, total.1 + round.1)
+            }),
+            rounds,
+            payoffs,
+            errors: vec![],
+            careers: None,
+            logs: None,
+            abandoned: None,
+        }
+    }
+
+    /// "a, b" beat "c" and the game of "c" against "a" was given up on
+    fn result() -> TournamentResult {
+        let mut abandoned = game(2, 0, vec![(true, true)]);
+        abandoned.abandoned = Some(StrategyError {
+            player: 2,
+            round: 1,
+            message: "Unavailable: the server is down".to_string(),
+            diagnostic: None,
+        });
+        TournamentResult {
+            seed: 42,
+            players: vec!["a, b".to_string(), "c".to_string(), "d".to_string()],
+            scores: vec![4, -1, 0],
+            matches: vec![
+                game(0, 1, vec![(true, true), (false, true)]),
+                game(1, 2, vec![(false, false)]),
+            ],
+            abandoned: vec![abandoned],
+            higher_is_better: true,
+        }
+    }
+
+    fn written(result: &TournamentResult, format: Format) -> String {
+        let mut buffer = vec![];
+        write(result, format, &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn json_follows_the_schema() {
+        let json = to_json(&result());
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["seed"], 42);
+        assert_eq!(
+            json["players"][0],
+            json!({"index": 0, "name": "a, b", "score": 4, "rank": 1, "games": 1, "abandoned": 1})
+        );
+        assert_eq!(json["players"][2]["games"], 1);
+        assert_eq!(
+            json["head_to_head"],
+            json!([[0, 5, 0], [1, 0, 0], [0, 0, 0]])
+        );
+        assert_eq!(
+            json["matches"][0]["rounds"][1],
+            json!({"actions": [false, true], "payoffs": [3, -1]})
+        );
+        assert!(json["matches"][0].get("error").is_none());
+        assert_eq!(json["abandoned"][0]["player1"], 2);
+        assert_eq!(json["abandoned"][0]["rounds"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            json["abandoned"][0]["error"],
+            json!({"player": 2, "round": 1, "message": "Unavailable: the server is down", "diagnostic": null})
+        );
+    }
+
+    #[test]
+    fn standings_count_games_and_tie_ranks() {
+        let mut result = result();
+        result.scores = vec![4, 4, 0];
+        assert_eq!(ranks(&result), vec![1, 1, 3]);
+        assert_eq!(
+            written(&result, Format::StandingsCsv),
+            "rank,player,score,games,abandoned\n1,\"a, b\",4,1,1\n1,c,4,2,0\n3,d,0,1,1\n"
+        );
+    }
+
+    #[test]
+    fn csv_lists_rounds_and_abandoned_games() {
+        assert_eq!(
+            written(&result(), Format::RoundsCsv).lines().nth(2),
+            Some("0,1,\"a, b\",c,D,C,3,-1")
+        );
+        assert_eq!(
+            written(&result(), Format::AbandonedCsv),
+            "player1,player2,rounds,player,round,error\nd,\"a, b\",1,d,1,Unavailable: the server is down\n"
+        );
+        assert_eq!(
+            written(&result(), Format::HeadToHeadCsv).lines().next(),
+            Some("player,\"a, b\",c,d")
+        );
+    }
+
+    #[test]
+    fn markdown_escapes_pipes() {
+        let mut result = result();
+        result.players[0] = "a|b".to_string();
+        assert!(written(&result, Format::Markdown).contains("| 1 | a\\|b | 4 | 1 | 1 |"));
+    }
+}
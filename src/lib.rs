@@ -1,3 +1,21 @@
+pub mod builtins;
+pub mod cache;
+pub mod checkpoint;
+pub mod command;
 pub mod core;
+pub mod dashboard;
+pub mod debug_log;
 pub mod error;
+pub mod export;
+pub mod loader;
+pub mod observer;
+pub mod piston;
+pub mod replay;
+pub mod report;
+pub mod rng;
+pub mod sandbox;
+#[cfg(test)]
+mod testing;
+pub mod validate;
 mod wasm_utils;
+pub mod worker;
@@ -0,0 +1,353 @@
+//! Loading strategies from disk
+//!
+//! The language of a strategy is picked from its extension:
+//! - `.lua` => Lua
+//! - `.py` => Python
+//! - `.js` => JavaScript
+//! - `.wasm` and `.wat` => WASM
+//! - `.ts` => compiled to WASM with AssemblyScript (`asc`)
+//!
+//! The player is named after the file, unless the file starts with
+//! a header comment like this one:
+//! ```lua
+//! -- name: Tit for Tat
+//! -- author: Anatol Rapoport
+//! function(history, storage)
+//!     ...
+//! ```
+//! Every `key: value` line of the header ends up in `Player::metadata`
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::core::{Executor, Player};
+use crate::error::LoadError;
+use crate::rng::hash_str;
+
+pub struct Loader {
+    /// The command used to compile TypeScript to WASM
+    typescript_compiler: Vec<String>,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader {
+            typescript_compiler: vec!["asc".to_string()],
+        }
+    }
+    /// e.g. `vec!["npx", "asc"]`. The source file, `--outFile <output>`
+    /// and `--optimize` are appended
+    pub fn with_typescript_compiler(mut self, command: Vec<String>) -> Loader {
+        self.typescript_compiler = command;
+        self
+    }
+    /// Whether `load_file` knows what to do with this file
+    pub fn is_supported(path: &Path) -> bool {
+        matches!(
+            extension(path),
+            Some("lua" | "py" | "js" | "wasm" | "wat" | "ts")
+        )
+    }
+    pub fn load_file(&self, path: &Path) -> Result<Player, LoadError> {
+        let read_error = |source| LoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let (executor, header) = match extension(path) {
+            Some("wasm") => (Executor::WASM(fs::read(path).map_err(read_error)?), None),
+            Some(extension) if Loader::is_supported(path) => {
+                let program = fs::read_to_string(path).map_err(read_error)?;
+                let header = header(&program, comment_prefix(extension));
+                let executor = match extension {
+                    "lua" => Executor::Lua(program),
+                    "py" => Executor::Python(program),
+                    "js" => Executor::JavaScript(program),
+                    "wat" => Executor::WASM(program.into_bytes()),
+                    _ => Executor::WASM(self.compile_typescript(path, &program)?),
+                };
+                (executor, Some(header))
+            }
+            _ => return Err(LoadError::UnsupportedExtension(path.to_path_buf())),
+        };
+        let metadata = header.unwrap_or_default();
+        let name = metadata.get("name").cloned().unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string())
+        });
+        Ok(Player::new(name, executor).with_metadata(metadata))
+    }
+    /// Load every supported file in a directory (not recursively),
+    /// sorted by file name. Other files are ignored. Fails on the first
+    /// file that doesn't load, see `load_directory_lenient`
+    pub fn load_directory(&self, path: &Path) -> Result<Vec<Player>, LoadError> {
+        let (players, mut errors) = self.load_directory_lenient(path)?;
+        if errors.is_empty() {
+            Ok(players)
+        } else {
+            Err(errors.remove(0))
+        }
+    }
+    /// Like `load_directory`, but a file that doesn't load only leaves out
+    /// that player, so one broken submission doesn't hold up the rest.
+    /// Gives the players that loaded and what went wrong with the others
+    pub fn load_directory_lenient(
+        &self,
+        path: &Path,
+    ) -> Result<(Vec<Player>, Vec<LoadError>), LoadError> {
+        let read_error = |source| LoadError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut files: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(read_error)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()
+            .map_err(read_error)?;
+        files.sort();
+        let mut players = vec![];
+        let mut errors = vec![];
+        for file in files
+            .iter()
+            .filter(|file| file.is_file() && Loader::is_supported(file))
+            .filter(|file| {
+                !file
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            })
+        {
+            match self.load_file(file) {
+                Ok(player) => players.push(player),
+                Err(err) => errors.push(err),
+            }
+        }
+        Ok((players, errors))
+    }
+    fn compile_typescript(&self, path: &Path, program: &str) -> Result<Vec<u8>, LoadError> {
+        let compile_error = |message: String| LoadError::CompileError {
+            path: path.to_path_buf(),
+            message,
+        };
+        let Some((command, args)) = self.typescript_compiler.split_first() else {
+            return Err(compile_error(
+                "No TypeScript compiler configured".to_string(),
+            ));
+        };
+        let output_path = output_file(program)
+            .map_err(|err| compile_error(format!("Could not create the output file: {err}")))?;
+        let output = Command::new(command)
+            .args(args)
+            .arg(path)
+            .arg("--outFile")
+            .arg(&output_path)
+            .arg("--optimize")
+            .output();
+        let module = match output {
+            Err(err) => Err(compile_error(format!("Could not run {command}: {err}"))),
+            Ok(output) if !output.status.success() => Err(compile_error(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            )),
+            Ok(_) => fs::read(&output_path).map_err(|err| compile_error(err.to_string())),
+        };
+        let _ = fs::remove_file(&output_path);
+        module
+    }
+}
+
+/// A new, empty file for the compiler to write to. Never shared, since the
+/// same program might be getting compiled by another loader or process
+fn output_file(program: &str) -> io::Result<PathBuf> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "ipdt-{:016x}-{}-{}.wasm",
+            hash_str(program),
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Shorthand for `Loader::new().load_directory(path)`
+pub fn load_directory(path: &Path) -> Result<Vec<Player>, LoadError> {
+    Loader::new().load_directory(path)
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+fn comment_prefix(extension: &str) -> &'static str {
+    match extension {
+        "lua" => "--",
+        "py" => "#",
+        "wat" => ";;",
+        _ => "//",
+    }
+}
+
+/// Parse the `key: value` lines of the leading comment block
+fn header(program: &str, prefix: &str) -> BTreeMap<String, String> {
+    program
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty())
+        .map_while(|line| line.strip_prefix(prefix))
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let key = key.trim();
+            let is_key = !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            is_key.then(|| (key.to_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testing;
+
+    /// An empty directory of its own for every test
+    fn scratch(name: &str) -> PathBuf {
+        let directory = testing::scratch(name);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Compiled modules nobody cleaned up after
+    fn leftovers() -> Vec<PathBuf> {
+        let ours = format!("-{}-", std::process::id());
+        fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.contains(&ours) && name.ends_with(".wasm")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers_name_players() {
+        let directory = scratch("headers");
+        let path = directory.join("tft.py");
+        fs::write(
+            &path,
+            "\n# name: Tit for Tat\n# Author: Anatol Rapoport\n# just a note\ndef main(history, storage):\n    # later: not a header\n    return (True, storage)\n",
+        )
+        .unwrap();
+        let player = Loader::new().load_file(&path).unwrap();
+        assert_eq!(player.name, "Tit for Tat");
+        assert_eq!(
+            player.metadata,
+            BTreeMap::from([
+                ("name".to_string(), "Tit for Tat".to_string()),
+                ("author".to_string(), "Anatol Rapoport".to_string()),
+            ])
+        );
+
+        let path = directory.join("plain.lua");
+        fs::write(
+            &path,
+            "return function(history, storage) return true, storage end",
+        )
+        .unwrap();
+        let player = Loader::new().load_file(&path).unwrap();
+        assert_eq!(player.name, "plain");
+        assert!(player.metadata.is_empty());
+        assert!(matches!(player.executor, Executor::Lua(_)));
+    }
+
+    #[test]
+    fn directories_load_supported_files_in_order() {
+        let directory = scratch("directory");
+        for name in ["b.js", "a.py", ".hidden.py", "notes.txt", "c.lua"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+        fs::create_dir(directory.join("d.py")).unwrap();
+        let names: Vec<String> = load_directory(&directory)
+            .unwrap()
+            .into_iter()
+            .map(|player| player.name.clone())
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert!(matches!(
+            Loader::new().load_file(&directory.join("notes.txt")),
+            Err(LoadError::UnsupportedExtension(_))
+        ));
+    }
+
+    #[test]
+    fn broken_files_only_leave_out_their_player() {
+        let directory = scratch("broken");
+        fs::write(directory.join("a.py"), "").unwrap();
+        fs::write(directory.join("b.ts"), "").unwrap();
+        fs::write(directory.join("c.wasm"), "").unwrap();
+        let loader = Loader::new().with_typescript_compiler(vec!["false".to_string()]);
+        let (players, errors) = loader.load_directory_lenient(&directory).unwrap();
+        let names: Vec<&str> = players.iter().map(|player| player.name.as_str()).collect();
+        assert_eq!(names, ["a", "c"]);
+        assert!(matches!(errors[..], [LoadError::CompileError { .. }]));
+        assert!(matches!(
+            loader.load_directory(&directory),
+            Err(LoadError::CompileError { .. })
+        ));
+    }
+
+    #[test]
+    fn typescript_compiles_to_a_file_of_its_own() {
+        let directory = scratch("typescript");
+        let path = directory.join("strategy.ts");
+        fs::write(&path, "(module)").unwrap();
+        // "Compiles" by copying the source, slowly enough for runs to overlap
+        let loader = Loader::new().with_typescript_compiler(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "sleep 0.1; cat \"$1\" > \"$3\"".to_string(),
+            "sh".to_string(),
+        ]);
+        thread::scope(|scope| {
+            let compiles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| loader.load_file(&path)))
+                .collect();
+            for compile in compiles {
+                match &compile.join().unwrap().unwrap().executor {
+                    Executor::WASM(module) => assert_eq!(module, b"(module)"),
+                    other => panic!("{other:?}"),
+                }
+            }
+        });
+        assert_eq!(leftovers(), Vec::<PathBuf>::new());
+
+        let failing = Loader::new().with_typescript_compiler(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "echo 'type error' >&2; exit 1".to_string(),
+        ]);
+        match failing.load_file(&path) {
+            Err(LoadError::CompileError { message, .. }) => assert_eq!(message, "type error\n"),
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(leftovers(), Vec::<PathBuf>::new());
+    }
+}
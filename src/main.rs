@@ -1,136 +1,1075 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ipdt::builtins;
+use ipdt::command::CommandProgram;
 use ipdt::core::Executor;
 use ipdt::core::Player;
+use ipdt::core::StrategyError;
 use ipdt::core::Tournament;
 use ipdt::core::TournamentConfig;
-fn main() {
-    let tit_for_tat = Player::new(
-        "Tit for Tat".to_string(),
-        Executor::Lua(
-            r#"
-            function(history, storage)
-                if #history == 0 then
-                    return true, storage
-                else
-                    return history[#history][2], storage
-                end
-            end
-            "#
-            .to_string(),
-        ),
-    );
+use ipdt::core::TournamentResult;
+use ipdt::dashboard;
+use ipdt::error::CheckpointError;
+use ipdt::export;
+use ipdt::loader::Loader;
+use ipdt::piston::{self, PistonInput, PistonProgram, PistonServer, Stub};
+use ipdt::replay::Replay;
+use ipdt::validate::{Problem, Report, Validator};
+use ipdt::worker::Isolation;
+use serde::Deserialize;
 
-    let grim_trigger = Player::new(
-        "Grim Trigger".to_string(),
-        Executor::Lua(
-            r#"
-            function(history, storage)
-                if storage == "defect" then
-                    return false, storage
-                end
-                for _, round in ipairs(history) do
-                    if not round[2] then
-                        return false, "defect"
-                    end
-                end
-                return true, storage
-            end
-            "#
-            .to_string(),
-        ),
-    );
+/// Iterated Prisoner's Dilemma Tournament
+#[derive(Parser)]
+#[command(name = "ipdt", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let always_cooperate = Player::new(
-        "Always Cooperate".to_string(),
-        Executor::Lua(
-            r#"
-            function(history, storage)
-                return true, storage
-            end
-            "#
-            .to_string(),
-        ),
-        //         Executor::Piston(
-        //             "python".to_string(),
-        //             r#"
-        // import json, sys
-        // print(json.dumps({"action": True, "storage": json.loads(sys.argv[2])}))
-        // "#
-        //             .to_string(),
-        //         ),
-    );
+#[derive(Subcommand)]
+enum Command {
+    /// Run a tournament described by a JSON config file,
+    /// or between every strategy in a directory
+    Run {
+        config: PathBuf,
+        #[command(flatten)]
+        options: Options,
+        /// Show a live dashboard on stderr while the tournament runs
+        #[arg(long)]
+        dashboard: bool,
+        /// Save finished games here, and skip the ones already saved
+        /// by an interrupted run of the same config
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+        /// Start the checkpoint over, even if it holds another config's games
+        #[arg(long, requires = "checkpoint")]
+        overwrite_checkpoint: bool,
+        /// Reuse games from earlier runs cached in this directory
+        /// (needs a fixed seed)
+        #[arg(long)]
+        cache: Option<PathBuf>,
+        /// Record every move and storage to this file, for `ipdt replay`
+        #[arg(long)]
+        replay_log: Option<PathBuf>,
+        /// Check every strategy first, and don't start if any has problems
+        #[arg(long)]
+        validate: bool,
+        #[command(flatten)]
+        smoke: Smoke,
+    },
+    /// Play a single game between two strategies
+    /// (strategy files or built-in names)
+    Match {
+        a: String,
+        b: String,
+        #[command(flatten)]
+        options: Options,
+    },
+    /// Show the results of a replay log without running anything
+    Replay {
+        log: PathBuf,
+        /// Show every round of the game with this schedule index instead
+        #[arg(long)]
+        game: Option<usize>,
+        /// Run the strategies of this config (or directory) again and check
+        /// that they make the logged moves
+        #[arg(long, value_name = "CONFIG")]
+        verify: Option<PathBuf>,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+        /// Write the results here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show what strategies printed, from a replay log
+    Logs {
+        log: PathBuf,
+        /// Only the game with this schedule index
+        #[arg(long)]
+        game: Option<usize>,
+        /// Only the strategy with this name
+        #[arg(long)]
+        player: Option<String>,
+    },
+    /// Check that strategies load and can play a few rounds against
+    /// the built-ins (strategy files, directories or config files)
+    Validate {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        smoke: Smoke,
+    },
+    /// List the built-in strategies
+    ListBuiltins,
+    /// Run strategies for another ipdt process (see `--isolate`)
+    #[command(hide = true)]
+    Worker,
+    /// Serve a minimal Piston API running programs with local interpreters,
+    /// to try Piston strategies offline (trusted programs only)
+    PistonStub {
+        #[arg(long, default_value = "127.0.0.1:2000")]
+        listen: String,
+        /// The command running a language, like `python=python3.12`
+        /// (python, javascript, lua, ruby and bash run with their usual interpreters)
+        #[arg(long = "runtime", value_parser = parse_runtime)]
+        runtimes: Vec<(String, Vec<String>)>,
+    },
+}
 
-    let always_defect = Player::new(
-        "Always Defect".to_string(),
-        // Executor::Lua(
-        //     r#"
-        //     function(history, storage)
-        //         return false, storage
-        //     end
-        //     "#
-        //     .to_string(),
-        // ),
-        Executor::Python(
-            r#"
-def main(history, storage):
-    return False, storage
-"#
-            .to_string(),
-        ),
-    );
-    let forgiving_tit_for_tat = Player::new(
-        "Forgiving Tit for Tat".to_string(),
-        Executor::Lua(
-            r#"
-            function(history, storage)
-                if #history == 0 then
-                    return true, storage
-                elseif #history == 1 then
-                    return history[#history][2], storage
-                else
-                    if not history[#history][2] and not history[#history - 1][2] then
-                        return false, storage
-                    else
-                        return true, storage
-                    end
-                end
-            end
-            "#
-            .to_string(),
-        ),
-    );
+/// Overrides for whatever the config says
+#[derive(Args)]
+struct Options {
+    /// Rounds per game
+    #[arg(short, long)]
+    rounds: Option<i32>,
+    /// Seed for reproducible runs
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Probability that a move gets flipped
+    #[arg(long)]
+    noise: Option<f64>,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Write the results here instead of stdout
+    /// (a directory for CSV, which gets standings, head-to-head and per-round files)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Games to play at once (0 = one per CPU)
+    #[arg(short = 'j', long)]
+    parallelism: Option<usize>,
+    /// Tell strategies how many rounds a game has
+    #[arg(long)]
+    disclose_rounds: bool,
+    /// Tell strategies an anonymous ID instead of their opponent's name
+    #[arg(long)]
+    anonymize_opponents: bool,
+    /// Let strategies keep a storage across all their games
+    /// (plays the games one at a time)
+    #[arg(long)]
+    career_storage: bool,
+    /// Bytes of printed output to keep per strategy per game (0 to drop it all)
+    #[arg(long)]
+    log_limit: Option<usize>,
+    /// Python modules strategies may import, comma separated
+    /// (collections, functools, itertools, json, math, random and re by default)
+    #[arg(long, value_delimiter = ',')]
+    python_modules: Option<Vec<String>>,
+    /// How deep Python calls may nest
+    #[arg(long)]
+    recursion_limit: Option<usize>,
+    /// Run every strategy in a worker process of its own
+    #[arg(long)]
+    isolate: bool,
+    /// MiB of memory a worker may use, 1024 by default (0 for no limit; implies --isolate)
+    #[arg(long)]
+    memory_limit: Option<u64>,
+    /// Seconds a move may take before its worker is killed (implies --isolate)
+    #[arg(long)]
+    worker_timeout: Option<f64>,
+    /// Give workers no network, with Linux namespaces (implies --isolate)
+    #[arg(long)]
+    namespaces: bool,
+    /// Keep workers from opening sockets, starting programs or changing
+    /// files, with seccomp on Linux (implies --isolate)
+    #[arg(long)]
+    seccomp: bool,
+    /// Base URL of the Piston server to run Piston strategies on
+    #[arg(long)]
+    piston_url: Option<String>,
+    /// API key for the Piston server (or set PISTON_API_KEY)
+    #[arg(long)]
+    piston_api_key: Option<String>,
+}
 
-    let detective = Player::new(
-        "Detective".to_string(),
-        Executor::Python(
-            r#"
-def main(history, storage):
-    if len(history) < 4:
-        moves = [True, False, True, True]
-        return moves[len(history)], storage
-    else:
-        for round in history:
-            if not round[1]:
-                return history[-1][1], storage
-        return False, storage
-"#
-            .to_string(),
-        ),
-    );
-    let mut tournament = Tournament::with_config(
-        TournamentConfig::new()
-            .with_players(vec![
-                tit_for_tat,
-                grim_trigger,
-                always_cooperate,
-                always_defect,
-                forgiving_tit_for_tat,
-                detective,
-            ])
-            .with_rounds(200),
+/// How hard `validate` tries
+#[derive(Args)]
+struct Smoke {
+    /// Rounds to play against each built-in when validating (0 to only parse)
+    #[arg(long, default_value_t = 5)]
+    smoke_rounds: usize,
+    /// Seconds a move may take when validating
+    #[arg(long, default_value_t = 5.0)]
+    move_timeout: f64,
+}
+
+impl Smoke {
+    fn validator(&self) -> Result<Validator, String> {
+        let timeout = Duration::try_from_secs_f64(self.move_timeout)
+            .map_err(|err| format!("Invalid timeout: {err}"))?;
+        Ok(Validator::new()
+            .with_rounds(self.smoke_rounds)
+            .with_timeout(timeout))
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+    Markdown,
+    /// A self-contained HTML report with charts
+    Html,
+}
+
+fn check_rounds(rounds: i32) -> Result<i32, String> {
+    if rounds < 0 {
+        return Err(format!("The number of rounds can't be negative ({rounds})"));
+    }
+    Ok(rounds)
+}
+
+fn check_noise(noise: f64) -> Result<f64, String> {
+    if !(0.0..=1.0).contains(&noise) {
+        return Err(format!("Noise is a probability, between 0 and 1 ({noise})"));
+    }
+    Ok(noise)
+}
+
+impl Options {
+    fn apply(&self, mut config: TournamentConfig) -> Result<TournamentConfig, String> {
+        if let Some(rounds) = self.rounds {
+            config = config.with_rounds(check_rounds(rounds)?);
+        }
+        if let Some(seed) = self.seed {
+            config = config.with_seed(seed);
+        }
+        if let Some(noise) = self.noise {
+            config = config.with_noise(check_noise(noise)?);
+        }
+        if let Some(parallelism) = self.parallelism {
+            config = config.with_parallelism(parallelism);
+        }
+        if self.disclose_rounds {
+            config = config.with_disclosed_rounds(true);
+        }
+        if self.anonymize_opponents {
+            config = config.with_anonymous_opponents(true);
+        }
+        if self.career_storage {
+            config = config.with_career_storage(true);
+        }
+        if let Some(limit) = self.log_limit {
+            config = config.with_debug_log_limit(limit);
+        }
+        if let Some(modules) = &self.python_modules {
+            config.sandbox = config.sandbox.with_python_modules(modules);
+        }
+        if let Some(limit) = self.recursion_limit {
+            config.sandbox = config.sandbox.with_recursion_limit(limit);
+        }
+        let isolate = self.isolate
+            || self.memory_limit.is_some()
+            || self.worker_timeout.is_some()
+            || self.namespaces
+            || self.seccomp;
+        if isolate {
+            let entry = IsolationEntry {
+                memory_limit: self.memory_limit,
+                move_timeout: self.worker_timeout,
+                namespaces: self.namespaces,
+                seccomp: self.seccomp,
+            };
+            let isolation = entry.apply(config.isolation.take().unwrap_or_default())?;
+            config = config.with_isolation(Some(isolation));
+        }
+        let api_key = self
+            .piston_api_key
+            .clone()
+            .or_else(|| std::env::var("PISTON_API_KEY").ok());
+        for player in &mut config.players {
+            if let Executor::Piston(piston) = &mut player.executor {
+                if let Some(url) = &self.piston_url {
+                    piston.server.url = url.clone();
+                }
+                if api_key.is_some() {
+                    piston.server.api_key = api_key.clone();
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The on-disk tournament description used by `ipdt run`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    rounds: Option<i32>,
+    seed: Option<u64>,
+    noise: Option<f64>,
+    parallelism: Option<usize>,
+    #[serde(default)]
+    disclose_rounds: bool,
+    #[serde(default)]
+    anonymize_opponents: bool,
+    #[serde(default)]
+    career_storage: bool,
+    debug_log_limit: Option<usize>,
+    python_modules: Option<Vec<String>>,
+    recursion_limit: Option<usize>,
+    isolation: Option<IsolationEntry>,
+    /// Where Piston strategies run, the public server by default
+    #[serde(default)]
+    piston: PistonServer,
+    #[serde(default)]
+    scoring: Scoring,
+    #[serde(default)]
+    players: Vec<PlayerEntry>,
+    /// Directories to load every strategy from, relative to the config file
+    #[serde(default)]
+    directories: Vec<PathBuf>,
+}
+
+/// Worker process settings, `"isolation": {}` for the defaults
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IsolationEntry {
+    /// MiB, 0 for no limit
+    memory_limit: Option<u64>,
+    /// Seconds
+    move_timeout: Option<f64>,
+    #[serde(default)]
+    namespaces: bool,
+    #[serde(default)]
+    seccomp: bool,
+}
+
+impl IsolationEntry {
+    /// Set what the entry says, on top of `isolation`
+    fn apply(&self, mut isolation: Isolation) -> Result<Isolation, String> {
+        if let Some(limit) = self.memory_limit {
+            isolation = isolation.with_memory_limit((limit > 0).then_some(limit * 1024 * 1024));
+        }
+        if let Some(timeout) = self.move_timeout {
+            let timeout = Duration::try_from_secs_f64(timeout)
+                .map_err(|err| format!("Invalid timeout: {err}"))?;
+            isolation = isolation.with_move_timeout(Some(timeout));
+        }
+        isolation.namespaces |= self.namespaces;
+        isolation.seccomp |= self.seccomp;
+        Ok(isolation)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Scoring {
+    #[default]
+    Nick,
+    Classic,
+    Custom {
+        mutual_win: i32,
+        mutual_loss: i32,
+        win: i32,
+        loss: i32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlayerEntry {
+    name: Option<String>,
+    /// The name of a built-in strategy
+    builtin: Option<String>,
+    /// Path to the strategy, relative to the config file
+    file: Option<PathBuf>,
+    /// Run `file` on Piston with this language instead of locally
+    piston: Option<String>,
+    /// The version of the Piston language, the latest by default
+    piston_version: Option<String>,
+    /// `"argv"` for Piston programs taking their input as arguments,
+    /// `"match"` for playing a whole match in one run
+    #[serde(default)]
+    piston_input: PistonInput,
+    /// A local program and its arguments, started once per game
+    command: Option<Vec<String>>,
+    /// Where to run `command`, relative to the config file
+    working_dir: Option<PathBuf>,
+    /// Environment variables for `command`
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Seconds `command` may take to start and make its first move
+    startup_timeout: Option<f64>,
+    /// Seconds every other move of `command` may take
+    move_timeout: Option<f64>,
+}
+
+impl ConfigFile {
+    /// A directory of strategies or a config file. Loading `leniently`
+    /// leaves out the strategies in directories that don't load (saying
+    /// so on stderr) rather than failing
+    fn load(path: &Path, leniently: bool) -> Result<TournamentConfig, String> {
+        if path.is_dir() {
+            let players = load_directory(path, leniently)?;
+            return Ok(TournamentConfig::new().with_players(players));
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+        let file: ConfigFile = serde_json::from_str(&text)
+            .map_err(|err| format!("Invalid config {}: {err}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        let mut config = TournamentConfig::new();
+        config = match file.scoring {
+            Scoring::Nick => config.with_nick_style_score(),
+            Scoring::Classic => config.with_classic_style_score(),
+            Scoring::Custom {
+                mutual_win,
+                mutual_loss,
+                win,
+                loss,
+            } => config
+                .with_mutual_win_score(mutual_win)
+                .with_mutual_loss_score(mutual_loss)
+                .with_win_score(win)
+                .with_loss_score(loss),
+        };
+        if let Some(rounds) = file.rounds {
+            config = config.with_rounds(check_rounds(rounds)?);
+        }
+        if let Some(seed) = file.seed {
+            config = config.with_seed(seed);
+        }
+        if let Some(noise) = file.noise {
+            config = config.with_noise(check_noise(noise)?);
+        }
+        if let Some(parallelism) = file.parallelism {
+            config = config.with_parallelism(parallelism);
+        }
+        if let Some(limit) = file.debug_log_limit {
+            config = config.with_debug_log_limit(limit);
+        }
+        if let Some(modules) = file.python_modules {
+            config.sandbox = config.sandbox.with_python_modules(modules);
+        }
+        if let Some(limit) = file.recursion_limit {
+            config.sandbox = config.sandbox.with_recursion_limit(limit);
+        }
+        if let Some(entry) = file.isolation {
+            config = config.with_isolation(Some(entry.apply(Isolation::new())?));
+        }
+        config = config
+            .with_disclosed_rounds(file.disclose_rounds)
+            .with_anonymous_opponents(file.anonymize_opponents)
+            .with_career_storage(file.career_storage);
+        let mut players = file
+            .players
+            .into_iter()
+            .map(|entry| entry.load(base, &file.piston))
+            .collect::<Result<Vec<_>, _>>()?;
+        for directory in file.directories {
+            players.extend(load_directory(&base.join(directory), leniently)?);
+        }
+        Ok(config.with_players(players))
+    }
+}
+
+impl PlayerEntry {
+    fn load(self, base: &Path, server: &PistonServer) -> Result<Player, String> {
+        let mut player = match (self.builtin, self.file, self.command) {
+            (None, None, Some(command)) => {
+                let timeout = |seconds: Option<f64>, default| match seconds {
+                    Some(seconds) => Duration::try_from_secs_f64(seconds)
+                        .map(Some)
+                        .map_err(|err| format!("Invalid timeout: {err}")),
+                    None => Ok(default),
+                };
+                let defaults = CommandProgram::new(vec![]);
+                // Relative to the config file, like `file`
+                let directory = base.join(self.working_dir.unwrap_or_default());
+                // Named after the script, rather than the interpreter running it
+                let name = command
+                    .last()
+                    .and_then(|last| Path::new(last).file_stem())
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let program = CommandProgram::new(command)
+                    .with_working_dir((!directory.as_os_str().is_empty()).then_some(directory))
+                    .with_env(self.env)
+                    .with_startup_timeout(timeout(self.startup_timeout, defaults.startup_timeout)?)
+                    .with_move_timeout(timeout(self.move_timeout, defaults.move_timeout)?);
+                Player::new(name, Executor::Command(program))
+            }
+            (Some(name), None, None) => {
+                builtins::builtin(&name).ok_or(format!("Unknown built-in strategy {name:?}"))?
+            }
+            (None, Some(file), None) => {
+                let path = base.join(file);
+                match self.piston {
+                    Some(language) => {
+                        let program = fs::read_to_string(&path)
+                            .map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+                        let name = path
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().into_owned())
+                            .unwrap_or_else(|| path.display().to_string());
+                        let mut piston = PistonProgram::new(language, program)
+                            .with_server(server.clone())
+                            .with_input(self.piston_input);
+                        if let Some(version) = self.piston_version {
+                            piston = piston.with_version(version);
+                        }
+                        Player::new(name, Executor::Piston(piston))
+                    }
+                    None => load_file(&path)?,
+                }
+            }
+            _ => {
+                return Err(
+                    "Each player needs exactly one of `builtin`, `file` or `command`".to_string(),
+                )
+            }
+        };
+        if let Some(name) = self.name {
+            player.name = name;
+        }
+        Ok(player)
+    }
+}
+
+fn load_directory(path: &Path, leniently: bool) -> Result<Vec<Player>, String> {
+    if !leniently {
+        return Loader::new()
+            .load_directory(path)
+            .map_err(|err| err.to_string());
+    }
+    let (players, errors) = Loader::new()
+        .load_directory_lenient(path)
+        .map_err(|err| err.to_string())?;
+    for err in errors {
+        eprintln!("Leaving out a strategy: {err}");
+    }
+    Ok(players)
+}
+
+fn load_file(path: &Path) -> Result<Player, String> {
+    Loader::new().load_file(path).map_err(|err| err.to_string())
+}
+
+/// A strategy given on the command line:
+/// either a file or the name of a built-in
+fn load_strategy(spec: &str) -> Result<Player, String> {
+    let path = Path::new(spec);
+    if path.exists() {
+        load_file(path)
+    } else {
+        builtins::builtin(spec).ok_or(format!("No such file or built-in strategy: {spec}"))
+    }
+}
+
+fn moves(actions: impl Iterator<Item = bool>) -> String {
+    actions
+        .map(|action| if action { 'C' } else { 'D' })
+        .collect()
+}
+
+/// The first strategy error of every player, with its traceback, on stderr
+fn print_errors(result: &TournamentResult) {
+    let mut errors: Vec<(usize, usize, &StrategyError)> = vec![];
+    for (index, game) in result.matches.iter().enumerate() {
+        for error in &game.errors {
+            match errors
+                .iter_mut()
+                .find(|(_, _, first)| first.player == error.player)
+            {
+                Some((_, count, _)) => *count += 1,
+                None => errors.push((index, 1, error)),
+            }
+        }
+    }
+    for game in &result.abandoned {
+        if let Some(error) = &game.abandoned {
+            eprintln!(
+                "{} vs {} was abandoned in round {}: {}",
+                result.players[game.player1],
+                result.players[game.player2],
+                error.round + 1,
+                error.message
+            );
+        }
+    }
+    for (index, count, error) in errors {
+        eprintln!(
+            "{} failed in game {}, round {}: {}",
+            result.players[error.player],
+            index + 1,
+            error.round + 1,
+            error.message
+        );
+        let traceback = error
+            .diagnostic
+            .as_ref()
+            .and_then(|diagnostic| diagnostic.traceback.as_deref());
+        for line in traceback.into_iter().flat_map(str::lines) {
+            eprintln!("    {line}");
+        }
+        if count > 1 {
+            eprintln!("    ({} more errors)", count - 1);
+        }
+    }
+}
+
+/// Write the result to stdout, or to `output` (a directory for CSV).
+/// Text is always printed
+fn export(result: &TournamentResult, format: Format, output: Option<&Path>) -> Result<(), String> {
+    let format = match format {
+        Format::Text => {
+            print_errors(result);
+            let games = result.games();
+            let abandoned = result.abandoned_games();
+            for player in result.standings() {
+                // Only worth a mention when games were abandoned, since
+                // everyone plays the same number of games otherwise
+                if result.abandoned.is_empty() {
+                    println!("{}: {}", result.players[player], result.scores[player]);
+                } else {
+                    println!(
+                        "{}: {} ({} games, {} abandoned)",
+                        result.players[player],
+                        result.scores[player],
+                        games[player],
+                        abandoned[player]
+                    );
+                }
+            }
+            return Ok(());
+        }
+        Format::Json => export::Format::Json,
+        Format::Markdown => export::Format::Markdown,
+        Format::Html => export::Format::Html,
+        Format::Csv => match output {
+            Some(directory) => {
+                return export::write_csv_directory(result, directory)
+                    .map_err(|err| format!("Could not write {}: {err}", directory.display()))
+            }
+            None => export::Format::StandingsCsv,
+        },
+    };
+    let written = match output {
+        Some(path) => fs::File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            export::write(result, format, &mut writer)?;
+            writer.flush()
+        }),
+        None => export::write(result, format, &mut io::stdout().lock()),
+    };
+    written.map_err(|err| format!("Could not write results: {err}"))
+}
+
+fn run(
+    config: TournamentConfig,
+    options: &Options,
+    dashboard: bool,
+    checkpoint: Option<PathBuf>,
+    overwrite_checkpoint: bool,
+    cache: Option<PathBuf>,
+    replay_log: Option<PathBuf>,
+) -> Result<(), String> {
+    let mut tournament = Tournament::with_config(config);
+    if let Some(checkpoint) = checkpoint {
+        let opened = if overwrite_checkpoint {
+            tournament.with_new_checkpoint(checkpoint)
+        } else {
+            tournament.with_checkpoint(checkpoint)
+        };
+        tournament = opened.map_err(|err| match err {
+            CheckpointError::Mismatch(_) => {
+                format!("{err} (pass --overwrite-checkpoint to start it over)")
+            }
+            CheckpointError::Io { .. } => err.to_string(),
+        })?;
+    }
+    if let Some(cache) = cache {
+        tournament = tournament.with_cache(cache);
+    }
+    if let Some(replay_log) = replay_log {
+        tournament = tournament
+            .with_replay_log(replay_log)
+            .map_err(|err| err.to_string())?;
+    }
+    let result = if dashboard {
+        let (sender, receiver) = mpsc::channel();
+        let dashboard = thread::spawn(move || dashboard::show(receiver));
+        let result = tournament.run_with_progress(sender);
+        let _ = dashboard.join();
+        result
+    } else {
+        tournament.run()
+    };
+    print_piston_health();
+    export(&result, options.format, options.output.as_deref())
+}
+
+/// Say so on stderr if a Piston server gave any trouble
+fn print_piston_health() {
+    for server in piston::health() {
+        if server.retries == 0 && server.failures == 0 {
+            continue;
+        }
+        eprintln!(
+            "{}: {} requests, {} retried, {} moves gave up, circuit breaker opened {} times{}",
+            server.url,
+            server.requests,
+            server.retries,
+            server.failures,
+            server.breaker_trips,
+            if server.breaker_open {
+                " (still open)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn play_match(
+    player1: Player,
+    player2: Player,
+    config: TournamentConfig,
+    options: &Options,
+) -> Result<(), String> {
+    let tournament = Tournament::with_config(config.with_players(vec![player1, player2]));
+    let seed = tournament.resolve_seed();
+    let game = tournament.play_match(0, 1, seed);
+    print_piston_health();
+    if let Some(error) = &game.abandoned {
+        return Err(format!(
+            "The game was abandoned in round {}: {}",
+            error.round + 1,
+            error.message
+        ));
+    }
+    let result = TournamentResult {
+        seed,
+        players: vec![
+            tournament.config.players[0].name.clone(),
+            tournament.config.players[1].name.clone(),
+        ],
+        scores: vec![game.scores.0, game.scores.1],
+        matches: vec![game],
+        abandoned: vec![],
+        higher_is_better: tournament.config.higher_is_better(),
+    };
+    match options.format {
+        Format::Text => {
+            print_errors(&result);
+            let game = &result.matches[0];
+            println!(
+                "{}: {} {}",
+                result.players[0],
+                game.scores.0,
+                moves(game.rounds.iter().map(|round| round.0))
+            );
+            println!(
+                "{}: {} {}",
+                result.players[1],
+                game.scores.1,
+                moves(game.rounds.iter().map(|round| round.1))
+            );
+            Ok(())
+        }
+        format => export(&result, format, options.output.as_deref()),
+    }
+}
+
+fn show_game(replay: &Replay, index: usize) -> Result<(), String> {
+    let game = replay
+        .matches
+        .iter()
+        .find(|game| game.index == index)
+        .ok_or(format!("No game {index} in the log"))?;
+    let name = |player: usize| replay.players[player].name.as_str();
+    println!("{} vs {}", name(game.player1), name(game.player2));
+    for (number, round) in game.rounds.iter().enumerate() {
+        let action = |action: bool, intended: Option<bool>| {
+            let action = if action { 'C' } else { 'D' };
+            match intended {
+                // flipped by noise
+                Some(intended) if intended != (action == 'C') => format!("{action}*"),
+                _ => format!("{action} "),
+            }
+        };
+        let storages = round.storages.clone().unwrap_or_default();
+        println!(
+            "{:>5}  {} {}  {:>3} {:>3}  {} {}",
+            number + 1,
+            action(round.actions.0, round.intended.map(|intended| intended.0)),
+            action(round.actions.1, round.intended.map(|intended| intended.1)),
+            round.payoffs.0,
+            round.payoffs.1,
+            storages.0,
+            storages.1
+        );
+    }
+    if !game.is_complete() {
+        println!("(this game came from a checkpoint or the cache, so storage wasn't logged)");
+    }
+    Ok(())
+}
+
+fn show_logs(replay: &Replay, game: Option<usize>, player: Option<&str>) -> Result<(), String> {
+    if let Some(player) = player {
+        if !replay.players.iter().any(|logged| logged.name == player) {
+            return Err(format!("No player {player} in the log"));
+        }
+    }
+    if let Some(index) = game {
+        if !replay.matches.iter().any(|game| game.index == index) {
+            return Err(format!("No game {index} in the log"));
+        }
+    }
+    for logged in &replay.matches {
+        if game.is_some_and(|index| index != logged.index) {
+            continue;
+        }
+        let Some(logs) = &logged.logs else {
+            continue;
+        };
+        let names = (
+            replay.players[logged.player1].name.as_str(),
+            replay.players[logged.player2].name.as_str(),
+        );
+        for (name, log) in [(names.0, &logs.0), (names.1, &logs.1)] {
+            if log.is_empty() || player.is_some_and(|player| player != name) {
+                continue;
+            }
+            println!(
+                "== game {}: {} vs {}, {name}",
+                logged.index, names.0, names.1
+            );
+            for entry in &log.entries {
+                for line in entry.output.lines() {
+                    println!("{:>5}  {line}", entry.round + 1);
+                }
+            }
+            if log.truncated {
+                println!("(truncated)");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify(replay: &Replay, config: &Path) -> Result<(), String> {
+    let config = ConfigFile::load(config, false)?;
+    let verification = replay
+        .verify(&config.players)
+        .map_err(|err| err.to_string())?;
+    for mismatch in &verification.mismatches {
+        let game = replay
+            .matches
+            .iter()
+            .find(|game| game.index == mismatch.index);
+        let opponent = game.map_or("?", |game| {
+            let opponent = if game.player1 == mismatch.player {
+                game.player2
+            } else {
+                game.player1
+            };
+            replay.players[opponent].name.as_str()
+        });
+        println!(
+            "{} against {}, round {}: logged {} {}, got {} {}",
+            replay.players[mismatch.player].name,
+            opponent,
+            mismatch.round + 1,
+            moves([mismatch.logged.0].into_iter()),
+            mismatch.logged.1,
+            moves([mismatch.replayed.0].into_iter()),
+            mismatch.replayed.1
+        );
+    }
+    println!(
+        "{} games verified, {} skipped",
+        verification.verified, verification.skipped
     );
+    let nondeterministic = verification.nondeterministic();
+    if nondeterministic.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "not deterministic: {}",
+        nondeterministic
+            .iter()
+            .map(|&player| replay.players[player].name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+fn replay(
+    log: &Path,
+    game: Option<usize>,
+    config: Option<&Path>,
+    format: Format,
+    output: Option<&Path>,
+) -> Result<(), String> {
+    let replay = Replay::load(log).map_err(|err| err.to_string())?;
+    if let Some(config) = config {
+        return verify(&replay, config);
+    }
+    match game {
+        Some(index) => show_game(&replay, index),
+        None => export(&replay.result(), format, output),
+    }
+}
+
+/// Print a line per player (and per problem), and whether all were fine
+fn print_reports(reports: &[Report], out: &mut impl Write) -> bool {
+    for report in reports {
+        if report.is_ok() {
+            let _ = writeln!(out, "ok    {}", report.player);
+        }
+        for problem in &report.problems {
+            let _ = writeln!(out, "error {}: {}", report.player, problem);
+            let traceback = match problem {
+                Problem::Invalid(error) | Problem::Failed { error, .. } => error
+                    .diagnostic()
+                    .and_then(|diagnostic| diagnostic.traceback.as_deref()),
+                Problem::LoadTimedOut { .. } | Problem::TimedOut { .. } => None,
+            };
+            for line in traceback.into_iter().flat_map(str::lines) {
+                let _ = writeln!(out, "      {line}");
+            }
+        }
+    }
+    reports.iter().all(Report::is_ok)
+}
+
+fn validate(paths: &[PathBuf], smoke: &Smoke) -> Result<bool, String> {
+    // Fail on a bad timeout before loading anything
+    smoke.validator()?;
+    let mut ok = true;
+    for path in paths {
+        let config = if path.is_dir() || path.extension().is_some_and(|ext| ext == "json") {
+            ConfigFile::load(path, false)
+        } else {
+            load_file(path).map(|player| TournamentConfig::new().with_players(vec![player]))
+        };
+        match config {
+            Ok(config) => {
+                let reports = smoke
+                    .validator()?
+                    .with_sandbox(config.sandbox)
+                    .with_isolation(Some(config.isolation.unwrap_or_default()))
+                    .validate(&config.players);
+                ok &= print_reports(&reports, &mut io::stdout().lock())
+            }
+            Err(err) => {
+                ok = false;
+                println!("error {}: {}", path.display(), err);
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn parse_runtime(text: &str) -> Result<(String, Vec<String>), String> {
+    let (language, command) = text.split_once('=').ok_or("expected language=command")?;
+    let command: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+    if command.is_empty() {
+        return Err(format!("no command for {language}"));
+    }
+    Ok((language.to_string(), command))
+}
+
+fn piston_stub(listen: &str, runtimes: Vec<(String, Vec<String>)>) -> Result<(), String> {
+    let mut all = Stub::default_runtimes();
+    all.extend(runtimes);
+    let stub = Stub::start(listen, all).map_err(|err| format!("Could not listen: {err}"))?;
+    println!("Piston stub listening on {}", stub.url());
+    loop {
+        thread::park();
+    }
+}
 
-    let scores = tournament.run();
-    for (i, player) in tournament.config.players.iter().enumerate() {
-        println!("{}: {}", player.name, scores[i]);
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let outcome = match cli.command {
+        Command::Run {
+            config,
+            options,
+            dashboard,
+            checkpoint,
+            overwrite_checkpoint,
+            cache,
+            replay_log,
+            validate,
+            smoke,
+        } => ConfigFile::load(&config, true).and_then(|config| {
+            let config = options.apply(config)?;
+            // stdout might be for the results
+            if validate
+                && !print_reports(
+                    &smoke
+                        .validator()?
+                        .with_sandbox(config.sandbox.clone())
+                        .with_isolation(Some(config.isolation.clone().unwrap_or_default()))
+                        .validate(&config.players),
+                    &mut io::stderr().lock(),
+                )
+            {
+                return Err("Some strategies have problems, not starting".to_string());
+            }
+            run(
+                config,
+                &options,
+                dashboard,
+                checkpoint,
+                overwrite_checkpoint,
+                cache,
+                replay_log,
+            )
+        }),
+        Command::Replay {
+            log,
+            game,
+            verify,
+            format,
+            output,
+        } => replay(&log, game, verify.as_deref(), format, output.as_deref()),
+        Command::Logs { log, game, player } => Replay::load(&log)
+            .map_err(|err| err.to_string())
+            .and_then(|replay| show_logs(&replay, game, player.as_deref())),
+        Command::Match { a, b, options } => load_strategy(&a).and_then(|player1| {
+            let player2 = load_strategy(&b)?;
+            play_match(
+                player1,
+                player2,
+                options.apply(TournamentConfig::new())?,
+                &options,
+            )
+        }),
+        Command::Validate { paths, smoke } => match validate(&paths, &smoke) {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(err) => Err(err),
+        },
+        Command::Worker => ipdt::worker::serve().map_err(|err| err.to_string()),
+        Command::PistonStub { listen, runtimes } => piston_stub(&listen, runtimes),
+        Command::ListBuiltins => {
+            for player in builtins::builtins() {
+                println!("{}", player.name);
+            }
+            Ok(())
+        }
+    };
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,298 @@
+//! Hooks into a running tournament
+//!
+//! Implement `TournamentObserver` for loggers, dashboards or custom
+//! analytics and register it with `Tournament::with_observer`.
+//! If you'd rather receive events on another thread, a
+//! `Sender<ProgressEvent>` is an observer too
+use std::sync::mpsc::Sender;
+
+use serde_json::Value;
+
+use crate::core::{MatchResult, StrategyError, TournamentConfig, TournamentResult};
+
+/// Games can be played in parallel, so observers are called from
+/// several threads at once. Every method does nothing by default
+#[allow(unused_variables)]
+pub trait TournamentObserver: Sync {
+    fn on_tournament_start(&self, config: &TournamentConfig, matches: usize) {}
+    /// `index` is the position of the game in the schedule. Games restored
+    /// from a checkpoint start and end with no rounds in between
+    fn on_match_start(&self, index: usize, player1: usize, player2: usize) {}
+    fn on_round(&self, index: usize, round: &Round) {}
+    /// A move failed (and counts as defecting)
+    fn on_strategy_error(&self, index: usize, error: &StrategyError) {}
+    fn on_match_end(&self, index: usize, result: &MatchResult) {}
+    fn on_tournament_end(&self, result: &TournamentResult) {}
+}
+
+/// A round that was just played
+#[derive(Clone, Debug)]
+pub struct Round {
+    pub player1: usize,
+    pub player2: usize,
+    /// 0-based
+    pub round: usize,
+    /// The (player1, player2) actions the strategies chose
+    pub intended: (bool, bool),
+    /// The (player1, player2) actions, after noise
+    pub actions: (bool, bool),
+    /// The (player1, player2) scores
+    pub payoffs: (i32, i32),
+    /// The (player1, player2) storage after the round
+    pub storages: (Value, Value),
+}
+
+/// The observer events as values, for sending across threads
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    Started {
+        players: Vec<String>,
+        matches: usize,
+        higher_is_better: bool,
+    },
+    MatchStarted {
+        /// Position of the game in the schedule
+        index: usize,
+        player1: usize,
+        player2: usize,
+    },
+    Round {
+        index: usize,
+        round: Round,
+    },
+    MatchFinished {
+        index: usize,
+        player1: usize,
+        player2: usize,
+        scores: (i32, i32),
+    },
+    StrategyError {
+        index: usize,
+        error: StrategyError,
+    },
+    /// Sent instead of `MatchFinished` (see `MatchResult::abandoned`)
+    MatchAbandoned {
+        index: usize,
+        error: StrategyError,
+    },
+    Finished,
+}
+
+// Nobody listening any more is fine, so send errors are ignored
+impl TournamentObserver for Sender<ProgressEvent> {
+    fn on_tournament_start(&self, config: &TournamentConfig, matches: usize) {
+        let _ = self.send(ProgressEvent::Started {
+            players: config
+                .players
+                .iter()
+                .map(|player| player.name.clone())
+                .collect(),
+            matches,
+            higher_is_better: config.higher_is_better(),
+        });
+    }
+    fn on_match_start(&self, index: usize, player1: usize, player2: usize) {
+        let _ = self.send(ProgressEvent::MatchStarted {
+            index,
+            player1,
+            player2,
+        });
+    }
+    fn on_round(&self, index: usize, round: &Round) {
+        let _ = self.send(ProgressEvent::Round {
+            index,
+            round: round.clone(),
+        });
+    }
+    fn on_strategy_error(&self, index: usize, error: &StrategyError) {
+        let _ = self.send(ProgressEvent::StrategyError {
+            index,
+            error: error.clone(),
+        });
+    }
+    fn on_match_end(&self, index: usize, result: &MatchResult) {
+        if let Some(error) = &result.abandoned {
+            let _ = self.send(ProgressEvent::MatchAbandoned {
+                index,
+                error: error.clone(),
+            });
+            return;
+        }
+        let _ = self.send(ProgressEvent::MatchFinished {
+            index,
+            player1: result.player1,
+            player2: result.player2,
+            scores: result.scores,
+        });
+    }
+    fn on_tournament_end(&self, _result: &TournamentResult) {
+        let _ = self.send(ProgressEvent::Finished);
+    }
+}
+
+/// Calls every observer in order
+pub(crate) struct Observers<'a>(pub Vec<&'a dyn TournamentObserver>);
+
+impl TournamentObserver for Observers<'_> {
+    fn on_tournament_start(&self, config: &TournamentConfig, matches: usize) {
+        for observer in &self.0 {
+            observer.on_tournament_start(config, matches);
+        }
+    }
+    fn on_match_start(&self, index: usize, player1: usize, player2: usize) {
+        for observer in &self.0 {
+            observer.on_match_start(index, player1, player2);
+        }
+    }
+    fn on_round(&self, index: usize, round: &Round) {
+        for observer in &self.0 {
+            observer.on_round(index, round);
+        }
+    }
+    fn on_strategy_error(&self, index: usize, error: &StrategyError) {
+        for observer in &self.0 {
+            observer.on_strategy_error(index, error);
+        }
+    }
+    fn on_match_end(&self, index: usize, result: &MatchResult) {
+        for observer in &self.0 {
+            observer.on_match_end(index, result);
+        }
+    }
+    fn on_tournament_end(&self, result: &TournamentResult) {
+        for observer in &self.0 {
+            observer.on_tournament_end(result);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::builtins::builtin;
+    use crate::core::{Executor, Player, Tournament};
+    use crate::testing::scratch;
+
+    #[test]
+    fn every_game_is_reported_in_order() {
+        let players = vec![
+            builtin("tit-for-tat").unwrap(),
+            builtin("always-defect").unwrap(),
+            Player::new(
+                "broken".to_string(),
+                Executor::Lua("return function(history, storage) error('boom') end".to_string()),
+            ),
+        ];
+        let (sender, receiver) = mpsc::channel();
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(3)
+                .with_seed(1)
+                .with_parallelism(4),
+        )
+        .with_observer(sender)
+        .run();
+        let events: Vec<ProgressEvent> = receiver.iter().collect();
+
+        let Some(ProgressEvent::Started {
+            players, matches, ..
+        }) = events.first()
+        else {
+            panic!("{events:?}");
+        };
+        assert_eq!(players.len(), 3);
+        assert_eq!(*matches, result.matches.len());
+        assert!(matches!(events.last(), Some(ProgressEvent::Finished)));
+
+        // Games run in parallel, but each game's events come in order
+        let mut games: BTreeMap<usize, Vec<&ProgressEvent>> = BTreeMap::new();
+        for event in &events[1..events.len() - 1] {
+            let index = match event {
+                ProgressEvent::MatchStarted { index, .. }
+                | ProgressEvent::Round { index, .. }
+                | ProgressEvent::MatchFinished { index, .. }
+                | ProgressEvent::StrategyError { index, .. }
+                | ProgressEvent::MatchAbandoned { index, .. } => *index,
+                other => panic!("{other:?}"),
+            };
+            games.entry(index).or_default().push(event);
+        }
+        assert_eq!(games.len(), result.matches.len());
+        let mut errors = 0;
+        for events in games.values() {
+            let Some(ProgressEvent::MatchStarted {
+                player1, player2, ..
+            }) = events.first()
+            else {
+                panic!("{events:?}");
+            };
+            let mut rounds = 0;
+            let mut total = (0, 0);
+            for event in &events[1..events.len() - 1] {
+                match event {
+                    ProgressEvent::Round { round, .. } => {
+                        assert_eq!(round.round, rounds);
+                        assert_eq!((round.player1, round.player2), (*player1, *player2));
+                        rounds += 1;
+                        total.0 += round.payoffs.0;
+                        total.1 += round.payoffs.1;
+                    }
+                    ProgressEvent::StrategyError { error, .. } => {
+                        assert_eq!(error.player, 2);
+                        errors += 1;
+                    }
+                    other => panic!("{other:?}"),
+                }
+            }
+            assert_eq!(rounds, 3);
+            let Some(ProgressEvent::MatchFinished { scores, .. }) = events.last() else {
+                panic!("{events:?}");
+            };
+            assert_eq!(*scores, total);
+        }
+        // Every move of the broken player, in its 4 games
+        assert_eq!(errors, 4 * 3);
+    }
+
+    #[test]
+    fn restored_games_start_and_end() {
+        let path = scratch("observer.ckpt");
+        let config = || {
+            TournamentConfig::new()
+                .with_players(vec![
+                    builtin("tit-for-tat").unwrap(),
+                    builtin("always-defect").unwrap(),
+                ])
+                .with_rounds(3)
+                .with_seed(1)
+        };
+        let played = Tournament::with_config(config())
+            .with_checkpoint(&path)
+            .unwrap()
+            .run();
+        let (sender, receiver) = mpsc::channel();
+        let resumed = Tournament::with_config(config())
+            .with_checkpoint(&path)
+            .unwrap()
+            .with_observer(sender)
+            .run();
+        assert_eq!(resumed.scores, played.scores);
+        let events: Vec<ProgressEvent> = receiver.iter().collect();
+        let games: Vec<(usize, usize)> = events[1..events.len() - 1]
+            .chunks(2)
+            .map(|pair| match pair {
+                [ProgressEvent::MatchStarted { index, .. }, ProgressEvent::MatchFinished {
+                    index: finished, ..
+                }] => (*index, *finished),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(games, [(0, 0), (1, 1)]);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,883 @@
+//! Running programs on Piston
+//!
+//! Piston strategies run on the public Piston server unless their
+//! `PistonServer` points them at a self-hosted one (with an API key, if it
+//! wants one), and use the latest version of their language unless their
+//! `PistonProgram` pins one.
+//!
+//! Programs read an envelope from stdin,
+//! `{"version": 1, "history": ..., "storage": ..., "context": ...}`
+//! (`context` only when there is one), unless they take the history, storage
+//! and context as JSON in `argv` like before (`PistonInput::Argv`). Long games
+//! can get past the argument length limit that way, and the arguments show
+//! up in process listings.
+//!
+//! Every move is a request to the server and a fresh run of the program
+//! (and maybe a compile). In match mode (`PistonInput::Match`) a program
+//! plays a whole match in one run instead. It reads the envelope without
+//! the history, `{"version": 1, "storage": ..., "context": ...}` (with the
+//! context of the first round), then one line per round,
+//! `{"round": 3, "them": true, "flipped": false}`, with what the opponent
+//! did last round (`null` in the first) and whether noise flipped its own
+//! move. It answers every line with a move on a line of its own, and
+//! everything else it prints is output. Since the run can't wait for the
+//! opponent, the rounds after the current one are a guess, that the
+//! opponent keeps doing what it did last and nothing gets flipped. The moves
+//! are used for as long as the guess holds, after which the program runs
+//! again with the rounds that were actually played, and so on. A program
+//! that decides by the rounds alone then gives the same moves as one run
+//! per move, with a run per wrong guess (one for the whole match against a
+//! strategy that always cooperates)
+//!
+//! A request that fails for reasons that aren't the program's fault (the
+//! server can't be reached, is too busy or breaks) is tried again after a
+//! growing wait, see `PistonLimits`. A move that still fails gives
+//! `ExecutionError::Unavailable`, which abandons the game rather than
+//! counting against the strategy. Servers can also get a concurrency and
+//! rate limit, shared by every player on them, and a circuit breaker that
+//! turns moves away for a while once enough of them in a row gave up.
+//! `health` tells how each server has been doing
+//!
+//! `Stub` is a minimal Piston-compatible server that runs programs with the
+//! interpreters installed locally, so Piston strategies can be tried offline
+//! (`ipdt piston-stub`). It only answers `/runtimes` and `/execute`, compiles
+//! nothing, ignores the limits in the request but `run_timeout` and doesn't
+//! sandbox anything, so only give it programs you trust
+
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::core::{Move, MoveContext};
+use crate::error::ExecutionError;
+
+/// The public Piston server
+pub const PUBLIC_URL: &str = "https://emkc.org/api/v2/piston";
+
+/// The version of the stdin envelope
+pub const ENVELOPE_VERSION: u32 = 1;
+
+/// How many rounds past the current one a program in match mode
+/// plays ahead, when the config doesn't disclose the number of rounds
+pub const MATCH_LOOKAHEAD: usize = 200;
+
+/// How a Piston program gets its input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PistonInput {
+    /// The envelope on stdin
+    #[default]
+    Stdin,
+    /// History, storage and context as `argv[1..]`, for older programs
+    Argv,
+    /// The whole match as lines on stdin, see `MatchSession`
+    Match,
+}
+
+/// What a program reads from stdin before a move
+#[derive(Serialize)]
+pub(crate) struct Envelope<'a> {
+    pub version: u32,
+    pub history: &'a [(bool, bool)],
+    pub storage: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'a MoveContext>,
+}
+
+/// Where to run Piston programs
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PistonServer {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub limits: PistonLimits,
+}
+
+/// How hard to try a Piston server, and how hard to hit it. The
+/// concurrency, rate limit and circuit breaker of a server are shared by
+/// every player on it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PistonLimits {
+    /// How many times to try a request again when the server can't be
+    /// reached, is too busy (429) or fails (5xx)
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling with every retry
+    pub backoff_ms: u64,
+    /// The longest wait between retries
+    pub max_backoff_ms: u64,
+    /// How many requests may be in flight at once
+    pub concurrency: Option<usize>,
+    /// How many requests may start every second
+    pub requests_per_second: Option<u32>,
+    /// How many moves in a row may give up on the server
+    /// before the circuit breaker opens
+    pub breaker_threshold: u32,
+    /// How long an open breaker turns moves away before trying again
+    pub breaker_cooldown_ms: u64,
+}
+
+impl Default for PistonLimits {
+    fn default() -> Self {
+        PistonLimits {
+            retries: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            concurrency: None,
+            requests_per_second: None,
+            breaker_threshold: 5,
+            breaker_cooldown_ms: 30_000,
+        }
+    }
+}
+
+impl PistonLimits {
+    /// The wait before retry number `retry` (0-based)
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.backoff_ms.saturating_mul(1 << retry.min(32));
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+}
+
+impl Default for PistonServer {
+    fn default() -> Self {
+        PistonServer::new(PUBLIC_URL)
+    }
+}
+
+/// Without the API key, since configs get printed
+impl fmt::Debug for PistonServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PistonServer")
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "..."))
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl PistonServer {
+    pub fn new(url: impl Into<String>) -> PistonServer {
+        PistonServer {
+            url: url.into(),
+            api_key: None,
+            limits: PistonLimits::default(),
+        }
+    }
+    pub fn with_api_key(mut self, key: Option<String>) -> PistonServer {
+        self.api_key = key;
+        self
+    }
+    pub fn with_limits(mut self, limits: PistonLimits) -> PistonServer {
+        self.limits = limits;
+        self
+    }
+    fn client(&self) -> piston_rs::Client {
+        let url = self.url.trim_end_matches('/');
+        match &self.api_key {
+            Some(key) => piston_rs::Client::with_url_and_key(url, key),
+            None => piston_rs::Client::with_url(url),
+        }
+    }
+}
+
+/// A program for Piston, see `Executor::Piston`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PistonProgram {
+    pub language: String,
+    /// Like `"3.10.0"`, or `"*"` for the latest
+    pub version: String,
+    pub program: String,
+    pub server: PistonServer,
+    #[serde(default)]
+    pub input: PistonInput,
+}
+
+impl PistonProgram {
+    /// The latest version of `language`, on the public server
+    pub fn new(language: impl Into<String>, program: impl Into<String>) -> PistonProgram {
+        PistonProgram {
+            language: language.into(),
+            version: "*".to_string(),
+            program: program.into(),
+            server: PistonServer::default(),
+            input: PistonInput::default(),
+        }
+    }
+    pub fn with_version(mut self, version: impl Into<String>) -> PistonProgram {
+        self.version = version.into();
+        self
+    }
+    pub fn with_server(mut self, server: PistonServer) -> PistonProgram {
+        self.server = server;
+        self
+    }
+    pub fn with_input(mut self, input: PistonInput) -> PistonProgram {
+        self.input = input;
+        self
+    }
+    /// The language, and the version if it's pinned (`python@3.10.0`)
+    pub fn runtime(&self) -> String {
+        if self.version == "*" {
+            self.language.clone()
+        } else {
+            format!("{}@{}", self.language, self.version)
+        }
+    }
+}
+
+/// Make a move on the program's server. See `Executor::run_logged`
+///
+/// A program in match mode gets its storage and context as the start of
+/// its match, and is asked for its move in the last round of `history`
+/// only, so it's only right for programs that don't care. Players keep a
+/// `MatchSession` instead
+pub(crate) fn run(
+    piston: &PistonProgram,
+    history: &[(bool, bool)],
+    storage: Value,
+    context: Option<&MoveContext>,
+    output: &mut String,
+) -> Result<Move, ExecutionError> {
+    match piston.input {
+        PistonInput::Stdin => {
+            let stdin = json(&Envelope {
+                version: ENVELOPE_VERSION,
+                history,
+                storage: &storage,
+                context,
+            })?;
+            last_move(&execute(piston, Some(stdin), vec![], output)?, output)
+        }
+        PistonInput::Argv => {
+            let mut args = vec![json(history)?, json(&storage)?];
+            if let Some(context) = context {
+                args.push(json(context)?);
+            }
+            last_move(&execute(piston, None, args, output)?, output)
+        }
+        PistonInput::Match => {
+            MatchSession::new(&storage, context)?.resolve(piston, history, 0, output)
+        }
+    }
+}
+
+fn json(value: &(impl Serialize + ?Sized)) -> Result<String, ExecutionError> {
+    serde_json::to_string(value)
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))
+}
+
+/// The move is the last line, anything printed before it is output
+fn last_move(stdout: &str, output: &mut String) -> Result<Move, ExecutionError> {
+    let stdout = stdout.trim_end();
+    let (printed, line) = stdout.rsplit_once('\n').unwrap_or(("", stdout));
+    if !printed.is_empty() {
+        output.push_str(printed);
+        output.push('\n');
+    }
+    serde_json::from_str(line).map_err(|err| ExecutionError::DeserializationError(err.to_string()))
+}
+
+/// Run the program once, returning its stdout. The compiler output and
+/// stderr go to `output`. Requests that fail for reasons that aren't the
+/// program's fault are tried again, and in the end give
+/// `ExecutionError::Unavailable`
+fn execute(
+    piston: &PistonProgram,
+    stdin: Option<String>,
+    args: Vec<String>,
+    output: &mut String,
+) -> Result<String, ExecutionError> {
+    let mut executor = piston_rs::Executor::new()
+        .set_language(&piston.language)
+        .set_version(&piston.version)
+        .add_file(piston_rs::File::default().set_content(&piston.program))
+        .add_args(args.iter().map(String::as_str).collect());
+    if let Some(stdin) = &stdin {
+        executor = executor.set_stdin(stdin);
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| {
+            ExecutionError::InitializationError(format!("Could not start tokio: {err}"))
+        })?;
+    let client = piston.server.client();
+    let limits = &piston.server.limits;
+    let gate = gate(&piston.server.url);
+
+    let mut retry = 0;
+    loop {
+        gate.enter(limits)?;
+        let failure = match runtime.block_on(client.execute(&executor)) {
+            Ok(response) if response.is_ok() => {
+                gate.leave(limits, Attempt::Answered);
+                if let Some(compile) = response.compile {
+                    output.push_str(&compile.output);
+                }
+                output.push_str(&response.run.stderr);
+                return Ok(response.run.stdout);
+            }
+            // Too busy or broken, which might pass
+            Ok(response) if response.status == 429 || response.status >= 500 => {
+                response.run.stderr.trim().to_string()
+            }
+            // A wrong key won't get any better
+            Ok(response) if matches!(response.status, 401 | 403) => {
+                gate.leave(limits, Attempt::GaveUp);
+                return Err(ExecutionError::Unavailable(
+                    response.run.stderr.trim().to_string(),
+                ));
+            }
+            // An unknown language or version, ...
+            Ok(response) => {
+                gate.leave(limits, Attempt::Answered);
+                return Err(ExecutionError::RuntimeError(
+                    format!("Piston refused: {}", response.run.stderr.trim()).into(),
+                ));
+            }
+            Err(e) => e.to_string(),
+        };
+        if retry >= limits.retries {
+            gate.leave(limits, Attempt::GaveUp);
+            return Err(ExecutionError::Unavailable(format!(
+                "{failure} (tried {} times)",
+                retry + 1
+            )));
+        }
+        gate.leave(limits, Attempt::Retrying);
+        thread::sleep(limits.backoff(retry));
+        retry += 1;
+    }
+}
+
+/// How a Piston server has been doing, see `health`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ServerHealth {
+    pub url: String,
+    pub requests: u64,
+    /// Requests that failed and were tried again
+    pub retries: u64,
+    /// Moves that gave up on the server (or were turned away by the breaker)
+    pub failures: u64,
+    /// How often the circuit breaker opened
+    pub breaker_trips: u64,
+    pub breaker_open: bool,
+}
+
+/// How every Piston server used so far has been doing
+pub fn health() -> Vec<ServerHealth> {
+    let gates = GATES.lock().unwrap_or_else(PoisonError::into_inner);
+    gates
+        .values()
+        .map(|gate| {
+            let state = gate.state.lock().unwrap_or_else(PoisonError::into_inner);
+            ServerHealth {
+                breaker_open: state.open_until.is_some_and(|until| until > Instant::now()),
+                ..state.health.clone()
+            }
+        })
+        .collect()
+}
+
+/// The Piston servers in use, by URL
+static GATES: Mutex<BTreeMap<String, Arc<Gate>>> = Mutex::new(BTreeMap::new());
+
+fn gate(url: &str) -> Arc<Gate> {
+    let mut gates = GATES.lock().unwrap_or_else(PoisonError::into_inner);
+    gates
+        .entry(url.to_string())
+        .or_insert_with(|| {
+            Arc::new(Gate {
+                state: Mutex::new(GateState {
+                    health: ServerHealth {
+                        url: url.to_string(),
+                        ..ServerHealth::default()
+                    },
+                    ..GateState::default()
+                }),
+                freed: Condvar::new(),
+            })
+        })
+        .clone()
+}
+
+/// The concurrency and rate limit and the circuit breaker of a server
+struct Gate {
+    state: Mutex<GateState>,
+    /// Signalled whenever a request finishes
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    running: usize,
+    /// When the next request may start, with a rate limit
+    next_start: Option<Instant>,
+    /// Moves that gave up in a row
+    failures: u32,
+    open_until: Option<Instant>,
+    health: ServerHealth,
+}
+
+enum Attempt {
+    /// The server answered, even if it was to refuse the program
+    Answered,
+    Retrying,
+    GaveUp,
+}
+
+impl Gate {
+    /// Wait for a turn to send a request, unless the breaker is open
+    fn enter(&self, limits: &PistonLimits) -> Result<(), ExecutionError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if state.open_until.is_some_and(|until| until > Instant::now()) {
+                state.health.failures += 1;
+                return Err(ExecutionError::Unavailable(format!(
+                    "{} failed too often, trying again later",
+                    state.health.url
+                )));
+            }
+            if limits
+                .concurrency
+                .is_none_or(|concurrency| state.running < concurrency.max(1))
+            {
+                break;
+            }
+            state = self
+                .freed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        state.running += 1;
+        state.health.requests += 1;
+        let start = limits.requests_per_second.map(|rate| {
+            let now = Instant::now();
+            let start = state.next_start.map_or(now, |next| next.max(now));
+            state.next_start = Some(start + Duration::from_secs(1) / rate.max(1));
+            start
+        });
+        drop(state);
+        if let Some(start) = start {
+            thread::sleep(start.saturating_duration_since(Instant::now()));
+        }
+        Ok(())
+    }
+    fn leave(&self, limits: &PistonLimits, attempt: Attempt) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.running = state.running.saturating_sub(1);
+        match attempt {
+            Attempt::Answered => state.failures = 0,
+            Attempt::Retrying => state.health.retries += 1,
+            Attempt::GaveUp => {
+                state.failures += 1;
+                state.health.failures += 1;
+                // Reopens right away if the first move after the cooldown gives up too
+                if state.failures >= limits.breaker_threshold.max(1) {
+                    state.open_until =
+                        Some(Instant::now() + Duration::from_millis(limits.breaker_cooldown_ms));
+                    state.health.breaker_trips += 1;
+                }
+            }
+        }
+        drop(state);
+        self.freed.notify_all();
+    }
+}
+
+#[derive(Serialize)]
+struct MatchHeader<'a> {
+    version: u32,
+    storage: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<&'a MoveContext>,
+}
+
+/// The start of a round, for a program in match mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+struct RoundLine {
+    round: usize,
+    /// What the opponent did last round
+    them: Option<bool>,
+    /// Whether noise flipped your move last round
+    flipped: bool,
+}
+
+/// A match of a program in match mode: the moves it made ahead of time
+/// and the rounds they assumed
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MatchSession {
+    header: String,
+    /// What the program did every round so far, before noise
+    played: Vec<bool>,
+    /// The rounds the program was last given, and its moves for them
+    rounds: Vec<RoundLine>,
+    moves: Vec<Move>,
+}
+
+impl MatchSession {
+    /// A match starting with `storage` and `context`
+    pub(crate) fn new(
+        storage: &Value,
+        context: Option<&MoveContext>,
+    ) -> Result<MatchSession, ExecutionError> {
+        // The program always starts from the first round
+        let context = context.cloned().map(|mut context| {
+            context.round = 0;
+            context.scores = (0, 0);
+            context
+        });
+        Ok(MatchSession {
+            header: json(&MatchHeader {
+                version: ENVELOPE_VERSION,
+                storage,
+                context: context.as_ref(),
+            })?,
+            ..MatchSession::default()
+        })
+    }
+    /// The move for the round after `history`. Runs the program again only
+    /// when the rounds it was given last time turned out differently
+    pub(crate) fn next(
+        &mut self,
+        piston: &PistonProgram,
+        history: &[(bool, bool)],
+        context: Option<&MoveContext>,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        let round = history.len();
+        let lookahead = context
+            .and_then(|context| context.rounds)
+            .map(|rounds| (rounds.max(0) as usize).saturating_sub(round + 1))
+            .unwrap_or(MATCH_LOOKAHEAD);
+        let result = self.resolve(piston, history, lookahead, output);
+        self.played.truncate(round);
+        self.played
+            .push(result.as_ref().is_ok_and(|decision| decision.action));
+        result
+    }
+    fn resolve(
+        &mut self,
+        piston: &PistonProgram,
+        history: &[(bool, bool)],
+        lookahead: usize,
+        output: &mut String,
+    ) -> Result<Move, ExecutionError> {
+        let round = history.len();
+        let mut rounds = vec![RoundLine {
+            round: 0,
+            them: None,
+            flipped: false,
+        }];
+        rounds.extend(
+            history
+                .iter()
+                .enumerate()
+                .map(|(index, &(you, them))| RoundLine {
+                    round: index + 1,
+                    them: Some(them),
+                    flipped: self.played.get(index).is_some_and(|&played| played != you),
+                }),
+        );
+        if self.rounds.get(..=round) != Some(&rounds[..]) || self.moves.len() <= round {
+            // Guess that the opponent keeps doing what it did last
+            let guess = history.last().is_none_or(|&(_, them)| them);
+            rounds.extend((round + 1..=round + lookahead).map(|round| RoundLine {
+                round,
+                them: Some(guess),
+                flipped: false,
+            }));
+            let mut stdin = self.header.clone();
+            for line in &rounds {
+                stdin.push('\n');
+                stdin.push_str(&json(line)?);
+            }
+            stdin.push('\n');
+            let stdout = execute(piston, Some(stdin), vec![], output)?;
+            // Every line that isn't a move is output
+            self.moves.clear();
+            for line in stdout.lines() {
+                match serde_json::from_str::<Move>(line) {
+                    Ok(decision) => self.moves.push(decision),
+                    Err(_) => {
+                        output.push_str(line);
+                        output.push('\n');
+                    }
+                }
+            }
+            self.rounds = rounds;
+        }
+        self.moves.get(round).cloned().ok_or_else(|| {
+            ExecutionError::DeserializationError(format!(
+                "The program made {} moves, but this is round {}",
+                self.moves.len(),
+                round + 1
+            ))
+        })
+    }
+}
+
+/// A local stand-in for a Piston server
+pub struct Stub {
+    address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct StubRequest {
+    language: String,
+    #[serde(default)]
+    version: String,
+    files: Vec<StubFile>,
+    #[serde(default)]
+    stdin: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Milliseconds, `-1` (or nothing) for `STUB_RUN_TIMEOUT`
+    run_timeout: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct StubFile {
+    name: Option<String>,
+    content: String,
+}
+
+/// How long a program may run unless the request says otherwise,
+/// the default of Piston
+const STUB_RUN_TIMEOUT: Duration = Duration::from_millis(3000);
+
+/// The largest request body the stub reads, well past any program and
+/// envelope it's meant for
+const STUB_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Files of programs being run get unique directories
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+impl Stub {
+    /// The usual interpreters of Python, JavaScript, Lua, Ruby and Bash
+    pub fn default_runtimes() -> BTreeMap<String, Vec<String>> {
+        [
+            ("python", "python3"),
+            ("javascript", "node"),
+            ("lua", "lua"),
+            ("ruby", "ruby"),
+            ("bash", "bash"),
+        ]
+        .into_iter()
+        .map(|(language, command)| (language.to_string(), vec![command.to_string()]))
+        .collect()
+    }
+    /// Start answering on `address` on a thread of its own. `runtimes` are
+    /// the commands running each language, which get the program's path
+    /// and arguments appended
+    pub fn start(
+        address: impl ToSocketAddrs,
+        runtimes: BTreeMap<String, Vec<String>>,
+    ) -> io::Result<Stub> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let runtimes = Arc::new(runtimes);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let runtimes = runtimes.clone();
+                thread::spawn(move || {
+                    let _ = answer(stream, &runtimes);
+                });
+            }
+        });
+        Ok(Stub { address })
+    }
+    /// For `PistonServer::new`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+/// One request per connection is all `piston_rs` needs
+fn answer(stream: TcpStream, runtimes: &BTreeMap<String, Vec<String>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    // Read no more than the client sent, and not whatever it claims
+    let mut body = vec![];
+    if length <= STUB_BODY_LIMIT {
+        reader.take(length as u64).read_to_end(&mut body)?;
+    }
+
+    let path = path.trim_end_matches('/');
+    let (status, response) = if length > STUB_BODY_LIMIT {
+        let message = format!("The body is over {STUB_BODY_LIMIT} bytes");
+        (413, json!({ "message": message }))
+    } else if method == "GET" && path.ends_with("/runtimes") {
+        let runtimes: Vec<Value> = runtimes
+            .keys()
+            .map(|language| json!({ "language": language, "version": "local", "aliases": [] }))
+            .collect();
+        (200, Value::from(runtimes))
+    } else if method == "POST" && path.ends_with("/execute") {
+        match serde_json::from_slice::<StubRequest>(&body) {
+            Ok(request) => execute_locally(&request, runtimes),
+            Err(err) => (400, json!({ "message": err.to_string() })),
+        }
+    } else {
+        (404, json!({ "message": "Not found" }))
+    };
+    let response = response.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{response}",
+        response.len()
+    )?;
+    stream.flush()
+}
+
+fn execute_locally(
+    request: &StubRequest,
+    runtimes: &BTreeMap<String, Vec<String>>,
+) -> (u16, Value) {
+    let Some((program, arguments)) = runtimes
+        .get(&request.language)
+        .and_then(|command| command.split_first())
+    else {
+        let message = format!(
+            "{}-{} runtime is unknown",
+            request.language, request.version
+        );
+        return (400, json!({ "message": message }));
+    };
+    let directory = std::env::temp_dir().join(format!(
+        "ipdt-piston-{}-{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = run_locally(request, program, arguments, &directory);
+    let _ = fs::remove_dir_all(&directory);
+    match result {
+        Ok(run) => (
+            200,
+            json!({ "language": request.language, "version": "local", "run": run }),
+        ),
+        Err(err) => (500, json!({ "message": err.to_string() })),
+    }
+}
+
+fn run_locally(
+    request: &StubRequest,
+    program: &str,
+    arguments: &[String],
+    directory: &Path,
+) -> io::Result<Value> {
+    fs::create_dir_all(directory)?;
+    let mut main = None;
+    for (index, file) in request.files.iter().enumerate() {
+        // Only the file name, so nothing lands outside the directory
+        let name = file
+            .name
+            .as_deref()
+            .and_then(|name| Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format!("file{index}"));
+        let path = directory.join(name);
+        fs::write(&path, &file.content)?;
+        main.get_or_insert(path);
+    }
+    let main = main.ok_or_else(|| io::Error::other("no files"))?;
+    let mut child = Command::new(program)
+        .args(arguments)
+        .arg(&main)
+        .args(&request.args)
+        .current_dir(directory)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // A program may print a pipe's worth before reading all its input,
+    // so writing and reading all happen on threads of their own
+    let input = request.stdin.clone();
+    let stdin = child.stdin.take();
+    let writer = thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let (stdout, stderr) = (drain(child.stdout.take()), drain(child.stderr.take()));
+    let timeout = request
+        .run_timeout
+        .and_then(|milliseconds| u64::try_from(milliseconds).ok())
+        .map_or(STUB_RUN_TIMEOUT, Duration::from_millis);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let _ = writer.join();
+    let stdout = String::from_utf8_lossy(&stdout.join().unwrap_or_default()).into_owned();
+    let stderr = String::from_utf8_lossy(&stderr.join().unwrap_or_default()).into_owned();
+    #[cfg(unix)]
+    let signal =
+        std::os::unix::process::ExitStatusExt::signal(&status).map(|signal| format!("SIG{signal}"));
+    #[cfg(not(unix))]
+    let signal: Option<String> = None;
+    Ok(json!({
+        "output": format!("{stdout}{stderr}"),
+        "stdout": stdout,
+        "stderr": stderr,
+        "code": status.code(),
+        "signal": signal,
+    }))
+}
+
+/// Read all of `pipe` on a thread of its own
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
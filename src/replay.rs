@@ -0,0 +1,714 @@
+//! Recording every move of a tournament and playing it back later
+//!
+//! A replay log is a JSON lines file. The first line describes the
+//! tournament (seed, scores, players and their fingerprints), every other
+//! line is a finished game: the moves of both players as `C`/`D` strings,
+//! the moves they chose before noise, their storage after every round and
+//! whatever they printed.
+//!
+//! `Replay::load` gives back the standings and the state of every round
+//! without running any strategy. `Replay::verify` does run them again,
+//! along the logged history, to catch strategies that don't always make
+//! the same move in the same situation.
+//!
+//! Games that came from a checkpoint or the cache were not played, so
+//! only their moves are logged and they can't be verified
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::core::{
+    strategy_seeds, MatchResult, Player, StrategyError, TournamentConfig, TournamentResult,
+};
+use crate::debug_log::DebugLog;
+use crate::error::ReplayError;
+use crate::observer::{Round, TournamentObserver};
+use crate::sandbox::Sandbox;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    seed: u64,
+    rounds: i32,
+    noise: f64,
+    mutual_win_score: i32,
+    mutual_loss_score: i32,
+    win_score: i32,
+    loss_score: i32,
+    #[serde(default)]
+    disclose_rounds: bool,
+    #[serde(default)]
+    anonymize_opponents: bool,
+    #[serde(default)]
+    career_storage: bool,
+    /// Missing from logs written before there was a sandbox
+    #[serde(default)]
+    sandbox: Sandbox,
+    players: Vec<LoggedPlayer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoggedPlayer {
+    pub name: String,
+    /// See `Executor::fingerprint`
+    pub fingerprint: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    index: usize,
+    player1: usize,
+    player2: usize,
+    /// The moves after noise, one string per player
+    moves: (String, String),
+    /// The moves before noise, if the game was played
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intended: Option<(String, String)>,
+    /// The storage after every round, if the game was played
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    storages: Option<Vec<(Value, Value)>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<StrategyError>,
+    /// The career storage after the game, with career storage on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    careers: Option<(Value, Value)>,
+    /// What the strategies printed, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logs: Option<(DebugLog, DebugLog)>,
+}
+
+fn encode(actions: impl Iterator<Item = bool>) -> String {
+    actions
+        .map(|action| if action { 'C' } else { 'D' })
+        .collect()
+}
+
+fn decode(moves: &str) -> Result<Vec<bool>, String> {
+    moves
+        .chars()
+        .map(|action| match action {
+            'C' => Ok(true),
+            'D' => Ok(false),
+            other => Err(format!("unknown move {other:?}")),
+        })
+        .collect()
+}
+
+/// An observer writing a replay log as the tournament runs.
+/// `Tournament::with_replay_log` sets one up for you
+pub struct ReplayRecorder {
+    /// The rounds of the games being played right now, by schedule index
+    playing: Mutex<HashMap<usize, Vec<Round>>>,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl ReplayRecorder {
+    pub fn create(
+        path: &Path,
+        config: &TournamentConfig,
+        seed: u64,
+    ) -> std::io::Result<ReplayRecorder> {
+        ReplayRecorder::new(File::create(path)?, config, seed)
+    }
+    /// Like `create`, writing to a file that's already open
+    pub fn new(
+        file: File,
+        config: &TournamentConfig,
+        seed: u64,
+    ) -> std::io::Result<ReplayRecorder> {
+        let mut file = BufWriter::new(file);
+        let header = Header {
+            version: VERSION,
+            seed,
+            rounds: config.rounds,
+            noise: config.noise,
+            mutual_win_score: config.mutual_win_score,
+            mutual_loss_score: config.mutual_loss_score,
+            win_score: config.win_score,
+            loss_score: config.loss_score,
+            disclose_rounds: config.disclose_rounds,
+            anonymize_opponents: config.anonymize_opponents,
+            career_storage: config.career_storage,
+            sandbox: config.sandbox.clone(),
+            players: config
+                .players
+                .iter()
+                .map(|player| LoggedPlayer {
+                    name: player.name.clone(),
+                    fingerprint: player.executor.fingerprint(),
+                })
+                .collect(),
+        };
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.flush()?;
+        Ok(ReplayRecorder {
+            playing: Mutex::new(HashMap::new()),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl TournamentObserver for ReplayRecorder {
+    fn on_match_start(&self, index: usize, _player1: usize, _player2: usize) {
+        if let Ok(mut playing) = self.playing.lock() {
+            playing.insert(index, vec![]);
+        }
+    }
+    fn on_round(&self, index: usize, round: &Round) {
+        if let Ok(mut playing) = self.playing.lock() {
+            if let Some(rounds) = playing.get_mut(&index) {
+                rounds.push(round.clone());
+            }
+        }
+    }
+    fn on_match_end(&self, index: usize, result: &MatchResult) {
+        let rounds = self
+            .playing
+            .lock()
+            .ok()
+            .and_then(|mut playing| playing.remove(&index))
+            .filter(|rounds| !rounds.is_empty());
+        // Only the games that count are logged
+        if result.abandoned.is_some() {
+            return;
+        }
+        let entry = Entry {
+            index,
+            player1: result.player1,
+            player2: result.player2,
+            moves: (
+                encode(result.rounds.iter().map(|round| round.0)),
+                encode(result.rounds.iter().map(|round| round.1)),
+            ),
+            intended: rounds.as_ref().map(|rounds| {
+                (
+                    encode(rounds.iter().map(|round| round.intended.0)),
+                    encode(rounds.iter().map(|round| round.intended.1)),
+                )
+            }),
+            storages: rounds.map(|rounds| rounds.into_iter().map(|round| round.storages).collect()),
+            errors: result.errors.clone(),
+            careers: result.careers.clone(),
+            logs: result.logs.clone(),
+        };
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        if let Ok(mut file) = self.file.lock() {
+            let _ = writeln!(file, "{line}").and_then(|_| file.flush());
+        }
+    }
+}
+
+/// The state of one round, as logged
+#[derive(Clone, Debug)]
+pub struct RoundState {
+    /// The (player1, player2) actions, after noise
+    pub actions: (bool, bool),
+    /// The (player1, player2) actions the strategies chose,
+    /// if the game was played during the logged run
+    pub intended: Option<(bool, bool)>,
+    /// The (player1, player2) scores
+    pub payoffs: (i32, i32),
+    /// The (player1, player2) storage after the round,
+    /// if the game was played during the logged run
+    pub storages: Option<(Value, Value)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplayMatch {
+    /// Position of the game in the schedule
+    pub index: usize,
+    /// Indices into `Replay::players`
+    pub player1: usize,
+    pub player2: usize,
+    pub rounds: Vec<RoundState>,
+    pub errors: Vec<StrategyError>,
+    /// The (player1, player2) career storage after the game,
+    /// with career storage on
+    pub careers: Option<(Value, Value)>,
+    /// What the (player1, player2) strategies printed, if anything
+    pub logs: Option<(DebugLog, DebugLog)>,
+}
+
+impl ReplayMatch {
+    /// The game as if it had just been played
+    pub fn result(&self) -> MatchResult {
+        let payoffs: Vec<(i32, i32)> = self.rounds.iter().map(|round| round.payoffs).collect();
+        MatchResult {
+            player1: self.player1,
+            player2: self.player2,
+            rounds: self.rounds.iter().map(|round| round.actions).collect(),
+            scores: payoffs.iter().fold((0, 0), |total, payoff| {
+                (total.0 + payoff.0, total.1 + payoff.1)
+            }),
+            payoffs,
+            errors: self.errors.clone(),
+            careers: self.careers.clone(),
+            logs: self.logs.clone(),
+            abandoned: None,
+        }
+    }
+    /// Whether the choices and storage of every round were logged
+    pub fn is_complete(&self) -> bool {
+        self.rounds
+            .iter()
+            .all(|round| round.intended.is_some() && round.storages.is_some())
+    }
+}
+
+/// A loaded replay log
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub seed: u64,
+    pub players: Vec<LoggedPlayer>,
+    /// The settings of the logged run, without any players
+    pub config: TournamentConfig,
+    /// Every logged game, in schedule order
+    pub matches: Vec<ReplayMatch>,
+}
+
+/// A round where a strategy didn't do what the log says it did
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Position of the game in the schedule
+    pub index: usize,
+    /// Index into `Replay::players`
+    pub player: usize,
+    /// 0-based
+    pub round: usize,
+    /// The logged (move, storage)
+    pub logged: (bool, Value),
+    /// The (move, storage) when run again
+    pub replayed: (bool, Value),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    /// Games that were run again
+    pub verified: usize,
+    /// Games that couldn't be, see `ReplayMatch::is_complete`
+    pub skipped: usize,
+    /// The first mismatch of each player in each game
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Verification {
+    /// The players that didn't reproduce their logged moves
+    pub fn nondeterministic(&self) -> BTreeSet<usize> {
+        self.mismatches
+            .iter()
+            .map(|mismatch| mismatch.player)
+            .collect()
+    }
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Replay, ReplayError> {
+        let text = fs::read_to_string(path).map_err(|source| ReplayError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let invalid = |line: usize, message: String| ReplayError::Invalid {
+            path: path.to_path_buf(),
+            line: line + 1,
+            message,
+        };
+        let mut lines = text.lines().enumerate();
+        let header: Header = lines
+            .next()
+            .ok_or_else(|| invalid(0, "the file is empty".to_string()))
+            .and_then(|(number, line)| {
+                serde_json::from_str(line).map_err(|err| invalid(number, err.to_string()))
+            })?;
+        if header.version != VERSION {
+            return Err(invalid(
+                0,
+                format!("unsupported version {}", header.version),
+            ));
+        }
+        let config = TournamentConfig::new()
+            .with_rounds(header.rounds)
+            .with_noise(header.noise)
+            .with_seed(header.seed)
+            .with_mutual_win_score(header.mutual_win_score)
+            .with_mutual_loss_score(header.mutual_loss_score)
+            .with_win_score(header.win_score)
+            .with_loss_score(header.loss_score)
+            .with_disclosed_rounds(header.disclose_rounds)
+            .with_anonymous_opponents(header.anonymize_opponents)
+            .with_career_storage(header.career_storage)
+            .with_sandbox(header.sandbox);
+
+        let mut matches = vec![];
+        for (number, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry =
+                serde_json::from_str(line).map_err(|err| invalid(number, err.to_string()))?;
+            let game = Replay::decode_entry(entry, &config, header.players.len())
+                .map_err(|message| invalid(number, message))?;
+            matches.push(game);
+        }
+        matches.sort_by_key(|game| game.index);
+        Ok(Replay {
+            seed: header.seed,
+            players: header.players,
+            config,
+            matches,
+        })
+    }
+    fn decode_entry(
+        entry: Entry,
+        config: &TournamentConfig,
+        players: usize,
+    ) -> Result<ReplayMatch, String> {
+        if entry.player1 >= players || entry.player2 >= players {
+            return Err("unknown player".to_string());
+        }
+        let moves = (decode(&entry.moves.0)?, decode(&entry.moves.1)?);
+        let intended = match &entry.intended {
+            Some((player1, player2)) => Some((decode(player1)?, decode(player2)?)),
+            None => None,
+        };
+        let length = moves.0.len();
+        if moves.1.len() != length
+            || intended
+                .as_ref()
+                .is_some_and(|intended| intended.0.len() != length || intended.1.len() != length)
+            || entry
+                .storages
+                .as_ref()
+                .is_some_and(|storages| storages.len() != length)
+        {
+            return Err("the players have a different number of rounds".to_string());
+        }
+        let rounds = (0..length)
+            .map(|round| {
+                let actions = (moves.0[round], moves.1[round]);
+                RoundState {
+                    actions,
+                    intended: intended
+                        .as_ref()
+                        .map(|intended| (intended.0[round], intended.1[round])),
+                    payoffs: (
+                        config.score(actions.0, actions.1),
+                        config.score(actions.1, actions.0),
+                    ),
+                    storages: entry
+                        .storages
+                        .as_ref()
+                        .map(|storages| storages[round].clone()),
+                }
+            })
+            .collect();
+        Ok(ReplayMatch {
+            index: entry.index,
+            player1: entry.player1,
+            player2: entry.player2,
+            rounds,
+            errors: entry.errors,
+            careers: entry.careers,
+            logs: entry.logs,
+        })
+    }
+    /// The standings and games of the logged run
+    pub fn result(&self) -> TournamentResult {
+        let mut scores = vec![0; self.players.len()];
+        let matches: Vec<MatchResult> = self.matches.iter().map(ReplayMatch::result).collect();
+        for result in &matches {
+            scores[result.player1] += result.scores.0;
+            scores[result.player2] += result.scores.1;
+        }
+        TournamentResult {
+            seed: self.seed,
+            players: self
+                .players
+                .iter()
+                .map(|player| player.name.clone())
+                .collect(),
+            scores,
+            matches,
+            abandoned: vec![],
+            higher_is_better: self.config.higher_is_better(),
+        }
+    }
+    /// Run the strategies again along the logged history and compare their
+    /// moves and storage with the log. `players` are matched to the logged
+    /// ones by fingerprint, so names and order don't matter
+    pub fn verify(&self, players: &[Player]) -> Result<Verification, ReplayError> {
+        let players = self
+            .players
+            .iter()
+            .map(|logged| {
+                players
+                    .iter()
+                    .find(|player| player.executor.fingerprint() == logged.fingerprint)
+                    .ok_or_else(|| ReplayError::MissingPlayer(logged.name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut verification = Verification::default();
+        // Every game starts from the careers the log says
+        // the games before it left behind
+        let mut careers: Vec<Value> = players
+            .iter()
+            .map(|player| player.career().clone())
+            .collect();
+        for game in &self.matches {
+            let starting = (careers[game.player1].clone(), careers[game.player2].clone());
+            if let Some((career1, career2)) = &game.careers {
+                careers[game.player1] = career1.clone();
+                careers[game.player2] = career2.clone();
+            }
+            if !game.is_complete() {
+                verification.skipped += 1;
+                continue;
+            }
+            verification.verified += 1;
+            let seeds = strategy_seeds(
+                self.seed,
+                &players[game.player1].executor,
+                &players[game.player2].executor,
+            );
+            let mut states = (
+                Some(
+                    players[game.player1]
+                        .clone()
+                        .with_seed(seeds.0)
+                        .with_career(starting.0)
+                        .with_sandbox(self.config.sandbox.clone()),
+                ),
+                Some(
+                    players[game.player2]
+                        .clone()
+                        .with_seed(seeds.1)
+                        .with_career(starting.1)
+                        .with_sandbox(self.config.sandbox.clone()),
+                ),
+            );
+            let mut history = (vec![], vec![]);
+            let mut scores = (0, 0);
+            let opponent = |player: usize| {
+                let logged = &self.players[player];
+                (logged.name.as_str(), logged.fingerprint.as_str())
+            };
+            for (number, round) in game.rounds.iter().enumerate() {
+                let (Some(intended), Some(storages)) = (round.intended, &round.storages) else {
+                    break;
+                };
+                for (player, state, history, context, logged) in [
+                    (
+                        game.player1,
+                        &mut states.0,
+                        &history.0,
+                        self.config
+                            .move_context(self.seed, number, opponent(game.player2), scores),
+                        (intended.0, &storages.0),
+                    ),
+                    (
+                        game.player2,
+                        &mut states.1,
+                        &history.1,
+                        self.config.move_context(
+                            self.seed,
+                            number,
+                            opponent(game.player1),
+                            (scores.1, scores.0),
+                        ),
+                        (intended.1, &storages.1),
+                    ),
+                ] {
+                    // Once a player goes off script there's no point going on
+                    let Some(player_state) = state else {
+                        continue;
+                    };
+                    let mut context = context;
+                    if self.config.career_storage {
+                        context.career = Some(player_state.career().clone());
+                    }
+                    let action = player_state.run(history, Some(&context)).unwrap_or(false);
+                    if action != logged.0 || player_state.storage() != logged.1 {
+                        verification.mismatches.push(Mismatch {
+                            index: game.index,
+                            player,
+                            round: number,
+                            logged: (logged.0, logged.1.clone()),
+                            replayed: (action, player_state.storage().clone()),
+                        });
+                        *state = None;
+                    }
+                }
+                history.0.push(round.actions);
+                history.1.push((round.actions.1, round.actions.0));
+                scores.0 += round.payoffs.0;
+                scores.1 += round.payoffs.1;
+            }
+        }
+        Ok(verification)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::builtins::builtin;
+    use crate::core::{Executor, Tournament};
+    use crate::testing::scratch;
+
+    fn players() -> Vec<Player> {
+        vec![
+            Player::new(
+                "coin".to_string(),
+                Executor::JavaScript(
+                    "function main(history, storage) { return [Math.random() < 0.5, history.length]; }"
+                        .to_string(),
+                ),
+            ),
+            Player::new(
+                "counter".to_string(),
+                Executor::Lua(
+                    "return function(history, storage) print(#history) return #history % 3 ~= 0, #history end"
+                        .to_string(),
+                ),
+            ),
+            builtin("tit-for-tat").unwrap(),
+        ]
+    }
+
+    /// A logged tournament, and what it played
+    fn record(name: &str) -> (PathBuf, TournamentResult) {
+        let path = scratch(name);
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players())
+                .with_rounds(10)
+                .with_noise(0.2)
+                .with_seed(3),
+        )
+        .with_replay_log(&path)
+        .unwrap()
+        .run();
+        assert!(result.matches.iter().all(|game| game.errors.is_empty()));
+        (path, result)
+    }
+
+    #[test]
+    fn logs_give_back_the_tournament() {
+        let (path, result) = record("load");
+        let replay = Replay::load(&path).unwrap();
+        assert_eq!(replay.seed, 3);
+        assert!(replay.matches.iter().all(ReplayMatch::is_complete));
+        let replayed = replay.result();
+        assert_eq!(replayed.scores, result.scores);
+        let rounds = |result: &TournamentResult| {
+            let mut games: Vec<_> = result
+                .matches
+                .iter()
+                .map(|game| (game.player1, game.player2, game.rounds.clone()))
+                .collect();
+            games.sort();
+            games
+        };
+        assert_eq!(rounds(&replayed), rounds(&result));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn verifying_runs_the_strategies_again() {
+        let (path, result) = record("verify");
+        let mut players = players();
+        // Matched by program, not by name or position
+        players.reverse();
+        let verification = Replay::load(&path).unwrap().verify(&players).unwrap();
+        assert_eq!(verification.verified, result.matches.len());
+        assert_eq!(verification.skipped, 0);
+        assert!(verification.mismatches.is_empty());
+
+        assert!(matches!(
+            Replay::load(&path).unwrap().verify(&players[..2]),
+            Err(ReplayError::MissingPlayer(_))
+        ));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn verifying_catches_a_different_move() {
+        let (path, _) = record("tampered");
+        let text = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let mut entry: Value = serde_json::from_str(&lines[1]).unwrap();
+        entry["storages"][4][0] = Value::from("tampered");
+        lines[1] = entry.to_string();
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let verification = Replay::load(&path).unwrap().verify(&players()).unwrap();
+        let [mismatch] = &verification.mismatches[..] else {
+            panic!("{:?}", verification.mismatches);
+        };
+        assert_eq!(mismatch.index, entry["index"].as_u64().unwrap() as usize);
+        assert_eq!(mismatch.player, entry["player1"].as_u64().unwrap() as usize);
+        assert_eq!(mismatch.round, 4);
+        assert_eq!(mismatch.logged.1, Value::from("tampered"));
+        assert_eq!(
+            verification.nondeterministic(),
+            BTreeSet::from([mismatch.player])
+        );
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn broken_logs_say_where() {
+        let path = scratch("broken");
+        fs::write(&path, "").unwrap();
+        assert!(matches!(
+            Replay::load(&path),
+            Err(ReplayError::Invalid { line: 1, .. })
+        ));
+        let (logged, _) = record("broken-source");
+        let header = fs::read_to_string(&logged)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        fs::write(
+            &path,
+            format!(
+                "{header}\n{}\n",
+                r#"{"index": 0, "player1": 0, "player2": 1, "moves": ["CX", "CC"]}"#
+            ),
+        )
+        .unwrap();
+        match Replay::load(&path) {
+            Err(ReplayError::Invalid { line, message, .. }) => {
+                assert_eq!(line, 2);
+                assert!(message.contains("unknown move"), "{message}");
+            }
+            other => panic!("{other:?}"),
+        }
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(logged);
+    }
+
+    #[test]
+    fn failing_to_create_is_an_error() {
+        let path = scratch("missing").join("run.log");
+        assert!(matches!(
+            Tournament::with_config(TournamentConfig::new().with_players(players()))
+                .with_replay_log(&path),
+            Err(ReplayError::Write { .. })
+        ));
+    }
+}
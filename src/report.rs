@@ -0,0 +1,463 @@
+//! A self-contained HTML report of a tournament
+//!
+//! Everything (styles and charts) is inlined so the file
+//! can be shared without the tool or an internet connection
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::core::TournamentResult;
+use crate::export;
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 240.0;
+const MARGIN: f64 = 36.0;
+/// How many generations the ecological simulation runs for
+pub const GENERATIONS: usize = 200;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn color(index: usize) -> String {
+    // Golden angle so neighbouring players get very different hues
+    format!("hsl({:.0}, 65%, 45%)", (index as f64 * 137.508) % 360.0)
+}
+
+/// A line chart of several series that share the same x axis
+fn line_chart(series: &[(String, String, Vec<f64>)], y_label: &str) -> String {
+    let length = series
+        .iter()
+        .map(|(_, _, values)| values.len())
+        .max()
+        .unwrap_or(0);
+    let values = series
+        .iter()
+        .flat_map(|(_, _, values)| values.iter().copied());
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+        (min.min(value), max.max(value))
+    });
+    if length == 0 || !min.is_finite() {
+        return String::new();
+    }
+    let (min, max) = if min == max {
+        (min - 1.0, max + 1.0)
+    } else {
+        (min, max)
+    };
+    let x = |i: usize| MARGIN + (WIDTH - 2.0 * MARGIN) * i as f64 / (length.max(2) - 1) as f64;
+    let y = |value: f64| HEIGHT - MARGIN - (HEIGHT - 2.0 * MARGIN) * (value - min) / (max - min);
+
+    let mut svg = format!(
+        r#"<svg viewBox="0 0 {WIDTH} {HEIGHT}" width="{WIDTH}" height="{HEIGHT}" role="img">"#
+    );
+    let _ = write!(
+        svg,
+        r#"<line x1="{MARGIN}" y1="{bottom}" x2="{right}" y2="{bottom}" class="axis"/><line x1="{MARGIN}" y1="{MARGIN}" x2="{MARGIN}" y2="{bottom}" class="axis"/>"#,
+        bottom = HEIGHT - MARGIN,
+        right = WIDTH - MARGIN,
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="4" y="{top}" class="label">{max}</text><text x="4" y="{bottom}" class="label">{min}</text><text x="{right}" y="{below}" class="label" text-anchor="end">{length}</text><text x="{MARGIN}" y="14" class="label">{label}</text>"#,
+        top = MARGIN,
+        bottom = HEIGHT - MARGIN,
+        right = WIDTH - MARGIN,
+        below = HEIGHT - MARGIN + 16.0,
+        max = format_number(max),
+        min = format_number(min),
+        label = escape(y_label),
+    );
+    for (name, color, values) in series {
+        let points: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("{:.1},{:.1}", x(i), y(*value)))
+            .collect();
+        let _ = write!(
+            svg,
+            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"><title>{}</title></polyline>"#,
+            points.join(" "),
+            color,
+            escape(name)
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn legend(series: &[(String, String, Vec<f64>)]) -> String {
+    let mut html = String::from(r#"<div class="legend">"#);
+    for (name, color, _) in series {
+        let _ = write!(
+            html,
+            r#"<span><i style="background: {}"></i>{}</span>"#,
+            color,
+            escape(name)
+        );
+    }
+    html.push_str("</div>");
+    html
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{value:.0}")
+    } else {
+        format!("{value:.2}")
+    }
+}
+
+fn standings(result: &TournamentResult) -> String {
+    let games = result.games();
+    let abandoned = result.abandoned_games();
+    let ranks = export::ranks(result);
+    let mut html = String::from(
+        "<table><tr><th>#</th><th>Player</th><th>Score</th><th>Games</th><th>Abandoned</th></tr>",
+    );
+    for player in result.standings() {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            ranks[player],
+            escape(&result.players[player]),
+            result.scores[player],
+            games[player],
+            abandoned[player]
+        );
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn heatmap(result: &TournamentResult) -> String {
+    let matrix = result.head_to_head();
+    let count = result.players.len();
+    let cell = 40.0;
+    let label = 160.0;
+    let values = (0..count).flat_map(|i| (0..count).filter(move |&j| i != j).map(move |j| (i, j)));
+    let (min, max) = values.fold((i32::MAX, i32::MIN), |(min, max), (i, j)| {
+        (min.min(matrix[i][j]), max.max(matrix[i][j]))
+    });
+    let size = label + cell * count as f64;
+    let mut svg =
+        format!(r#"<svg viewBox="0 0 {size} {size}" width="{size}" height="{size}" role="img">"#);
+    for (i, name) in result.players.iter().enumerate() {
+        let offset = label + cell * i as f64 + cell / 2.0;
+        let _ = write!(
+            svg,
+            r#"<text x="{x}" y="{offset}" class="label" text-anchor="end" dominant-baseline="middle">{name}</text><text x="{offset}" y="{y}" class="label" transform="rotate(-45 {offset} {y})">{name}</text>"#,
+            x = label - 6.0,
+            y = label - 6.0,
+            name = escape(name),
+        );
+    }
+    for (i, row) in matrix.iter().enumerate() {
+        for (j, score) in row.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let mut ratio = if max == min {
+                0.5
+            } else {
+                (score - min) as f64 / (max - min) as f64
+            };
+            if !result.higher_is_better {
+                ratio = 1.0 - ratio;
+            }
+            let _ = write!(
+                svg,
+                r#"<rect x="{x}" y="{y}" width="{cell}" height="{cell}" fill="hsl({hue:.0}, 70%, 50%)"><title>{row} vs {column}: {score}</title></rect><text x="{tx}" y="{ty}" class="cell" text-anchor="middle" dominant-baseline="middle">{score}</text>"#,
+                x = label + cell * j as f64,
+                y = label + cell * i as f64,
+                hue = 120.0 * ratio,
+                row = escape(&result.players[i]),
+                column = escape(&result.players[j]),
+                tx = label + cell * j as f64 + cell / 2.0,
+                ty = label + cell * i as f64 + cell / 2.0,
+            );
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// The fraction of cooperative moves in each round, over every game
+fn cooperation_rate(result: &TournamentResult) -> Vec<f64> {
+    let length = result
+        .matches
+        .iter()
+        .map(|game| game.rounds.len())
+        .max()
+        .unwrap_or(0);
+    (0..length)
+        .map(|round| {
+            let (cooperations, moves) = result
+                .matches
+                .iter()
+                .filter_map(|game| game.rounds.get(round))
+                .fold((0, 0), |(cooperations, moves), (a, b)| {
+                    (cooperations + *a as usize + *b as usize, moves + 2)
+                });
+            cooperations as f64 / moves.max(1) as f64
+        })
+        .collect()
+}
+
+/// Axelrod's ecological tournament: every player starts with an equal
+/// share of the population, and each generation a strategy's share grows
+/// with its average score against the current population.
+/// Returns the share of every player for every generation, or `None`
+/// when it doesn't apply (fewer than 2 players, or lower scores are better)
+pub fn ecology(result: &TournamentResult, generations: usize) -> Option<Vec<Vec<f64>>> {
+    let count = result.players.len();
+    if count < 2 || !result.higher_is_better {
+        return None;
+    }
+    // Average score per round of i against j
+    let mut totals = vec![vec![0.0; count]; count];
+    let mut rounds = vec![vec![0usize; count]; count];
+    for game in &result.matches {
+        totals[game.player1][game.player2] += game.scores.0 as f64;
+        totals[game.player2][game.player1] += game.scores.1 as f64;
+        rounds[game.player1][game.player2] += game.rounds.len();
+        rounds[game.player2][game.player1] += game.rounds.len();
+    }
+    // Fitness has to be positive, so measure it from the worst payoff
+    let worst = result
+        .matches
+        .iter()
+        .flat_map(|game| game.payoffs.iter().flat_map(|(a, b)| [*a, *b]))
+        .min()? as f64;
+    let payoff = |i: usize, j: usize| {
+        if rounds[i][j] == 0 {
+            0.0
+        } else {
+            totals[i][j] / rounds[i][j] as f64 - worst
+        }
+    };
+
+    let mut population = vec![1.0 / count as f64; count];
+    let mut history = vec![population.clone()];
+    for _ in 0..generations {
+        let fitness: Vec<f64> = (0..count)
+            .map(|i| {
+                let others: f64 = (0..count).filter(|&j| j != i).map(|j| population[j]).sum();
+                if others == 0.0 {
+                    return 0.0;
+                }
+                (0..count)
+                    .filter(|&j| j != i)
+                    .map(|j| population[j] * payoff(i, j))
+                    .sum::<f64>()
+                    / others
+            })
+            .collect();
+        let average: f64 = (0..count).map(|i| population[i] * fitness[i]).sum();
+        if average <= 0.0 {
+            break;
+        }
+        population = (0..count)
+            .map(|i| population[i] * fitness[i] / average)
+            .collect();
+        history.push(population.clone());
+    }
+    Some(history)
+}
+
+pub fn write_html(result: &TournamentResult, writer: &mut impl Write) -> io::Result<()> {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>IPDT tournament report</title>
+<style>
+body { font-family: system-ui, sans-serif; max-width: 960px; margin: 2em auto; padding: 0 1em; color: #222; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; }
+td:last-child { text-align: right; }
+svg { max-width: 100%; height: auto; }
+.axis { stroke: #888; }
+.label { font-size: 11px; fill: #444; }
+.cell { font-size: 10px; fill: #fff; pointer-events: none; }
+.legend span { display: inline-block; margin-right: 1em; font-size: 0.9em; }
+.legend i { display: inline-block; width: 0.8em; height: 0.8em; margin-right: 0.3em; }
+details { margin: 0.5em 0; }
+</style>
+</head>
+<body>
+<h1>Tournament report</h1>
+"#,
+    );
+    let _ = write!(
+        html,
+        "<p>Seed <code>{}</code>, {} players, {} games. {} scores are better.</p>",
+        result.seed,
+        result.players.len(),
+        result.matches.len(),
+        if result.higher_is_better {
+            "Higher"
+        } else {
+            "Lower"
+        }
+    );
+
+    html.push_str("<h2>Standings</h2>");
+    html.push_str(&standings(result));
+
+    html.push_str("<h2>Head to head</h2><p>Score of the row player against the column player.</p>");
+    html.push_str(&heatmap(result));
+
+    html.push_str("<h2>Cooperation over time</h2>");
+    html.push_str(&line_chart(
+        &[(
+            "Cooperation rate".to_string(),
+            color(0),
+            cooperation_rate(result),
+        )],
+        "cooperation rate by round",
+    ));
+
+    if let Some(generations) = ecology(result, GENERATIONS) {
+        let series: Vec<(String, String, Vec<f64>)> = result
+            .players
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                (
+                    name.clone(),
+                    color(i),
+                    generations.iter().map(|shares| shares[i]).collect(),
+                )
+            })
+            .collect();
+        html.push_str("<h2>Ecology</h2><p>Population share of each strategy by generation.</p>");
+        html.push_str(&legend(&series));
+        html.push_str(&line_chart(&series, "population share"));
+    }
+
+    html.push_str("<h2>Games</h2><p>Cumulative score of both players in every game.</p>");
+    for game in &result.matches {
+        let cumulative = |payoffs: Vec<i32>| {
+            payoffs
+                .into_iter()
+                .scan(0.0, |total, payoff| {
+                    *total += payoff as f64;
+                    Some(*total)
+                })
+                .collect::<Vec<f64>>()
+        };
+        let series = [
+            (
+                result.players[game.player1].clone(),
+                color(game.player1),
+                cumulative(game.payoffs.iter().map(|payoff| payoff.0).collect()),
+            ),
+            (
+                result.players[game.player2].clone(),
+                color(game.player2),
+                cumulative(game.payoffs.iter().map(|payoff| payoff.1).collect()),
+            ),
+        ];
+        let _ = write!(
+            html,
+            "<details><summary>{} ({}) vs {} ({})</summary>{}{}</details>",
+            escape(&result.players[game.player1]),
+            game.scores.0,
+            escape(&result.players[game.player2]),
+            game.scores.1,
+            legend(&series),
+            line_chart(&series, "cumulative score")
+        );
+    }
+    html.push_str("</body>\n</html>\n");
+    writer.write_all(html.as_bytes())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::builtins::builtin;
+    use crate::core::{Executor, Player, Tournament, TournamentConfig};
+
+    fn tournament(players: Vec<Player>) -> TournamentResult {
+        Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(50)
+                .with_seed(1),
+        )
+        .run()
+    }
+
+    fn classics() -> Vec<Player> {
+        ["tit-for-tat", "always-cooperate", "always-defect"]
+            .into_iter()
+            .map(|name| builtin(name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reports_stand_alone_and_escape_names() {
+        let mut players = classics();
+        players.push(Player::new(
+            "<script>alert(\"hi\")</script> & co".to_string(),
+            Executor::Lua("return function(history, storage) return true, storage end".to_string()),
+        ));
+        let mut html = vec![];
+        write_html(&tournament(players), &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(!html.contains("<script>alert"));
+        assert!(html.contains("&lt;script&gt;alert(&quot;hi&quot;)&lt;/script&gt; &amp; co"));
+        for external in ["src=\"http", "href=\"http", "@import", "url(http"] {
+            assert!(!html.contains(external), "{external}");
+        }
+        for player in ["Tit for Tat", "Always Cooperate", "Always Defect"] {
+            assert!(html.contains(player), "{player}");
+        }
+    }
+
+    #[test]
+    fn tied_players_share_a_rank() {
+        let mut players = classics();
+        players.push(builtin("always-cooperate").unwrap());
+        let html = standings(&tournament(players));
+        assert_eq!(html.matches("<tr><td>3</td>").count(), 2, "{html}");
+        assert!(!html.contains("<tr><td>4</td>"), "{html}");
+    }
+
+    #[test]
+    fn the_ecology_keeps_the_population() {
+        let shares = ecology(&tournament(classics()), GENERATIONS).unwrap();
+        assert_eq!(shares.len(), GENERATIONS + 1);
+        assert_eq!(shares[0], [1.0 / 3.0; 3]);
+        for generation in &shares {
+            assert!(generation.iter().all(|share| *share >= 0.0));
+            assert!((generation.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+        // Always Cooperate gets exploited by Always Defect from the start
+        assert!(shares
+            .windows(2)
+            .all(|generations| generations[1][1] <= generations[0][1]));
+        assert!(shares[1][2] > shares[0][2]);
+    }
+
+    #[test]
+    fn no_ecology_without_a_population() {
+        assert!(ecology(&tournament(classics()[..1].to_vec()), GENERATIONS).is_none());
+        let lower_is_better = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(classics())
+                .with_rounds(5)
+                .with_win_score(0)
+                .with_loss_score(5),
+        )
+        .run();
+        assert!(ecology(&lower_is_better, GENERATIONS).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+//! A tiny deterministic random number generator
+//!
+//! We don't need anything cryptographic here, just something
+//! that gives the same sequence for the same seed on every
+//! platform so tournaments can be reproduced
+
+/// SplitMix64, see https://prng.di.unimi.it/splitmix64.c
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+    /// Derive an independent generator from a seed and some labels
+    /// (e.g. the names of the two players in a match)
+    pub fn derive(seed: u64, labels: &[&str]) -> Rng {
+        let mut state = seed;
+        for label in labels {
+            state = mix(state ^ hash_str(label));
+        }
+        Rng::new(state)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        mix(self.state)
+    }
+    /// A float in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// Returns true with the given probability
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// FNV-1a, stable across platforms and Rust versions
+/// (unlike `std::hash::DefaultHasher`)
+pub fn hash_str(value: &str) -> u64 {
+    value.bytes().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_implementations() {
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+        assert_eq!(hash_str(""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(hash_str("a"), 0xAF63_DC4C_8601_EC8C);
+    }
+
+    #[test]
+    fn derived_generators_depend_on_seed_and_labels() {
+        let draw = |seed, labels: &[&str]| Rng::derive(seed, labels).next_u64();
+        assert_eq!(draw(1, &["a", "b"]), draw(1, &["a", "b"]));
+        assert_ne!(draw(1, &["a", "b"]), draw(2, &["a", "b"]));
+        assert_ne!(draw(1, &["a", "b"]), draw(1, &["b", "a"]));
+    }
+
+    #[test]
+    fn chance_stays_within_its_bounds() {
+        let mut rng = Rng::new(3);
+        assert!((0..1000).all(|_| !rng.chance(0.0)));
+        assert!((0..1000).all(|_| rng.chance(1.0)));
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&rng.next_f64())));
+    }
+}
@@ -0,0 +1,785 @@
+//! Limits on what strategies may do
+//!
+//! Python strategies can only import the modules on the allowlist (and
+//! their submodules), can't use `open`, `exec`, `eval`, `compile`,
+//! `globals`, `locals` or `vars`, and can't recurse deeper than the recursion limit. The allowlist
+//! is checked for imports made by the strategy's own code, both in its
+//! bytecode and when it calls `__import__`; the allowed modules import
+//! whatever they need.
+//!
+//! Allowed modules hold references to modules that aren't allowed (like
+//! `random._os`), so strategies can't get at the private names of allowed
+//! modules or at modules that aren't allowed through their attributes, and
+//! can't name the attributes that lead from functions, frames and classes
+//! back to the globals of modules (`HIDDEN_ATTRIBUTES`), in their code,
+//! with `getattr` or in the fields of `str.format` and `str.format_map`.
+//! Any of that fails with a `ForbiddenAccess` error. Handing the guarded
+//! builtins or names to an allowed module to use (`functools.partial` calls
+//! anything) doesn't help either: strategies get copies of the builtins
+//! that refuse them wherever they're called from, and modules only get
+//! past the guards for a strategy when none of its code is on the stack,
+//! or for the few hidden attributes that can't lead anywhere, like the
+//! `__mro__` of a class.
+//!
+//! This keeps strategies away from the filesystem, sockets and `os`, but
+//! it is best-effort: Python has many doors, and this only closes the ones
+//! we know of. For code you don't trust, add `--isolate` and `--seccomp`
+//! (see `worker`), which keep a strategy that gets out anyway from changing
+//! files or reaching the network, though not from reading files
+
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use rustpython_vm::builtins::{PyDict, PyStr, PyStrRef, PyType, PyTypeRef};
+use rustpython_vm::bytecode::{CodeObject, ConstantData, Instruction, OpArgState};
+use rustpython_vm::compiler::{self, CompileOpts, Mode};
+use rustpython_vm::frame::FrameRef;
+use rustpython_vm::function::{FuncArgs, PySetterValue};
+use rustpython_vm::scope::Scope;
+use rustpython_vm::{AsObject, PyObjectRef, PyResult, VirtualMachine};
+
+use crate::error::{Diagnostic, ExecutionError};
+
+/// Builtins that only the allowed modules may call. `exec` and friends
+/// would let a strategy run imports we never got to check, `vars` hands
+/// out the `__dict__` of anything and `globals` and `locals` the globals
+const GUARDED_BUILTINS: &[&str] = &[
+    "open", "exec", "eval", "compile", "globals", "locals", "vars",
+];
+
+/// Methods of `str` that look up attributes named in a format string
+const FORMAT_METHODS: &[&str] = &["format", "format_map"];
+
+/// Builtins that only the allowed modules may call with a hidden attribute
+const ATTRIBUTE_BUILTINS: &[&str] = &["getattr", "setattr", "delattr"];
+
+/// Attributes (and names) that lead from what a strategy can reach back to
+/// the globals of modules, the import machinery or every class there is,
+/// and from a guarded module to the plain module class
+const HIDDEN_ATTRIBUTES: &[&str] = &[
+    "__base__",
+    "__bases__",
+    "__builtins__",
+    "__class__",
+    "__closure__",
+    "__code__",
+    "__defaults__",
+    "__dict__",
+    "__getattribute__",
+    "__globals__",
+    "__kwdefaults__",
+    "__loader__",
+    "__mro__",
+    "__reduce__",
+    "__reduce_ex__",
+    "__self__",
+    "__spec__",
+    "__strategy_builtins__",
+    "__subclasses__",
+    "__traceback__",
+    "ag_code",
+    "ag_frame",
+    "cell_contents",
+    "cr_code",
+    "cr_frame",
+    "f_back",
+    "f_builtins",
+    "f_code",
+    "f_globals",
+    "f_locals",
+    "gi_code",
+    "gi_frame",
+    "mro",
+    "tb_frame",
+    "tb_next",
+];
+
+/// Where the builtins a strategy gets in its own globals wait in `builtins`
+const STRATEGY_BUILTINS: &str = "__strategy_builtins__";
+
+/// Private-looking names of modules that strategies may still use
+const MODULE_DUNDERS: &[&str] = &["__all__", "__doc__", "__name__"];
+
+thread_local! {
+    /// Built-in types are shared by the interpreters of a thread,
+    /// so `str` only gets its guarded methods once
+    static FORMAT_GUARDED: Cell<bool> = const { Cell::new(false) };
+    /// How many frames were on the stack when the outermost import the
+    /// sandbox let through started, while it runs
+    static IMPORT_FRAMES: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Stack for the threads strategies run on. Every Python call takes some
+/// of it, and the 2 MiB threads get by default run out before the default
+/// recursion limit does in debug builds
+pub(crate) const STACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// The Python modules strategies may import by default
+pub const DEFAULT_PYTHON_MODULES: &[&str] = &[
+    "collections",
+    "functools",
+    "itertools",
+    "json",
+    "math",
+    "random",
+    "re",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Sandbox {
+    /// Top-level names of the modules Python strategies may import
+    pub python_modules: BTreeSet<String>,
+    /// How deep Python calls may nest
+    pub recursion_limit: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            python_modules: DEFAULT_PYTHON_MODULES
+                .iter()
+                .map(|module| module.to_string())
+                .collect(),
+            recursion_limit: 200,
+        }
+    }
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        Sandbox::default()
+    }
+    pub fn with_python_modules<S: Into<String>>(
+        mut self,
+        modules: impl IntoIterator<Item = S>,
+    ) -> Sandbox {
+        self.python_modules = modules.into_iter().map(Into::into).collect();
+        self
+    }
+    pub fn with_recursion_limit(mut self, limit: usize) -> Sandbox {
+        self.recursion_limit = limit;
+        self
+    }
+    /// Whether a strategy may `import module`
+    pub fn allows(&self, module: &str) -> bool {
+        let root = module.split('.').next().unwrap_or(module);
+        self.python_modules.contains(root)
+    }
+    /// The first import in `program` of a module that isn't allowed, or
+    /// the first hidden attribute it names, if the program compiles
+    pub(crate) fn forbidden(&self, program: &str) -> Option<ExecutionError> {
+        let code = compiler::compile(
+            program,
+            Mode::BlockExpr,
+            "<embedded>".to_string(),
+            CompileOpts::default(),
+        )
+        .ok()?;
+        self.scan(&code)
+    }
+    fn scan(&self, code: &CodeObject) -> Option<ExecutionError> {
+        let mut state = OpArgState::default();
+        for (unit, location) in code.instructions.iter().zip(code.locations.iter()) {
+            let (instruction, arg) = state.get(*unit);
+            let at = |message| {
+                Diagnostic::new(message)
+                    .with_line(Some(location.row.get() as usize))
+                    .with_column(Some(location.column.get() as usize))
+            };
+            let name = match instruction {
+                // Which would bring back the builtin modules call
+                Instruction::DeleteLocal(idx) | Instruction::DeleteGlobal(idx) => {
+                    let name = code.names[idx.get(arg) as usize].as_str();
+                    if strategy_builtin(name) {
+                        return Some(ExecutionError::ForbiddenAccess(at(not_available(
+                            &format!("del {name}"),
+                        ))));
+                    }
+                    continue;
+                }
+                Instruction::ImportName { idx } => {
+                    let module = code.names[idx.get(arg) as usize].as_str();
+                    if module.is_empty() || !self.allows(module) {
+                        return Some(ExecutionError::ForbiddenImport(at(not_allowed(module))));
+                    }
+                    continue;
+                }
+                // `from . import x`
+                Instruction::ImportNameless => {
+                    return Some(ExecutionError::ForbiddenImport(at(not_allowed(""))))
+                }
+                Instruction::LoadAttr { idx }
+                | Instruction::StoreAttr { idx }
+                | Instruction::DeleteAttr { idx }
+                | Instruction::LoadMethod { idx } => code.names[idx.get(arg) as usize].as_str(),
+                Instruction::LoadNameAny(idx) | Instruction::LoadGlobal(idx) => {
+                    code.names[idx.get(arg) as usize].as_str()
+                }
+                _ => continue,
+            };
+            if HIDDEN_ATTRIBUTES.contains(&name) {
+                return Some(ExecutionError::ForbiddenAccess(at(not_available(name))));
+            }
+        }
+        code.constants.iter().find_map(|constant| match constant {
+            ConstantData::Code { code } => self.scan(code),
+            _ => None,
+        })
+    }
+    /// Swap `__import__` for one that checks the allowlist, the guarded
+    /// builtins for ones that refuse the strategy, and the class of the
+    /// allowed modules for one that hides their private names. The
+    /// strategy's own globals get copies of the builtins that refuse it
+    /// wherever they're called from (see `strategy_scope`), so handing one
+    /// to a module to call doesn't get it anywhere
+    pub(crate) fn install(&self, vm: &VirtualMachine) -> PyResult<()> {
+        let builtins = vm.builtins.as_object();
+        let import = builtins.get_attr("__import__", vm)?;
+        let forbidden = vm.ctx.new_exception_type(
+            "sandbox",
+            "ForbiddenImport",
+            Some(vec![vm.ctx.exceptions.import_error.to_owned()]),
+        );
+        let hidden = vm.ctx.new_exception_type(
+            "sandbox",
+            "ForbiddenAccess",
+            Some(vec![vm.ctx.exceptions.permission_error.to_owned()]),
+        );
+        let originals = GUARDED_BUILTINS
+            .iter()
+            .chain(ATTRIBUTE_BUILTINS)
+            .map(|&name| Ok((name, builtins.get_attr(name, vm)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        let mut strict = Vec::new();
+        for strategy in [false, true] {
+            let mut guards = vec![(
+                "__import__",
+                self.import_guard(&import, &forbidden, &hidden, strategy, vm),
+            )];
+            for (name, original) in &originals {
+                let guard = if ATTRIBUTE_BUILTINS.contains(name) {
+                    attribute_guard(name, original, &hidden, strategy, vm)
+                } else {
+                    builtin_guard(name, original, &hidden, strategy, vm)
+                };
+                guards.push((name, guard));
+            }
+            for (name, guard) in guards {
+                if strategy {
+                    strict.push(guard);
+                } else {
+                    builtins.set_attr(name, guard, vm)?;
+                }
+            }
+        }
+        builtins.set_attr(STRATEGY_BUILTINS, vm.ctx.new_tuple(strict), vm)?;
+        if !FORMAT_GUARDED.with(|guarded| guarded.replace(true)) {
+            let str_type = vm.ctx.types.str_type;
+            for &name in FORMAT_METHODS {
+                let original = str_type.as_object().get_attr(name, vm)?;
+                let hidden = hidden.clone();
+                let guarded = vm.new_method(
+                    name,
+                    str_type,
+                    move |args: FuncArgs, vm: &VirtualMachine| {
+                        let format = args.args.first().and_then(|text| text.payload::<PyStr>());
+                        if let Some(attribute) = format.and_then(|text| hidden_field(text.as_str()))
+                        {
+                            if !trusted(vm) {
+                                return Err(
+                                    vm.new_exception_msg(hidden.clone(), not_available(attribute))
+                                );
+                            }
+                        }
+                        original.call(args, vm)
+                    },
+                );
+                str_type.set_attr(vm.ctx.intern_str(name), guarded.into());
+            }
+        }
+        self.guard_modules(&hidden, vm)
+    }
+    /// An `__import__` that checks the allowlist, unless a module calls it
+    /// and it isn't for the `strategy`
+    fn import_guard(
+        &self,
+        import: &PyObjectRef,
+        forbidden: &PyTypeRef,
+        hidden: &PyTypeRef,
+        strategy: bool,
+        vm: &VirtualMachine,
+    ) -> PyObjectRef {
+        let sandbox = self.clone();
+        let (import, forbidden, hidden) = (import.clone(), forbidden.clone(), hidden.clone());
+        vm.new_function(
+            "__import__",
+            move |args: FuncArgs, vm: &VirtualMachine| -> PyResult {
+                let module = args
+                    .args
+                    .first()
+                    .or_else(|| args.kwargs.get("name"))
+                    .and_then(|name| name.payload::<PyStr>())
+                    .map(|name| name.as_str().to_string())
+                    .unwrap_or_default();
+                let level = args
+                    .args
+                    .get(4)
+                    .or_else(|| args.kwargs.get("level"))
+                    .and_then(|level| level.clone().try_into_value::<usize>(vm).ok())
+                    .unwrap_or(0);
+                let module = if level > 0 { "" } else { module.as_str() };
+                if (module.is_empty() || !sandbox.allows(module))
+                    && (strategy || !called_by_module(vm))
+                {
+                    return Err(vm.new_exception_msg(forbidden.clone(), not_allowed(module)));
+                }
+                let imported = importing(vm, || import.call(args, vm))?;
+                // Whatever it imported along the way
+                sandbox.guard_modules(&hidden, vm)?;
+                Ok(imported)
+            },
+        )
+        .into()
+    }
+    /// Give every allowed module that's been imported a class of its own,
+    /// which keeps the strategy from its private names and from the
+    /// modules it imported that aren't allowed, and from swapping that
+    /// class back out
+    fn guard_modules(&self, hidden: &PyTypeRef, vm: &VirtualMachine) -> PyResult<()> {
+        let module_type = vm.ctx.types.module_type;
+        let modules = vm.sys_module.get_attr("modules", vm)?;
+        let Some(modules) = modules.payload::<PyDict>() else {
+            return Ok(());
+        };
+        let unguarded: Vec<(String, PyObjectRef)> = modules
+            .into_iter()
+            .filter_map(|(name, module)| {
+                let name = name.payload::<PyStr>()?.as_str().to_string();
+                (self.allows(&name) && module.class().is(module_type)).then_some((name, module))
+            })
+            .collect();
+        // `module.__getattribute__` would look up the new class, and end
+        // up back in the guard
+        let Some(getattribute) = module_type.slots.getattro.load() else {
+            return Ok(());
+        };
+        for (name, module) in unguarded {
+            let class_name = format!("{name}.__class__");
+            let (set_hidden, set_guarded) = (hidden.clone(), module.clone());
+            let sandbox = self.clone();
+            let hidden = hidden.clone();
+            let guarded = module.clone();
+            let guard = vm.new_function(
+                "__getattribute__",
+                move |attribute: PyStrRef, vm: &VirtualMachine| -> PyResult {
+                    let value = getattribute(&guarded, &attribute, vm)?;
+                    let private = attribute.as_str().starts_with('_')
+                        && !MODULE_DUNDERS.contains(&attribute.as_str());
+                    let foreign = value.fast_isinstance(module_type)
+                        && !value
+                            .get_attr("__name__", vm)
+                            .ok()
+                            .and_then(|name| {
+                                name.payload::<PyStr>()
+                                    .map(|name| name.as_str().to_string())
+                            })
+                            .is_some_and(|name| sandbox.allows(&name));
+                    if (private || foreign) && !trusted(vm) {
+                        return Err(vm.new_exception_msg(
+                            hidden.clone(),
+                            not_available(&format!("{name}.{attribute}")),
+                        ));
+                    }
+                    Ok(value)
+                },
+            );
+            let set_guard = vm.new_function(
+                "__setattr__",
+                move |attribute: PyStrRef, value: PyObjectRef, vm: &VirtualMachine| {
+                    if attribute.as_str() == "__class__" && !trusted(vm) {
+                        return Err(
+                            vm.new_exception_msg(set_hidden.clone(), not_available(&class_name))
+                        );
+                    }
+                    set_guarded.generic_setattr(&attribute, PySetterValue::Assign(value), vm)
+                },
+            );
+            let attributes = vm.ctx.new_dict();
+            attributes.set_item("__getattribute__", guard.into(), vm)?;
+            attributes.set_item("__setattr__", set_guard.into(), vm)?;
+            let class = vm.ctx.types.type_type.as_object().call(
+                (
+                    "module",
+                    vm.ctx.new_tuple(vec![module_type.to_owned().into()]),
+                    attributes,
+                ),
+                vm,
+            )?;
+            module.set_attr("__class__", class, vm)?;
+        }
+        Ok(())
+    }
+}
+
+/// A scope for the code of a strategy, whose globals hold the copies of the
+/// guarded builtins that refuse it
+pub(crate) fn strategy_scope(vm: &VirtualMachine) -> PyResult<Scope> {
+    let scope = vm.new_scope_with_builtins();
+    let guards = vm.builtins.as_object().get_attr(STRATEGY_BUILTINS, vm)?;
+    for guard in guards.try_to_value::<Vec<PyObjectRef>>(vm)? {
+        let name = guard.get_attr("__name__", vm)?;
+        scope.globals.set_item(&*name, guard, vm)?;
+    }
+    Ok(scope)
+}
+
+/// Whether the strategy gets its own copy of builtin `name`
+fn strategy_builtin(name: &str) -> bool {
+    name == "__import__" || GUARDED_BUILTINS.contains(&name) || ATTRIBUTE_BUILTINS.contains(&name)
+}
+
+/// A builtin that refuses the `strategy`, and any other code but a module
+fn builtin_guard(
+    name: &'static str,
+    original: &PyObjectRef,
+    hidden: &PyTypeRef,
+    strategy: bool,
+    vm: &VirtualMachine,
+) -> PyObjectRef {
+    let (original, hidden) = (original.clone(), hidden.clone());
+    vm.new_function(name, move |args: FuncArgs, vm: &VirtualMachine| {
+        if strategy || !called_by_module(vm) {
+            return Err(vm.new_exception_msg(
+                hidden.clone(),
+                format!("{name}() is not available to strategies"),
+            ));
+        }
+        original.call(args, vm)
+    })
+    .into()
+}
+
+/// A builtin that refuses hidden attributes to the `strategy`, and to
+/// modules working for it, which may have been handed the name
+fn attribute_guard(
+    name: &'static str,
+    original: &PyObjectRef,
+    hidden: &PyTypeRef,
+    strategy: bool,
+    vm: &VirtualMachine,
+) -> PyObjectRef {
+    let (original, hidden) = (original.clone(), hidden.clone());
+    vm.new_function(name, move |args: FuncArgs, vm: &VirtualMachine| {
+        let attribute = args.args.get(1).and_then(|name| name.payload::<PyStr>());
+        if let Some(attribute) = attribute.map(|attribute| attribute.as_str()) {
+            let module = trusted(vm)
+                || (called_by_module(vm)
+                    && args
+                        .args
+                        .first()
+                        .is_some_and(|target| harmless(attribute, target, vm)));
+            if HIDDEN_ATTRIBUTES.contains(&attribute) && (strategy || !module) {
+                return Err(vm.new_exception_msg(hidden.clone(), not_available(attribute)));
+            }
+        }
+        original.call(args, vm)
+    })
+    .into()
+}
+
+/// Whether a module may look up the hidden `attribute` of `target` for the
+/// strategy, like `_py_abc` does `__mro__` and `functools.wraps` does
+/// `__dict__`
+fn harmless(attribute: &str, target: &PyObjectRef, vm: &VirtualMachine) -> bool {
+    let module_type = vm.ctx.types.module_type;
+    match attribute {
+        // Only the guarded classes of modules lead to the plain one
+        "__base__" | "__bases__" | "__class__" | "__mro__" => !target
+            .downcast_ref::<PyType>()
+            .is_some_and(|class| class.fast_issubclass(module_type)),
+        // The `__dict__` of a class holds the slots of its methods, like
+        // `object.__getattribute__`, and that of a module is guarded
+        "__dict__" => !target.fast_isinstance(vm.ctx.types.type_type),
+        _ => false,
+    }
+}
+
+/// Run `import`, an import the sandbox let through: the frames it pushes
+/// are trusted as long as they're all modules'
+fn importing(vm: &VirtualMachine, import: impl FnOnce() -> PyResult) -> PyResult {
+    let frames = vm.frames.borrow().len();
+    let outer = IMPORT_FRAMES.with(|start| start.replace(Some(start.get().unwrap_or(frames))));
+    let imported = import();
+    IMPORT_FRAMES.with(|start| start.set(outer));
+    imported
+}
+
+/// The first hidden or double-underscore attribute that a field of a
+/// `str.format` string looks up, like `__globals__` in `{0.__globals__}`
+fn hidden_field(format: &str) -> Option<&str> {
+    // Over-eager with `{{`, but no strategy needs to print those names
+    format.split('{').skip(1).find_map(|field| {
+        let name = field.split(['!', ':', '}']).next().unwrap_or_default();
+        name.split(['.', '[', ']']).skip(1).find(|attribute| {
+            HIDDEN_ATTRIBUTES.contains(attribute)
+                || (attribute.starts_with("__") && attribute.ends_with("__"))
+        })
+    })
+}
+
+fn not_available(name: &str) -> String {
+    format!("'{name}' is not available to strategies")
+}
+
+fn not_allowed(module: &str) -> String {
+    if module.is_empty() {
+        "relative imports are not allowed".to_string()
+    } else {
+        format!("module '{module}' is not allowed")
+    }
+}
+
+/// Whether the running Python code belongs to an imported module
+fn called_by_module(vm: &VirtualMachine) -> bool {
+    let frame = vm.current_frame().map(|frame| frame.clone());
+    frame.is_some_and(|frame| module_frame(&frame, vm))
+}
+
+/// Whether all the Python code on the stack belongs to imported modules,
+/// back to where the running import the sandbox let through started. The
+/// strategy can hand a module anything to call (`functools.partial` calls
+/// whatever it's given), so the frame on top doesn't say who asked
+fn trusted(vm: &VirtualMachine) -> bool {
+    let start = IMPORT_FRAMES.with(Cell::get).unwrap_or(0);
+    // Looking at a frame can run Python, which pushes frames of its own
+    let frames: Vec<FrameRef> = vm.frames.borrow().iter().skip(start).cloned().collect();
+    frames.iter().all(|frame| module_frame(frame, vm))
+}
+
+/// Whether `frame` runs the code of an imported module (rather than the
+/// strategy, or code it made up). Looks at the frame, since any `globals`
+/// argument could be borrowed from a module
+fn module_frame(frame: &FrameRef, vm: &VirtualMachine) -> bool {
+    let globals = PyObjectRef::from(frame.globals.clone());
+    globals
+        .get_item("__name__", vm)
+        .and_then(|name| vm.sys_module.get_attr("modules", vm)?.get_item(&*name, vm))
+        .ok()
+        // Not `__dict__`, which the guard of the module would ask us about
+        .and_then(|module| module.dict())
+        .is_some_and(|dict| dict.is(&globals))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::core::Executor;
+
+    fn forbidden(program: &str) -> Option<ExecutionError> {
+        Sandbox::default().forbidden(program)
+    }
+
+    /// Run a move of `program` in the default sandbox, on a thread with
+    /// the stack Python needs
+    fn run(program: &str) -> Result<bool, ExecutionError> {
+        let program = program.to_string();
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                Executor::Python(program)
+                    .run(&[], Value::from(""))
+                    .map(|decision| decision.action)
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn allows_modules_on_the_allowlist_and_their_submodules() {
+        let sandbox = Sandbox::default();
+        assert!(sandbox.allows("json"));
+        assert!(sandbox.allows("collections.abc"));
+        assert!(!sandbox.allows("os"));
+        assert!(!sandbox.allows("jsonx"));
+        let sandbox = sandbox.with_python_modules(["os"]);
+        assert!(sandbox.allows("os.path"));
+        assert!(!sandbox.allows("json"));
+    }
+
+    #[test]
+    fn finds_forbidden_imports_anywhere_in_the_program() {
+        assert!(forbidden("import json\nfrom collections import abc").is_none());
+        let Some(ExecutionError::ForbiddenImport(diagnostic)) =
+            forbidden("import json\ndef main(h, s):\n    import os\n")
+        else {
+            panic!("os should be forbidden");
+        };
+        assert_eq!(diagnostic.message, "module 'os' is not allowed");
+        assert_eq!(diagnostic.line, Some(3));
+        assert!(matches!(
+            forbidden("from . import x"),
+            Some(ExecutionError::ForbiddenImport(_))
+        ));
+    }
+
+    #[test]
+    fn finds_hidden_attributes_and_names() {
+        assert!(forbidden("class A:\n    def __init__(self):\n        self._x = 1\n").is_none());
+        for program in [
+            "f.__globals__",
+            "object.__subclasses__()",
+            "def f(g):\n    return g.gi_frame.f_globals\n",
+            "__loader__.load_module('os')",
+            "x.__dict__ = {}",
+            "del getattr",
+            "def f():\n    global open\n    del open\n",
+        ] {
+            assert!(
+                matches!(forbidden(program), Some(ExecutionError::ForbiddenAccess(_))),
+                "{program}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_hidden_attributes_in_format_fields() {
+        assert_eq!(hidden_field("{0.__globals__}"), Some("__globals__"));
+        assert_eq!(hidden_field("{} {x:{y.__class__}}"), Some("__class__"));
+        assert_eq!(hidden_field("{0[1].f_back!r}"), Some("f_back"));
+        assert_eq!(hidden_field("{0} {x.real:>3} __dict__"), None);
+    }
+
+    #[test]
+    fn hides_what_allowed_modules_imported() {
+        let programs = [
+            "import random\nrandom._os.listdir('/')",
+            "import random\ngetattr(random, '_os')",
+            "from random import _os",
+            "import json\njson.codecs.open('/etc/passwd')",
+            "import random\ngetattr(random.seed, '__glo' + 'bals__')",
+            "vars(object)",
+            "globals()",
+            "locals()",
+            "open('/etc/passwd')",
+            "'{0.__globals__}'.format(len)",
+            "('{0.__glo' + 'bals__}').format(len)",
+            "'{f.__self__}'.format_map({'f': len})",
+            // Guarded builtins and format strings handed to a module to call
+            "import functools, random\n\
+             g = functools.partial(getattr, random.seed, '__glo' + 'bals__')()\n\
+             g['_os'].listdir('/')",
+            "import functools, random\nfunctools.partial(getattr, random, '_os')().listdir('/')",
+            "import functools\nfunctools.partial(open, '/etc/passwd')()",
+            "import functools\nfunctools.partial('{0.__globals__}'.format, len)()",
+        ];
+        // A module looking up hidden attributes the strategy named, and
+        // copying them into `box`
+        let copies = [
+            ("random.seed", "'__glo' + 'bals__'"),
+            ("object", "'__di' + 'ct__'"),
+            ("type(random)", "'__mr' + 'o__'"),
+            ("type", "'mr' + 'o'"),
+        ]
+        .map(|(target, attribute)| {
+            format!(
+                "import functools, random\n\
+                 box = []\n\
+                 class W:\n    def __setattr__(self, name, value):\n        box.append(value)\n\
+                 functools.update_wrapper(W(), {target}, ({attribute},), ())"
+            )
+        });
+        for program in programs.map(String::from).into_iter().chain(copies) {
+            let program =
+                format!("{program}\ndef main(history, storage):\n    return (True, storage)\n");
+            match run(&program) {
+                Err(ExecutionError::ForbiddenAccess(diagnostic)) => {
+                    assert!(diagnostic
+                        .message
+                        .contains("is not available to strategies"))
+                }
+                other => panic!("{program} gave {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn modules_keep_their_guarded_class() {
+        let escape = "import random\nM = type(random).__bases__[0]\nrandom.__class__ = M\n";
+        let program = format!(
+            "{escape}print(random._os.listdir('/'))\ndef main(history, storage):\n    return (True, storage)\n"
+        );
+        assert!(matches!(
+            run(&program),
+            Err(ExecutionError::ForbiddenAccess(_))
+        ));
+        for name in ["__class__", "__bases__", "__base__", "__mro__", "mro"] {
+            assert!(
+                matches!(
+                    forbidden(&format!("import random\nx = random.{name}")),
+                    Some(ExecutionError::ForbiddenAccess(_))
+                ),
+                "{name}"
+            );
+        }
+
+        // Even if the strategy got past the check, the class would refuse
+        let escape = escape.to_string();
+        let error = std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let interpreter = rustpython::InterpreterConfig::new()
+                    .init_stdlib()
+                    .interpreter();
+                interpreter.enter(|vm| {
+                    vm.import("random", 0).unwrap();
+                    Sandbox::default().install(vm).unwrap();
+                    let scope = vm.new_scope_with_builtins();
+                    vm.run_code_string(scope, &escape, "<escape>".to_string())
+                        .err()
+                        .map(|err| err.class().name().to_string())
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(error.as_deref(), Some("ForbiddenAccess"));
+    }
+
+    #[test]
+    fn allowed_modules_still_work() {
+        let program = "
+import functools, json, random, re
+from collections import Counter, namedtuple
+from math import *
+Point = namedtuple('Point', 'x y')
+@functools.lru_cache(maxsize=None)
+def twice(n):
+    return 2 * n
+def main(history, storage):
+    random.seed(1)
+    ok = (
+        json.loads(json.dumps({'a': [1, 2]}))['a'] == [1, 2]
+        and Counter('aab')['a'] == 2
+        and Counter('aab').most_common(1) == [('a', 2)]
+        and Point(1, 2).y == 2
+        and twice(2) == 4
+        and functools.partial(max, 1)(2) == 2
+        and re.match(r'\\d+', '42') is not None
+        and floor(2.5) == 2
+        and 0 <= random.random() < 1
+        and random.__name__ == 'random'
+        and '{0} {1:>3} {x.real}'.format(1, 2, x=3) == '1   2 3'
+    )
+    return (ok, storage)
+";
+        assert!(run(program).unwrap());
+    }
+}
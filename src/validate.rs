@@ -0,0 +1,422 @@
+//! Checking submissions before a tournament starts
+//!
+//! `Validator::validate` first checks every program with
+//! `Executor::validate`, then plays a few smoke rounds against
+//! reference strategies (the built-ins by default). Loading the
+//! program and every move have to finish within the timeout.
+//!
+//! The check and the smoke rounds run on their own thread, and the
+//! program being checked runs in a worker process (see `worker`), which
+//! gets killed when its top-level code or a move takes longer than the
+//! timeout. Without isolation (`Validator::with_isolation(None)`) it runs
+//! in this process instead, where that can't be stopped: the thread is
+//! left behind until it finishes (or the program exits), keeping a core
+//! busy if the program loops forever. A thread that panics is reported as
+//! a failed check or move
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::builtins;
+use crate::core::{panic_message, Player, TournamentConfig};
+use crate::error::ExecutionError;
+use crate::sandbox::{self, Sandbox};
+use crate::worker::Isolation;
+
+pub struct Validator {
+    rounds: usize,
+    timeout: Duration,
+    opponents: Vec<Player>,
+    sandbox: Sandbox,
+    isolation: Option<Isolation>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The program doesn't parse or lacks its entry point
+    Invalid(ExecutionError),
+    /// Loading the program (running its top level) took longer than the timeout
+    LoadTimedOut { timeout: Duration },
+    /// A smoke round failed
+    Failed {
+        opponent: String,
+        /// 0-based
+        round: usize,
+        error: ExecutionError,
+    },
+    /// A smoke round took longer than the timeout
+    TimedOut {
+        opponent: String,
+        /// 0-based
+        round: usize,
+        timeout: Duration,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Invalid(error) => write!(f, "{error}"),
+            Problem::LoadTimedOut { timeout } => {
+                write!(f, "still loading after {timeout:?}")
+            }
+            Problem::Failed {
+                opponent,
+                round,
+                error,
+            } => write!(f, "against {opponent}, round {}: {error}", round + 1),
+            Problem::TimedOut {
+                opponent,
+                round,
+                timeout,
+            } => write!(
+                f,
+                "against {opponent}, round {}: no move after {timeout:?}",
+                round + 1
+            ),
+        }
+    }
+}
+
+/// Everything wrong with one player
+#[derive(Debug)]
+pub struct Report {
+    pub player: String,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What the smoke test thread sends back, in this order
+enum Step {
+    /// The result of `Executor::validate_sandboxed`
+    Checked(Result<(), ExecutionError>),
+    /// Every player is warmed up
+    Ready,
+    /// A move of the player
+    Moved(Smoke),
+    /// Every smoke round was played
+    Done,
+}
+
+struct Smoke {
+    opponent: usize,
+    round: usize,
+    result: Result<bool, ExecutionError>,
+}
+
+impl Validator {
+    /// 5 rounds against each built-in, 5 seconds per move, in a worker
+    /// with the default limits
+    pub fn new() -> Validator {
+        Validator {
+            rounds: 5,
+            timeout: Duration::from_secs(5),
+            opponents: builtins::builtins(),
+            sandbox: Sandbox::default(),
+            isolation: Some(Isolation::new()),
+        }
+    }
+    /// Smoke rounds to play against each opponent (0 to only parse)
+    pub fn with_rounds(mut self, rounds: usize) -> Validator {
+        self.rounds = rounds;
+        self
+    }
+    /// How long a single move may take
+    pub fn with_timeout(mut self, timeout: Duration) -> Validator {
+        self.timeout = timeout;
+        self
+    }
+    /// The reference strategies to play the smoke rounds against
+    pub fn with_opponents(mut self, opponents: Vec<Player>) -> Validator {
+        self.opponents = opponents;
+        self
+    }
+    /// The sandbox the programs are checked and played in
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Validator {
+        self.sandbox = sandbox;
+        self
+    }
+    /// The worker the programs are checked and played in, with the timeout
+    /// as its move timeout. `None` runs them in this process, where one that
+    /// times out can't be stopped. The opponents always play in this process
+    pub fn with_isolation(mut self, isolation: Option<Isolation>) -> Validator {
+        self.isolation = isolation;
+        self
+    }
+    /// A report for each player, in the same order
+    pub fn validate(&self, players: &[Player]) -> Vec<Report> {
+        players
+            .iter()
+            .map(|player| self.validate_player(player))
+            .collect()
+    }
+    pub fn validate_player(&self, player: &Player) -> Report {
+        let mut report = Report {
+            player: player.name.clone(),
+            problems: vec![],
+        };
+        let (sender, receiver) = mpsc::channel();
+        let rounds = if self.opponents.is_empty() {
+            0
+        } else {
+            self.rounds
+        };
+        // The top level runs when checking, so it gets timed like a move
+        let isolation = self
+            .isolation
+            .clone()
+            .map(|isolation| isolation.with_move_timeout(Some(self.timeout)));
+        let tested = player
+            .clone()
+            .with_sandbox(self.sandbox.clone())
+            .with_isolation(isolation);
+        let opponents = self
+            .opponents
+            .iter()
+            .map(|opponent| {
+                opponent
+                    .clone()
+                    .with_sandbox(self.sandbox.clone())
+                    .with_isolation(None)
+            })
+            .collect();
+        let spawned = thread::Builder::new()
+            .stack_size(sandbox::STACK_SIZE)
+            .spawn(move || smoke(tested, opponents, rounds, sender));
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(err) => {
+                report
+                    .problems
+                    .push(Problem::Invalid(ExecutionError::InitializationError(
+                        format!("Could not start the smoke rounds: {err}"),
+                    )));
+                return report;
+            }
+        };
+        // The thread only hangs up early when it panics
+        let panicked = || {
+            let message = match thread.join() {
+                Err(payload) => panic_message(&*payload),
+                Ok(()) => "stopped early".to_string(),
+            };
+            ExecutionError::Panic(message)
+        };
+        match receiver.recv_timeout(self.timeout) {
+            Ok(Step::Checked(Ok(()))) => {}
+            Ok(Step::Checked(Err(ExecutionError::Timeout(_)))) | Err(RecvTimeoutError::Timeout) => {
+                report.problems.push(Problem::LoadTimedOut {
+                    timeout: self.timeout,
+                });
+                return report;
+            }
+            Ok(Step::Checked(Err(error))) => {
+                report.problems.push(Problem::Invalid(error));
+                return report;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                report.problems.push(Problem::Invalid(panicked()));
+                return report;
+            }
+            Ok(_) => return report,
+        }
+        if rounds == 0 {
+            return report;
+        }
+
+        // Starting the opponents' interpreters too can take a while,
+        // so only start the clock on moves once the thread is ready
+        let mut failures = HashSet::new();
+        let mut expected = (0, 0);
+        let ready = receiver.recv();
+        if !matches!(ready, Ok(Step::Ready)) {
+            report.problems.push(self.panicked(expected, panicked()));
+            return report;
+        }
+        loop {
+            match receiver.recv_timeout(self.timeout) {
+                Ok(Step::Moved(Smoke {
+                    opponent,
+                    round,
+                    result: Err(ExecutionError::Timeout(_)),
+                })) => {
+                    report.problems.push(Problem::TimedOut {
+                        opponent: self.opponent_name(opponent),
+                        round,
+                        timeout: self.timeout,
+                    });
+                    break;
+                }
+                Ok(smoke) => {
+                    let Step::Moved(smoke) = smoke else {
+                        break;
+                    };
+                    expected = (smoke.opponent, smoke.round + 1);
+                    if let Err(error) = smoke.result {
+                        // The same failure against every opponent says nothing new
+                        if failures.insert(error.to_string()) {
+                            report.problems.push(Problem::Failed {
+                                opponent: self.opponents[smoke.opponent].name.clone(),
+                                round: smoke.round,
+                                error,
+                            });
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let (opponent, round) = self.next_move(expected);
+                    report.problems.push(Problem::TimedOut {
+                        opponent: self.opponent_name(opponent),
+                        round,
+                        timeout: self.timeout,
+                    });
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    report.problems.push(self.panicked(expected, panicked()));
+                    break;
+                }
+            }
+        }
+        report
+    }
+    /// The move after `expected` (the opponent and the round after the
+    /// last move that came back)
+    fn next_move(&self, expected: (usize, usize)) -> (usize, usize) {
+        if expected.1 >= self.rounds {
+            (expected.0 + 1, 0)
+        } else {
+            expected
+        }
+    }
+    fn opponent_name(&self, opponent: usize) -> String {
+        self.opponents
+            .get(opponent)
+            .map_or("?".to_string(), |opponent| opponent.name.clone())
+    }
+    /// The smoke rounds stopped with a panic before the move after `expected`
+    fn panicked(&self, expected: (usize, usize), error: ExecutionError) -> Problem {
+        let (opponent, round) = self.next_move(expected);
+        Problem::Failed {
+            opponent: self.opponent_name(opponent),
+            round,
+            error,
+        }
+    }
+}
+
+/// Check `player`, then play `rounds` rounds against every opponent,
+/// reporting every move of `player` and when it's done
+fn smoke(player: Player, opponents: Vec<Player>, rounds: usize, sender: mpsc::Sender<Step>) {
+    let checked = player.check();
+    let ok = checked.is_ok();
+    if sender.send(Step::Checked(checked)).is_err() || !ok || rounds == 0 {
+        return;
+    }
+    for player in std::iter::once(&player).chain(&opponents) {
+        player.warm_up();
+    }
+    if sender.send(Step::Ready).is_err() {
+        return;
+    }
+    let config = TournamentConfig::new().with_rounds(rounds as i32);
+    let fingerprint = player.executor.fingerprint();
+    for (index, opponent) in opponents.into_iter().enumerate() {
+        let opponent_fingerprint = opponent.executor.fingerprint();
+        let (mut player, mut opponent) = (player.clone(), opponent);
+        let mut history = vec![];
+        let mut scores = (0, 0);
+        for round in 0..rounds {
+            let mut context =
+                config.move_context(0, round, (&opponent.name, &opponent_fingerprint), scores);
+            // In case the strategy relies on career storage
+            context.career = Some(player.career().clone());
+            let result = player.run(&history, Some(&context));
+            let action = *result.as_ref().unwrap_or(&false);
+            if sender
+                .send(Step::Moved(Smoke {
+                    opponent: index,
+                    round,
+                    result,
+                }))
+                .is_err()
+            {
+                return;
+            }
+            let context =
+                config.move_context(0, round, (&player.name, &fingerprint), (scores.1, scores.0));
+            let reply = opponent
+                .run(
+                    &history
+                        .iter()
+                        .map(|&(you, them)| (them, you))
+                        .collect::<Vec<_>>(),
+                    Some(&context),
+                )
+                .unwrap_or(false);
+            scores.0 += config.score(action, reply);
+            scores.1 += config.score(reply, action);
+            history.push((action, reply));
+        }
+    }
+    let _ = sender.send(Step::Done);
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::core::Executor;
+
+    /// Check in this process, since the worker is the `ipdt` binary (see
+    /// `validate_stops_programs_that_hang` in the CLI tests for timeouts)
+    fn check(program: &str) -> Report {
+        // Starting an interpreter is slow in debug builds and counts towards the timeout
+        Validator::new()
+            .with_rounds(2)
+            .with_timeout(Duration::from_secs(20))
+            .with_isolation(None)
+            .with_opponents(vec![builtins::builtin("tit-for-tat").unwrap()])
+            .validate_player(&Player::new(
+                "tested".to_string(),
+                Executor::Python(program.to_string()),
+            ))
+    }
+
+    #[test]
+    fn good_programs_pass() {
+        let report = check("def main(history, storage):\n    return (True, storage)\n");
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn broken_programs_are_invalid() {
+        let report = check("def main(history, storage)\n");
+        assert!(matches!(report.problems[..], [Problem::Invalid(_)]));
+        let report = check("def not_main(history, storage):\n    return (True, storage)\n");
+        assert!(matches!(report.problems[..], [Problem::Invalid(_)]));
+    }
+
+    #[test]
+    fn failing_moves_are_reported_once() {
+        let report = check("def main(history, storage):\n    return 1 / 0\n");
+        let [Problem::Failed { round, error, .. }] = &report.problems[..] else {
+            panic!("{:?}", report.problems);
+        };
+        assert_eq!(*round, 0);
+        assert!(error.to_string().contains("ZeroDivisionError"), "{error}");
+    }
+}
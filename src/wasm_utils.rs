@@ -1,3 +1,302 @@
-// macro_rules!  {
-//     () => {};
-// }
+//! Helpers for talking to WASM strategies
+//!
+//! WASM only knows about numbers, so we pass JSON through linear memory.
+//! A strategy module must export:
+//! - `memory`
+//! - `alloc(size: i32) -> i32`, returning a pointer to `size` free bytes
+//! - `main(pointer: i32, length: i32) -> i64`, which reads the input JSON
+//!   and returns `(pointer << 32) | length` of the output JSON
+//!
+//! and may import
+//! - `env.random() -> f64`, a seeded float in [0, 1)
+//! - `env.log(pointer: i32, length: i32)`, to print the UTF-8 text there
+//!   (up to 64 KiB a move)
+
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use std::cell::RefCell;
+
+use wasmer::{
+    imports, Engine, ExternType, Function, FunctionEnv, FunctionEnvMut, Instance, Memory, Module,
+    Store, Type,
+};
+
+use crate::error::ExecutionError;
+use crate::rng::Rng;
+
+/// How many compiled modules each thread keeps around
+const MODULE_CACHE_SIZE: usize = 16;
+
+thread_local! {
+    static ENGINE: Engine = Engine::default();
+    // Compiling is by far the slowest part, so only do it once per program.
+    // Keyed by the whole module, since a hash of a submission could be made
+    // to collide with another player's, most recently used last
+    static MODULES: RefCell<Vec<(Vec<u8>, Module)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn module(bytes: &[u8]) -> Result<(Store, Module), ExecutionError> {
+    ENGINE.with(|engine| {
+        let module = MODULES.with(|modules| {
+            let mut modules = modules.borrow_mut();
+            if let Some(index) = modules.iter().position(|(key, _)| key == bytes) {
+                let cached = modules.remove(index);
+                let module = cached.1.clone();
+                modules.push(cached);
+                return Ok(module);
+            }
+            let module = Module::new(engine, bytes)
+                .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+            if modules.len() >= MODULE_CACHE_SIZE {
+                modules.remove(0);
+            }
+            modules.push((bytes.to_vec(), module.clone()));
+            Ok(module)
+        })?;
+        Ok((Store::new(engine.clone()), module))
+    })
+}
+
+/// Check that the module compiles, exports what we need
+/// and imports nothing but `env.random` and `env.log`
+pub fn check(bytes: &[u8]) -> Result<(), ExecutionError> {
+    let (_, module) = module(bytes)?;
+    let function = |name: &str, params: &[Type], results: &[Type]| {
+        let signature = module
+            .exports()
+            .find(|export| export.name() == name)
+            .map(|export| export.ty().clone());
+        match signature {
+            Some(ExternType::Function(ty)) if ty.params() == params && ty.results() == results => {
+                Ok(())
+            }
+            _ => Err(ExecutionError::InitializationError(format!(
+                "Missing export `{name}({params:?}) -> {results:?}`"
+            ))),
+        }
+    };
+    if !module
+        .exports()
+        .any(|export| export.name() == "memory" && matches!(export.ty(), ExternType::Memory(_)))
+    {
+        return Err(ExecutionError::InitializationError(
+            "Missing export `memory`".to_string(),
+        ));
+    }
+    function("alloc", &[Type::I32], &[Type::I32])?;
+    function("main", &[Type::I32, Type::I32], &[Type::I64])?;
+    for import in module.imports() {
+        let known = match (import.module(), import.name(), import.ty()) {
+            ("env", "random", ExternType::Function(ty)) => {
+                ty.params().is_empty() && ty.results() == [Type::F64]
+            }
+            ("env", "log", ExternType::Function(ty)) => {
+                ty.params() == [Type::I32, Type::I32] && ty.results().is_empty()
+            }
+            _ => false,
+        };
+        if !known {
+            return Err(ExecutionError::InitializationError(format!(
+                "Unknown import `{}.{}`",
+                import.module(),
+                import.name()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What the imports get to work with
+struct Host {
+    rng: Rng,
+    /// Set once the module is instantiated
+    memory: Option<Memory>,
+    printed: String,
+}
+
+fn random(mut host: FunctionEnvMut<Host>) -> f64 {
+    host.data_mut().rng.next_f64()
+}
+
+/// Lines logged past this many bytes in a move are dropped
+const LOG_LIMIT: usize = 64 * 1024;
+
+fn log(mut host: FunctionEnvMut<Host>, pointer: i32, length: i32) {
+    let (host, store) = host.data_and_store_mut();
+    let Some(memory) = &host.memory else {
+        return;
+    };
+    let view = memory.view(&store);
+    let (pointer, length) = (pointer as u32 as u64, length.max(0) as u64);
+    let room = LOG_LIMIT.saturating_sub(host.printed.len()) as u64;
+    // Whatever the module claims, only read what's there and what we keep
+    if room == 0 || pointer + length > view.data_size() {
+        return;
+    }
+    let mut text = vec![0; length.min(room) as usize];
+    if view.read(pointer, &mut text).is_ok() {
+        host.printed.push_str(&String::from_utf8_lossy(&text));
+        host.printed.push('\n');
+    }
+}
+
+/// Call `main` with `input` and return the raw output bytes,
+/// appending what the module logged to `output`
+pub fn call(
+    bytes: &[u8],
+    input: &[u8],
+    seed: u64,
+    output: &mut String,
+) -> Result<Vec<u8>, ExecutionError> {
+    let (mut store, module) = module(bytes)?;
+    let host = FunctionEnv::new(
+        &mut store,
+        Host {
+            rng: Rng::new(seed),
+            memory: None,
+            printed: String::new(),
+        },
+    );
+    let imports = imports! {
+        "env" => {
+            "random" => Function::new_typed_with_env(&mut store, &host, random),
+            "log" => Function::new_typed_with_env(&mut store, &host, log),
+        }
+    };
+    let instance = Instance::new(&mut store, &module, &imports)
+        .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+    let memory = instance
+        .exports
+        .get_memory("memory")
+        .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+    host.as_mut(&mut store).memory = Some(memory.clone());
+    let result = run(&mut store, &instance, memory, input);
+    output.push_str(&host.as_ref(&store).printed);
+    result
+}
+
+fn run(
+    store: &mut Store,
+    instance: &Instance,
+    memory: &Memory,
+    input: &[u8],
+) -> Result<Vec<u8>, ExecutionError> {
+    let alloc = instance
+        .exports
+        .get_typed_function::<i32, i32>(store, "alloc")
+        .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+    let main = instance
+        .exports
+        .get_typed_function::<(i32, i32), i64>(store, "main")
+        .map_err(|err| ExecutionError::InitializationError(err.to_string()))?;
+
+    let length = i32::try_from(input.len())
+        .map_err(|_| ExecutionError::RuntimeError("Input too large".into()))?;
+    let pointer = alloc
+        .call(store, length)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+    memory
+        .view(store)
+        .write(pointer as u32 as u64, input)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+    let packed = main
+        .call(store, pointer, length)
+        .map_err(|err| ExecutionError::RuntimeError(err.to_string().into()))?;
+
+    let (pointer, length) = (packed as u64 >> 32, packed as u32 as u64);
+    let view = memory.view(store);
+    if pointer + length > view.data_size() {
+        return Err(ExecutionError::DeserializationError(format!(
+            "The output ({length} bytes at {pointer}) is outside the memory"
+        )));
+    }
+    let mut output = vec![0; length as usize];
+    view.read(pointer, &mut output)
+        .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Logs `log_length` bytes from 0, `times` times, and returns
+    /// `output_length` bytes from `output`
+    fn module(log_length: i64, times: i32, output: i64, output_length: i64) -> Vec<u8> {
+        format!(
+            r#"(module
+                (import "env" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{{\"action\":true,\"storage\":\"\"}}")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "main") (param i32 i32) (result i64)
+                    (local $i i32)
+                    (block $done
+                        (loop $again
+                            (br_if $done (i32.ge_s (local.get $i) (i32.const {times})))
+                            (call $log (i32.const 0) (i32.const {log_length}))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $again)))
+                    (i64.or
+                        (i64.shl (i64.const {output}) (i64.const 32))
+                        (i64.const {output_length}))))"#
+        )
+        .into_bytes()
+    }
+
+    fn run(module: &[u8]) -> (Result<Vec<u8>, ExecutionError>, String) {
+        let mut printed = String::new();
+        let result = call(module, b"{}", 1, &mut printed);
+        (result, printed)
+    }
+
+    #[test]
+    fn modules_log_and_answer() {
+        let module = module(8, 1, 0, 28);
+        assert!(check(&module).is_ok());
+        let (output, printed) = run(&module);
+        assert_eq!(output.unwrap(), br#"{"action":true,"storage":""}"#);
+        assert_eq!(printed, "{\"action\n");
+    }
+
+    #[test]
+    fn output_outside_the_memory_is_an_error() {
+        for (pointer, length) in [(0, 0xffff_ffff), (0xffff, 28), (0x1_0000, 1)] {
+            let (output, _) = run(&module(0, 0, pointer, length));
+            assert!(
+                matches!(output, Err(ExecutionError::DeserializationError(_))),
+                "{pointer} {length}: {output:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn logs_outside_the_memory_are_dropped() {
+        let (output, printed) = run(&module(0x7fff_ffff, 1, 0, 28));
+        assert!(output.is_ok());
+        assert_eq!(printed, "");
+    }
+
+    #[test]
+    fn logs_are_capped() {
+        let (output, printed) = run(&module(0x1_0000, 3, 0, 28));
+        assert!(output.is_ok());
+        // The first line takes all the room
+        assert_eq!(printed.len(), LOG_LIMIT + 1);
+        let (_, printed) = run(&module(1000, 100, 0, 28));
+        assert!(printed.len() <= LOG_LIMIT + 1000, "{}", printed.len());
+    }
+
+    #[test]
+    fn compiled_modules_are_kept_by_content_and_capped() {
+        for length in 0..MODULE_CACHE_SIZE as i64 + 4 {
+            let (_, printed) = run(&module(length, 1, 0, 28));
+            assert_eq!(printed.len(), length as usize + 1);
+        }
+        assert_eq!(
+            MODULES.with(|modules| modules.borrow().len()),
+            MODULE_CACHE_SIZE
+        );
+    }
+}
@@ -0,0 +1,731 @@
+//! Running strategies in a worker process
+//!
+//! Normally every interpreter runs inside the tournament process, so a
+//! RustPython or wasmer bug, or a strategy eating all the memory, takes the
+//! whole tournament down. With an `Isolation` (`TournamentConfig::with_isolation`)
+//! every player's moves run in a child process instead: the same binary,
+//! started as `ipdt worker`, which calls `serve`. When it crashes or runs out
+//! of memory, the move fails with `ExecutionError::WorkerError` (or
+//! `ExecutionError::Timeout` when it takes too long) and the next move starts
+//! a fresh worker.
+//!
+//! The worker runs with a data size limit, can't make files grow and leaves
+//! no core dumps. That still lets it empty, delete or rename files: on Linux
+//! a seccomp filter refuses opening files for writing and everything else
+//! that changes them, along with sockets, starting programs and tracing
+//! other processes, and the worker can also get its own user and network
+//! namespaces (so no network). It can still read what the user can.
+//!
+//! Parent and worker talk in JSON lines over the worker's stdin and
+//! stdout: a `Setup`, answered once the interpreter is ready, then a
+//! `Request` per move, each answered with a `Response`.
+//!
+//! Piston programs already run somewhere else, and local programs
+//! (`Executor::Command`) in processes of their own, so they stay in the
+//! tournament process
+
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::core::{self, Executor, Move, MoveContext};
+use crate::error::ExecutionError;
+use crate::sandbox::{self, Sandbox};
+
+/// How many workers each thread keeps around
+const WORKER_POOL_SIZE: usize = 16;
+
+/// How much of what a worker writes to stderr to keep for the error
+const STDERR_LIMIT: u64 = 16 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Isolation {
+    /// The program to start a worker with, this binary by default
+    pub command: PathBuf,
+    pub args: Vec<String>,
+    /// Bytes of data the worker may allocate (`RLIMIT_DATA`)
+    pub memory_limit: Option<u64>,
+    /// How long a move may take before the worker gets killed
+    pub move_timeout: Option<Duration>,
+    /// Run the worker in its own user and network namespaces (Linux only)
+    pub namespaces: bool,
+    /// Refuse sockets, `exec`, `ptrace` and changing files with seccomp
+    /// (Linux on x86-64 and AArch64 only)
+    pub seccomp: bool,
+}
+
+impl Default for Isolation {
+    /// `ipdt worker` with 1 GiB of memory and no timeout
+    fn default() -> Self {
+        Isolation {
+            command: std::env::current_exe().unwrap_or_else(|_| PathBuf::from("ipdt")),
+            args: vec!["worker".to_string()],
+            memory_limit: Some(1024 * 1024 * 1024),
+            move_timeout: None,
+            namespaces: false,
+            seccomp: false,
+        }
+    }
+}
+
+impl Isolation {
+    pub fn new() -> Isolation {
+        Isolation::default()
+    }
+    /// The limits strategies play under, leaving out where the worker comes
+    /// from, which doesn't change how they play
+    pub(crate) fn limits(&self) -> (Option<u64>, Option<Duration>, bool, bool) {
+        (
+            self.memory_limit,
+            self.move_timeout,
+            self.namespaces,
+            self.seccomp,
+        )
+    }
+    /// Start workers with something other than `ipdt worker`,
+    /// for programs embedding the library (which should call `serve`)
+    pub fn with_command(mut self, command: impl Into<PathBuf>, args: Vec<String>) -> Isolation {
+        self.command = command.into();
+        self.args = args;
+        self
+    }
+    pub fn with_memory_limit(mut self, bytes: Option<u64>) -> Isolation {
+        self.memory_limit = bytes;
+        self
+    }
+    pub fn with_move_timeout(mut self, timeout: Option<Duration>) -> Isolation {
+        self.move_timeout = timeout;
+        self
+    }
+    pub fn with_namespaces(mut self, namespaces: bool) -> Isolation {
+        self.namespaces = namespaces;
+        self
+    }
+    pub fn with_seccomp(mut self, seccomp: bool) -> Isolation {
+        self.seccomp = seccomp;
+        self
+    }
+}
+
+/// The first message to a worker: what it runs and how it's locked down
+#[derive(Serialize, Deserialize)]
+struct Setup {
+    executor: Executor,
+    sandbox: Sandbox,
+    /// Check the program (running its top level) rather than just get the
+    /// interpreter ready
+    check: bool,
+    memory_limit: Option<u64>,
+    namespaces: bool,
+    seccomp: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    /// The game of the player (see `core::next_game`), so every game
+    /// gets a fresh interpreter
+    game: u64,
+    history: Vec<(bool, bool)>,
+    storage: Value,
+    seed: u64,
+    context: Option<MoveContext>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    result: Result<Move, ExecutionError>,
+    output: String,
+}
+
+/// A worker process running one program
+struct Worker {
+    key: String,
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+}
+
+thread_local! {
+    /// Workers are kept between games, keyed by the isolation, the sandbox
+    /// and the fingerprint of the program. Their interpreters only last a game
+    static WORKERS: RefCell<Vec<Worker>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Worker {
+    /// Start a worker and wait for it to be ready, or to have checked the
+    /// program if there is a `check` timeout
+    fn start(
+        key: String,
+        isolation: &Isolation,
+        executor: &Executor,
+        sandbox: &Sandbox,
+        check: Option<Option<Duration>>,
+    ) -> Result<Worker, ExecutionError> {
+        let mut child = Command::new(&isolation.command)
+            .args(&isolation.args)
+            // Only the message of a panic ends up in the error
+            .env("RUST_BACKTRACE", "0")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| ExecutionError::WorkerError(format!("Could not start: {err}")))?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ExecutionError::WorkerError("No pipes".to_string()));
+        };
+        // Reading on threads of their own, so a move can time out
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let stderr = thread::spawn(move || {
+            let mut stderr = stderr;
+            let mut kept = vec![];
+            let _ = (&mut stderr).take(STDERR_LIMIT).read_to_end(&mut kept);
+            let _ = io::copy(&mut stderr, &mut io::sink());
+            kept
+        });
+        let mut worker = Worker {
+            key,
+            child,
+            stdin,
+            lines,
+            stderr: Some(stderr),
+        };
+        let setup = Setup {
+            executor: executor.clone(),
+            sandbox: sandbox.clone(),
+            check: check.is_some(),
+            memory_limit: isolation.memory_limit,
+            namespaces: isolation.namespaces,
+            seccomp: isolation.seccomp,
+        };
+        // Starting an interpreter doesn't run the strategy, so no timeout
+        // unless it's checked
+        worker.call::<Result<(), ExecutionError>>(&setup, check.flatten())??;
+        Ok(worker)
+    }
+    fn call<T: DeserializeOwned>(
+        &mut self,
+        message: &impl Serialize,
+        timeout: Option<Duration>,
+    ) -> Result<T, ExecutionError> {
+        let line = serde_json::to_string(message)
+            .map_err(|err| ExecutionError::DeserializationError(err.to_string()))?;
+        if writeln!(self.stdin, "{line}")
+            .and_then(|()| self.stdin.flush())
+            .is_err()
+        {
+            return Err(self.died());
+        }
+        let line = match timeout {
+            Some(timeout) => match self.lines.recv_timeout(timeout) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(ExecutionError::Timeout(format!(
+                        "The move took longer than {}s",
+                        timeout.as_secs_f64()
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.died()),
+            },
+            None => match self.lines.recv() {
+                Ok(line) => line,
+                Err(_) => return Err(self.died()),
+            },
+        };
+        serde_json::from_str(&line)
+            .map_err(|err| ExecutionError::WorkerError(format!("Unreadable answer: {err}")))
+    }
+    /// What happened to a worker that stopped answering
+    fn died(&mut self) -> ExecutionError {
+        let _ = self.child.kill();
+        let status = self.child.wait();
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr);
+        let mut message = match status {
+            Ok(status) => describe(status),
+            Err(err) => format!("Crashed: {err}"),
+        };
+        // Panics and failed allocations end with what went wrong
+        if let Some(last) = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty() && !line.starts_with("note:"))
+        {
+            message = format!("{message}: {}", last.trim());
+        }
+        ExecutionError::WorkerError(message)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub(crate) fn describe(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        match status.signal() {
+            Some(libc::SIGABRT) => return "Aborted (out of memory?)".to_string(),
+            Some(libc::SIGKILL) => return "Killed".to_string(),
+            Some(libc::SIGSEGV) => return "Crashed with a segmentation fault".to_string(),
+            Some(signal) => return format!("Killed by signal {signal}"),
+            None => {}
+        }
+    }
+    match status.code() {
+        Some(code) => format!("Exited with status {code}"),
+        None => "Exited".to_string(),
+    }
+}
+
+/// Make a move in the worker for `executor`, starting one if needed.
+/// See `Executor::run_logged`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run(
+    isolation: &Isolation,
+    game: u64,
+    executor: &Executor,
+    sandbox: &Sandbox,
+    history: &[(bool, bool)],
+    storage: Value,
+    seed: u64,
+    context: Option<&MoveContext>,
+    output: &mut String,
+) -> Result<Move, ExecutionError> {
+    if let Executor::Piston(..) | Executor::Command(..) = executor {
+        return executor.run_in_game(game, history, storage, seed, context, sandbox, output);
+    }
+    let mut worker = worker(isolation, executor, sandbox)?;
+    let request = Request {
+        game,
+        history: history.to_vec(),
+        storage,
+        seed,
+        context: context.cloned(),
+    };
+    // A worker that failed is dropped (and killed), the next move starts another
+    let response: Response = worker.call(&request, isolation.move_timeout)?;
+    output.push_str(&response.output);
+    keep(worker);
+    response.result
+}
+
+/// Check `executor` (see `Executor::validate_sandboxed`) in a new worker,
+/// which gets killed if it takes longer than the move timeout, and keep
+/// the worker on this thread for the moves after
+pub(crate) fn check(
+    isolation: &Isolation,
+    executor: &Executor,
+    sandbox: &Sandbox,
+) -> Result<(), ExecutionError> {
+    if let Executor::Piston(..) | Executor::Command(..) = executor {
+        return executor.validate_sandboxed(sandbox);
+    }
+    let key = key(isolation, executor, sandbox);
+    let worker = Worker::start(
+        key,
+        isolation,
+        executor,
+        sandbox,
+        Some(isolation.move_timeout),
+    )?;
+    keep(worker);
+    Ok(())
+}
+
+fn keep(worker: Worker) {
+    WORKERS.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() >= WORKER_POOL_SIZE {
+            pool.remove(0);
+        }
+        pool.push(worker);
+    });
+}
+
+/// Start the worker for `executor` on this thread ahead of its first move
+pub(crate) fn warm_up(isolation: &Isolation, executor: &Executor, sandbox: &Sandbox) {
+    if let Executor::Piston(..) | Executor::Command(..) = executor {
+        return;
+    }
+    if let Ok(worker) = worker(isolation, executor, sandbox) {
+        keep(worker);
+    }
+}
+
+fn key(isolation: &Isolation, executor: &Executor, sandbox: &Sandbox) -> String {
+    format!("{isolation:?}\0{sandbox:?}\0{}", executor.fingerprint())
+}
+
+fn worker(
+    isolation: &Isolation,
+    executor: &Executor,
+    sandbox: &Sandbox,
+) -> Result<Worker, ExecutionError> {
+    let key = key(isolation, executor, sandbox);
+    let pooled = WORKERS.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        let index = pool.iter().position(|worker| worker.key == key)?;
+        Some(pool.remove(index))
+    });
+    match pooled {
+        Some(worker) => Ok(worker),
+        None => Worker::start(key, isolation, executor, sandbox, None),
+    }
+}
+
+/// The worker side: read a `Setup` and then moves from stdin, answering on
+/// stdout, until stdin closes. Has to be called before the process starts
+/// any threads, since namespaces and seccomp only apply to the calling one
+/// (and the threads it starts after)
+pub fn serve() -> io::Result<()> {
+    let Some(setup) = io::stdin().lines().next() else {
+        return Ok(());
+    };
+    let setup: Setup = serde_json::from_str(&setup?).map_err(io::Error::other)?;
+    let locked = lock_down(&setup)
+        .map_err(|err| ExecutionError::WorkerError(format!("Could not lock down: {err}")));
+    // Strategies get the stack they get when they run in the tournament
+    let spawned = thread::Builder::new()
+        .stack_size(sandbox::STACK_SIZE)
+        .spawn(move || play(setup, locked));
+    match spawned {
+        Ok(thread) => thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("the worker panicked"))),
+        Err(err) => answer(
+            &mut io::stdout().lock(),
+            &Err::<(), _>(ExecutionError::WorkerError(format!(
+                "Could not start a thread: {err}"
+            ))),
+        ),
+    }
+}
+
+/// Answer whether the worker is ready, then play the moves that come in
+fn play(setup: Setup, locked: Result<(), ExecutionError>) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let ready = locked.and_then(|()| {
+        if setup.check {
+            setup.executor.validate_sandboxed(&setup.sandbox)
+        } else {
+            setup.executor.warm_up(&setup.sandbox);
+            Ok(())
+        }
+    });
+    answer(&mut stdout, &ready)?;
+    if ready.is_err() {
+        return Ok(());
+    }
+    // The worker outlives games, its interpreters don't. Workers belong to
+    // a thread of the tournament, which plays one game at a time, so at
+    // most two games (one per player running this program) are going on
+    let mut games = VecDeque::with_capacity(3);
+    for line in io::stdin().lock().lines() {
+        let request: Request = serde_json::from_str(&line?).map_err(io::Error::other)?;
+        if !games.contains(&request.game) {
+            games.push_back(request.game);
+            if games.len() > 2 {
+                if let Some(over) = games.pop_front() {
+                    core::end_game(over);
+                }
+            }
+        }
+        let mut output = String::new();
+        let result = setup.executor.run_in_game(
+            request.game,
+            &request.history,
+            request.storage,
+            request.seed,
+            request.context.as_ref(),
+            &setup.sandbox,
+            &mut output,
+        );
+        answer(&mut stdout, &Response { result, output })?;
+    }
+    Ok(())
+}
+
+fn answer(stdout: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    let line = serde_json::to_string(message).map_err(io::Error::other)?;
+    writeln!(stdout, "{line}")?;
+    stdout.flush()
+}
+
+#[cfg(unix)]
+fn lock_down(setup: &Setup) -> io::Result<()> {
+    // Writing files fails (rather than killing the worker, since the
+    // interpreters try to cache things) and there are no core dumps
+    // SAFETY: ignoring a signal installs no handler
+    if unsafe { libc::signal(libc::SIGXFSZ, libc::SIG_IGN) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    set_limit(libc::RLIMIT_FSIZE, 0)?;
+    set_limit(libc::RLIMIT_CORE, 0)?;
+    if let Some(bytes) = setup.memory_limit {
+        // Not `RLIMIT_AS`: wasmer reserves far more address space than it uses
+        set_limit(libc::RLIMIT_DATA, bytes)?;
+    }
+    if setup.namespaces {
+        unshare()?;
+    }
+    if setup.seccomp {
+        seccomp()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_down(_setup: &Setup) -> io::Result<()> {
+    Err(io::Error::other("workers need a Unix"))
+}
+
+#[cfg(unix)]
+fn set_limit(resource: impl Into<i64>, limit: u64) -> io::Result<()> {
+    let resource = resource.into();
+    let limit = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    // SAFETY: `limit` is a valid rlimit that outlives the call
+    if unsafe { libc::setrlimit(resource as _, &limit) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unshare() -> io::Result<()> {
+    // SAFETY: takes no pointers, and the process has a single thread
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn unshare() -> io::Result<()> {
+    Err(io::Error::other("namespaces need Linux"))
+}
+
+/// `AUDIT_ARCH_*` of the syscalls the filter knows
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls a strategy has no business making
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    // Changing files, other than by writing to them
+    libc::SYS_openat2,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_truncate,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_mknodat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    // Which would do all of the above without a syscall of its own
+    libc::SYS_io_uring_setup,
+];
+
+/// The same for the older syscalls AArch64 doesn't have
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const DENIED_LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_creat,
+    libc::SYS_unlink,
+    libc::SYS_rmdir,
+    libc::SYS_rename,
+    libc::SYS_link,
+    libc::SYS_symlink,
+    libc::SYS_mkdir,
+    libc::SYS_mknod,
+    libc::SYS_chmod,
+    libc::SYS_chown,
+    libc::SYS_lchown,
+    libc::SYS_utime,
+    libc::SYS_utimes,
+    libc::SYS_futimesat,
+];
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const DENIED_LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+/// Syscalls that open files, and which of their arguments holds the flags
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const OPEN_SYSCALLS: &[(libc::c_long, u32)] = &[(libc::SYS_open, 1), (libc::SYS_openat, 2)];
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const OPEN_SYSCALLS: &[(libc::c_long, u32)] = &[(libc::SYS_openat, 2)];
+
+/// A filter failing the denied syscalls, and opening files for writing,
+/// with `EPERM`, and killing the worker on syscalls of any other
+/// architecture
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn seccomp() -> io::Result<()> {
+    use libc::{
+        sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_JSET, BPF_K, BPF_LD,
+        BPF_RET, BPF_W, O_CREAT, O_RDWR, O_TRUNC, O_WRONLY, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO,
+        SECCOMP_RET_KILL_PROCESS,
+    };
+    let statement = |code: u32, k: u32| sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |test: u32, k: u32, jt: u8, jf: u8| sock_filter {
+        code: (BPF_JMP | test | BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    // Offsets into `struct seccomp_data`, where the arguments are 64 bits
+    // each, lower half first
+    let (nr, arch, args) = (0, 4, 16);
+    let deny = statement(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let mut filter = vec![
+        statement(BPF_LD | BPF_W | BPF_ABS, arch),
+        jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, nr),
+        // x32 syscalls share the x86-64 arch, with this bit set
+        jump(BPF_JGE, 0x4000_0000, 0, 1),
+        statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+    ];
+    for &syscall in DENIED_SYSCALLS.iter().chain(DENIED_LEGACY_SYSCALLS) {
+        filter.push(jump(BPF_JEQ, syscall as u32, 0, 1));
+        filter.push(deny);
+    }
+    let writing = (O_WRONLY | O_RDWR | O_CREAT | O_TRUNC) as u32;
+    for &(syscall, flags) in OPEN_SYSCALLS {
+        filter.extend([
+            jump(BPF_JEQ, syscall as u32, 0, 4),
+            statement(BPF_LD | BPF_W | BPF_ABS, args + 8 * flags),
+            jump(BPF_JSET, writing, 0, 1),
+            deny,
+            statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+        ]);
+    }
+    filter.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: `program` points at `filter`, which outlives both calls
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const sock_fprog,
+            ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(
+    unix,
+    not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))
+))]
+fn seccomp() -> io::Result<()> {
+    Err(io::Error::other("seccomp needs Linux on x86-64 or AArch64"))
+}
+
+#[cfg(all(test, unix))]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn status(script: &str) -> ExitStatus {
+        Command::new("sh").args(["-c", script]).status().unwrap()
+    }
+
+    #[test]
+    fn exits_say_how_the_worker_died() {
+        assert_eq!(describe(status("exit 3")), "Exited with status 3");
+        assert_eq!(describe(status("kill -9 $$")), "Killed");
+        assert_eq!(describe(status("kill -6 $$")), "Aborted (out of memory?)");
+        assert_eq!(
+            describe(status("kill -11 $$")),
+            "Crashed with a segmentation fault"
+        );
+        assert_eq!(describe(status("kill -15 $$")), "Killed by signal 15");
+    }
+}
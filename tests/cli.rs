@@ -0,0 +1,408 @@
+//! Running the `ipdt` binary the way people do
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+/// A directory of its own for every test, emptied first
+fn scratch(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("ipdt-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn write(directory: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = directory.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn ipdt(arguments: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ipdt"))
+        .args(arguments)
+        .output()
+        .unwrap()
+}
+
+/// Run `ipdt` and read the JSON it printed
+fn ipdt_json(arguments: &[&str]) -> Value {
+    let output = ipdt(arguments);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// Cooperates as long as nothing it did before this game is left over
+const COUNTING: &str = "
+import math
+def main(history, storage):
+    math.calls = getattr(math, 'calls', 0) + 1
+    return (math.calls == len(history) + 1, storage)
+";
+
+#[test]
+fn python_state_does_not_outlive_a_game_in_workers() {
+    let directory = scratch("counting");
+    for name in ["a.py", "b.py", "c.py"] {
+        write(&directory, name, COUNTING);
+    }
+    let directory = directory.to_str().unwrap();
+    for parallelism in ["1", "4"] {
+        let results = ipdt_json(&[
+            "run",
+            directory,
+            "--isolate",
+            "--seed",
+            "1",
+            "-r",
+            "3",
+            "-j",
+            parallelism,
+            "-f",
+            "json",
+        ]);
+        let games = results["matches"].as_array().unwrap();
+        assert_eq!(games.len(), 6);
+        for game in games {
+            for round in game["rounds"].as_array().unwrap() {
+                assert_eq!(round["actions"], serde_json::json!([true, true]), "{game}");
+            }
+        }
+    }
+}
+
+#[test]
+fn rounds_and_noise_are_checked() {
+    let directory = scratch("checked");
+    let rounds = write(
+        &directory,
+        "rounds.json",
+        r#"{"players": [], "rounds": -1}"#,
+    );
+    let noise = write(&directory, "noise.json", r#"{"players": [], "noise": 2}"#);
+    for (arguments, complaint) in [
+        (
+            vec!["match", "tit-for-tat", "tit-for-tat", "--rounds=-1"],
+            "rounds",
+        ),
+        (
+            vec!["match", "tit-for-tat", "tit-for-tat", "--noise=1.5"],
+            "Noise",
+        ),
+        (
+            vec!["match", "tit-for-tat", "tit-for-tat", "--noise=-0.1"],
+            "Noise",
+        ),
+        (vec!["run", rounds.to_str().unwrap()], "rounds"),
+        (vec!["run", noise.to_str().unwrap()], "Noise"),
+    ] {
+        let output = ipdt(&arguments);
+        assert!(!output.status.success(), "{arguments:?}");
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains(complaint),
+            "{arguments:?}"
+        );
+    }
+}
+
+#[test]
+fn the_same_seed_plays_the_same_tournament() {
+    let directory = scratch("seeded");
+    write(
+        &directory,
+        "coin.py",
+        "import random\ndef main(history, storage):\n    return (random.random() < 0.5, storage)\n",
+    );
+    // Draws at the top of the program too, which runs once per game
+    write(
+        &directory,
+        "biased.py",
+        "import random\nrandom.random()\ndef main(history, storage):\n    return (random.random() < 0.7, storage)\n",
+    );
+    write(
+        &directory,
+        "coin.js",
+        "function main(history, storage) { return [Math.random() < 0.5, storage]; }\n",
+    );
+    let directory = directory.to_str().unwrap();
+    let run = |seed: &str, parallelism: &str| {
+        ipdt_json(&[
+            "run",
+            directory,
+            "--seed",
+            seed,
+            "-j",
+            parallelism,
+            "--noise",
+            "0.1",
+            "-r",
+            "20",
+            "-f",
+            "json",
+        ])
+    };
+    let first = run("7", "4");
+    assert!(first["matches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|game| game["errors"].as_array().unwrap().is_empty()));
+    assert_eq!(first, run("7", "4"));
+    assert_eq!(first, run("7", "1"));
+    assert_ne!(first, run("8", "4"));
+}
+
+#[test]
+fn checkpoints_of_other_runs_are_not_overwritten() {
+    let directory = scratch("checkpoint");
+    write(&directory, "a.py", COUNTING);
+    write(&directory, "b.py", COUNTING);
+    let checkpoint = write(&directory, "run.ckpt", "not a checkpoint\n");
+    let (directory, checkpoint) = (directory.to_str().unwrap(), checkpoint.to_str().unwrap());
+    let run = |extra: &[&str]| {
+        let mut arguments = vec!["run", directory, "--checkpoint", checkpoint, "-r", "2"];
+        arguments.extend(extra);
+        ipdt(&arguments)
+    };
+    let output = run(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--overwrite-checkpoint"));
+    assert_eq!(
+        fs::read_to_string(checkpoint).unwrap(),
+        "not a checkpoint\n"
+    );
+
+    assert!(run(&["--overwrite-checkpoint"]).status.success());
+    assert!(run(&[]).status.success());
+    // A header and the 2 games
+    assert_eq!(fs::read_to_string(checkpoint).unwrap().lines().count(), 3);
+}
+
+#[test]
+fn worker_limits_only_cost_the_move() {
+    let directory = scratch("limits");
+    let slow = write(
+        &directory,
+        "slow.py",
+        "def main(history, storage):\n    while True:\n        pass\n",
+    );
+    let greedy = write(
+        &directory,
+        "greedy.py",
+        "def main(history, storage):\n    hoard = [0] * (1 << 30)\n    return (True, storage)\n",
+    );
+    let results = ipdt_json(&[
+        "match",
+        slow.to_str().unwrap(),
+        greedy.to_str().unwrap(),
+        "-r",
+        "2",
+        "--worker-timeout",
+        "1",
+        "--memory-limit",
+        "256",
+        "-f",
+        "json",
+    ]);
+    let errors = results["matches"][0]["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 4, "{errors:?}");
+    for error in errors {
+        let message = error["message"].as_str().unwrap();
+        match error["player"].as_u64() {
+            Some(0) => assert!(message.starts_with("Timeout:"), "{message}"),
+            _ => assert!(message.starts_with("Worker error:"), "{message}"),
+        }
+    }
+}
+
+#[test]
+fn workers_have_the_stack_of_the_tournament() {
+    let directory = scratch("deep");
+    let deep = write(
+        &directory,
+        "deep.py",
+        "def depth(n):\n    return 0 if n == 0 else 1 + depth(n - 1)\n\ndef main(history, storage):\n    return (depth(500) == 500, storage)\n",
+    );
+    let deep = deep.to_str().unwrap();
+    let results = ipdt_json(&[
+        "match",
+        deep,
+        deep,
+        "-r",
+        "1",
+        "--isolate",
+        "--recursion-limit",
+        "1000",
+        "-f",
+        "json",
+    ]);
+    let game = &results["matches"][0];
+    assert_eq!(game["errors"], serde_json::json!([]), "{game}");
+    assert_eq!(
+        game["rounds"][0]["actions"],
+        serde_json::json!([true, true])
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn seccomp_keeps_workers_from_changing_files() {
+    let directory = scratch("seccomp");
+    let kept = write(&directory, "kept.txt", "kept");
+    let meddling = write(
+        &directory,
+        "meddling.py",
+        &format!(
+            "import os
+PATH = {kept:?}
+CHANGES = [
+    lambda: os.open(PATH, os.O_WRONLY | os.O_TRUNC),
+    lambda: os.open(PATH + '.new', os.O_RDONLY | os.O_CREAT),
+    lambda: os.rename(PATH, PATH + '.old'),
+    lambda: os.remove(PATH),
+]
+def main(history, storage):
+    for change in CHANGES:
+        try:
+            change()
+            return (False, storage)
+        except OSError:
+            pass
+    return (True, storage)
+"
+        ),
+    );
+    let lua = write(
+        &directory,
+        "lua.lua",
+        "return function(history, storage) return true, storage end",
+    );
+    let results = ipdt_json(&[
+        "match",
+        meddling.to_str().unwrap(),
+        lua.to_str().unwrap(),
+        "-r",
+        "1",
+        "--seccomp",
+        "--python-modules",
+        "os",
+        "-f",
+        "json",
+    ]);
+    let game = &results["matches"][0];
+    assert_eq!(game["errors"], serde_json::json!([]), "{game}");
+    assert_eq!(
+        game["rounds"][0]["actions"],
+        serde_json::json!([true, true])
+    );
+    assert_eq!(fs::read_to_string(&kept).unwrap(), "kept");
+    assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+}
+
+#[test]
+fn run_leaves_out_strategies_that_do_not_load() {
+    let directory = scratch("lenient");
+    for name in ["a.lua", "b.lua"] {
+        write(
+            &directory,
+            name,
+            "return function(history, storage) return true, storage end",
+        );
+    }
+    write(&directory, "broken.ts", "not even typescript");
+    let directory = directory.to_str().unwrap();
+    let output = ipdt(&["run", directory, "-r", "1", "-f", "json"]);
+    assert!(output.status.success());
+    let results: Value = serde_json::from_slice(&output.stdout).unwrap();
+    let names: Vec<&str> = results["players"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|player| player["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["a", "b"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("broken.ts"));
+    assert!(!ipdt(&["validate", directory]).status.success());
+}
+
+#[test]
+fn the_basic_commands_work() {
+    let output = ipdt(&["list-builtins"]);
+    assert!(output.status.success());
+    let builtins = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        builtins.lines().any(|line| line == "Tit for Tat"),
+        "{builtins}"
+    );
+
+    let output = ipdt(&["match", "tit-for-tat", "always-defect", "-r", "3"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Tit for Tat: -1 CDD\nAlways Defect: 3 DDD\n"
+    );
+
+    let output = ipdt(&["match", "tit-for-tat", "nobody"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nobody"));
+}
+
+#[test]
+fn validate_stops_programs_that_hang() {
+    let directory = scratch("hanging");
+    let loading = write(&directory, "loading.lua", "while true do end");
+    let moving = write(
+        &directory,
+        "moving.lua",
+        "return function(history, storage) while true do end end",
+    );
+    let started = Instant::now();
+    let output = ipdt(&[
+        "validate",
+        loading.to_str().unwrap(),
+        moving.to_str().unwrap(),
+        "--move-timeout",
+        "1",
+    ]);
+    assert!(!output.status.success());
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        report.contains("error loading: still loading after 1s"),
+        "{report}"
+    );
+    assert!(
+        report.contains("error moving: against Tit for Tat, round 1: no move after 1s"),
+        "{report}"
+    );
+    // Every check and move in a worker of its own, which gets killed
+    assert!(started.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn validate_fails_on_broken_strategies() {
+    let directory = scratch("validate");
+    let good = write(
+        &directory,
+        "good.py",
+        "def main(history, storage):\n    return (True, storage)\n",
+    );
+    let bad = write(&directory, "bad.py", "def main(history, storage)\n");
+    let (good, bad) = (good.to_str().unwrap(), bad.to_str().unwrap());
+
+    let output = ipdt(&["validate", good]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok    good\n");
+
+    let output = ipdt(&["validate", good, bad]);
+    assert!(!output.status.success());
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(report.contains("error bad: Syntax error"), "{report}");
+    assert!(report.contains("line 1"), "{report}");
+}
@@ -0,0 +1,245 @@
+//! Piston strategies against the local stand-in for a Piston server
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use serde_json::{json, Value};
+
+use ipdt::builtins::builtin;
+use ipdt::core::{Executor, Player, Tournament, TournamentConfig};
+use ipdt::error::ExecutionError;
+use ipdt::piston::{self, PistonInput, PistonLimits, PistonProgram, PistonServer, Stub};
+
+fn stub() -> Stub {
+    Stub::start("127.0.0.1:0", Stub::default_runtimes()).unwrap()
+}
+
+/// Gives up quickly, so tests don't wait on backoff
+fn impatient() -> PistonLimits {
+    PistonLimits {
+        retries: 2,
+        backoff_ms: 1,
+        max_backoff_ms: 1,
+        ..PistonLimits::default()
+    }
+}
+
+fn health(url: &str) -> piston::ServerHealth {
+    piston::health()
+        .into_iter()
+        .find(|health| health.url == url)
+        .unwrap_or_default()
+}
+
+/// Echoes what it read on stdin back in its storage
+const ECHO: &str = "
+import json, sys
+envelope = json.load(sys.stdin)
+print('thinking')
+print(json.dumps({'action': len(envelope['history']) % 2 == 0, 'storage': envelope}))
+";
+
+#[test]
+fn programs_get_the_envelope_on_stdin() {
+    let stub = stub();
+    let program = PistonProgram::new("python", ECHO).with_server(PistonServer::new(stub.url()));
+    let decision = Executor::Piston(program)
+        .run(&[(true, false)], json!({"seen": 1}))
+        .unwrap();
+    assert!(!decision.action);
+    assert_eq!(
+        decision.storage,
+        json!({"version": 1, "history": [[true, false]], "storage": {"seen": 1}})
+    );
+}
+
+#[test]
+fn the_stub_reads_output_while_writing_input() {
+    let stub = stub();
+    let chatty = "
+import json, sys
+print('x' * (1 << 20), file=sys.stderr)
+envelope = json.load(sys.stdin)
+print(json.dumps({'action': True, 'storage': len(envelope['storage'])}))
+";
+    let program = PistonProgram::new("python", chatty).with_server(PistonServer::new(stub.url()));
+    let decision = Executor::Piston(program)
+        .run(&[], Value::from("y".repeat(1 << 20)))
+        .unwrap();
+    assert_eq!(decision.storage, json!(1 << 20));
+}
+
+#[test]
+fn the_stub_stops_programs_that_run_too_long() {
+    let stub = stub();
+    let program = PistonProgram::new("python", "while True:\n    pass\n")
+        .with_server(PistonServer::new(stub.url()));
+    let started = std::time::Instant::now();
+    assert!(Executor::Piston(program).run(&[], Value::from("")).is_err());
+    assert!(started.elapsed() < std::time::Duration::from_secs(30));
+}
+
+#[test]
+fn older_programs_get_argv() {
+    let stub = stub();
+    let program = PistonProgram::new(
+        "python",
+        "import json, sys\nprint(json.dumps({'action': True, 'storage': [json.loads(arg) for arg in sys.argv[1:]]}))",
+    )
+    .with_server(PistonServer::new(stub.url()))
+    .with_input(PistonInput::Argv);
+    let decision = Executor::Piston(program)
+        .run(&[(false, true)], Value::from(""))
+        .unwrap();
+    assert_eq!(decision.storage, json!([[[false, true]], ""]));
+}
+
+/// Tit for tat, playing a whole match per run
+const MATCH_TIT_FOR_TAT: &str = "
+import json, sys
+json.loads(sys.stdin.readline())
+for line in sys.stdin:
+    them = json.loads(line)['them']
+    print(json.dumps({'action': them is not False, 'storage': ''}), flush=True)
+";
+
+#[test]
+fn match_mode_plays_ahead_and_runs_again_on_a_wrong_guess() {
+    let stub = stub();
+    let url = stub.url();
+    let program = PistonProgram::new("python", MATCH_TIT_FOR_TAT)
+        .with_server(PistonServer::new(url.clone()))
+        .with_input(PistonInput::Match);
+    let play = |opponent: &str| {
+        let players = vec![
+            Player::new("piston".to_string(), Executor::Piston(program.clone())),
+            builtin(opponent).unwrap(),
+        ];
+        let result = Tournament::with_config(
+            TournamentConfig::new()
+                .with_players(players)
+                .with_rounds(10)
+                .with_disclosed_rounds(true)
+                .with_seed(1),
+        )
+        .run();
+        assert!(result.abandoned.is_empty());
+        // Both ways round
+        assert_eq!(result.matches.len(), 2);
+        let game = &result.matches[0];
+        assert_eq!(game.player1, 0);
+        assert!(game.errors.is_empty(), "{:?}", game.errors);
+        game.rounds.clone()
+    };
+    let before = health(&url).requests;
+    let rounds = play("always-cooperate");
+    assert!(rounds.iter().all(|&round| round == (true, true)));
+    // The guess that the opponent keeps cooperating holds all the way
+    assert_eq!(health(&url).requests - before, 2);
+
+    let before = health(&url).requests;
+    let rounds = play("always-defect");
+    let moves: Vec<bool> = rounds.iter().map(|round| round.0).collect();
+    assert_eq!(moves[..3], [true, false, false]);
+    assert!(moves[3..].iter().all(|&action| !action));
+    // Wrong about the second round only
+    assert_eq!(health(&url).requests - before, 4);
+}
+
+#[test]
+fn unreachable_servers_are_retried_then_abandon_the_game() {
+    // A port nobody listens on any more
+    let url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let server = PistonServer::new(url.clone()).with_limits(PistonLimits {
+        breaker_threshold: 1,
+        ..impatient()
+    });
+    let program = PistonProgram::new("python", ECHO).with_server(server);
+    let players = vec![
+        Player::new("piston".to_string(), Executor::Piston(program)),
+        builtin("tit-for-tat").unwrap(),
+    ];
+    let result = Tournament::with_config(
+        TournamentConfig::new()
+            .with_players(players)
+            .with_rounds(5)
+            .with_parallelism(1),
+    )
+    .run();
+    assert!(result.matches.is_empty());
+    assert_eq!(result.scores, vec![0, 0]);
+    let errors: Vec<_> = result
+        .abandoned
+        .iter()
+        .map(|game| game.abandoned.clone().unwrap())
+        .collect();
+    let [first, second] = &errors[..] else {
+        panic!("{errors:?}");
+    };
+    assert_eq!((first.player, first.round), (0, 0));
+    assert!(first.message.contains("tried 3 times"), "{}", first.message);
+    // The breaker opened after the first game gave up
+    assert!(
+        second.message.contains("failed too often"),
+        "{}",
+        second.message
+    );
+
+    let health = health(&url);
+    assert_eq!(
+        (health.requests, health.retries, health.failures),
+        (3, 2, 2)
+    );
+    assert_eq!(health.breaker_trips, 1);
+    assert!(health.breaker_open);
+}
+
+#[test]
+fn server_errors_are_retried() {
+    // Starting the interpreter fails, which the stub answers with a 500
+    let runtimes = BTreeMap::from([(
+        "python".to_string(),
+        vec!["/nonexistent/python".to_string()],
+    )]);
+    let stub = Stub::start("127.0.0.1:0", runtimes).unwrap();
+    let program = PistonProgram::new("python", ECHO)
+        .with_server(PistonServer::new(stub.url()).with_limits(impatient()));
+    match Executor::Piston(program).run(&[], Value::from("")) {
+        Err(ExecutionError::Unavailable(message)) => {
+            assert!(message.contains("tried 3 times"), "{message}")
+        }
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(health(&stub.url()).retries, 2);
+}
+
+#[test]
+fn unknown_languages_are_the_program_s_fault() {
+    let stub = stub();
+    let program = PistonProgram::new("cobol", "")
+        .with_server(PistonServer::new(stub.url()).with_limits(impatient()));
+    assert!(matches!(
+        Executor::Piston(program).run(&[], Value::from("")),
+        Err(ExecutionError::RuntimeError(_))
+    ));
+    assert_eq!(health(&stub.url()).retries, 0);
+}
+
+#[test]
+fn the_stub_refuses_huge_bodies() {
+    let stub = stub();
+    let address = stub.url().trim_start_matches("http://").to_string();
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "POST /api/v2/execute HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n{{}}"
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+}